@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use clap::{Args, Subcommand};
+use reqwest::{Method, blocking::Client};
+use serde_json::Value;
+
+#[derive(Debug, Args)]
+pub struct AdminArgs {
+    /// Address of the server's admin listener
+    #[arg(long, default_value = "127.0.0.1:9001")]
+    address: String,
+
+    /// Unix socket of the server's admin listener, used instead of the address
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    /// Admin token configured for the server
+    #[arg(long, env = "WASSEL_ADMIN_TOKEN", hide_env_values = true)]
+    token: String,
+
+    #[command(subcommand)]
+    command: AdminCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AdminCommand {
+    /// List loaded plugins
+    Plugins,
+
+    /// Show route table
+    Routes,
+
+    /// Show health and pool stats of a plugin
+    Health { id: String },
+
+    /// Reload stack from disk
+    Reload,
+
+    /// Start routing requests to a plugin again
+    Enable { id: String },
+
+    /// Answer requests to a plugin with 503 Service Unavailable
+    Disable { id: String },
+}
+
+pub fn run(args: AdminArgs) -> anyhow::Result<()> {
+    let (method, path) = match &args.command {
+        AdminCommand::Plugins => (Method::GET, "plugins".to_owned()),
+        AdminCommand::Routes => (Method::GET, "routes".to_owned()),
+        AdminCommand::Health { id } => (Method::GET, format!("plugins/{id}/health")),
+        AdminCommand::Reload => (Method::POST, "reload".to_owned()),
+        AdminCommand::Enable { id } => (Method::POST, format!("plugins/{id}/enable")),
+        AdminCommand::Disable { id } => (Method::POST, format!("plugins/{id}/disable")),
+    };
+
+    let mut client = Client::builder();
+    #[cfg(unix)]
+    if let Some(socket) = &args.socket {
+        client = client.unix_socket(socket.clone());
+    }
+    #[cfg(not(unix))]
+    if args.socket.is_some() {
+        anyhow::bail!("Admin sockets are only supported on Unix");
+    }
+    let client = client.build().context("Creating admin client")?;
+
+    // Host is ignored when connecting through a socket
+    let url = format!("http://{}/{path}", args.address);
+    let response = client
+        .request(method, &url)
+        .bearer_auth(&args.token)
+        .send()
+        .context(format!("Sending admin request to `{url}`"))?;
+
+    let status = response.status();
+    let body: Value = response.json().context("Reading admin response")?;
+    if !status.is_success() {
+        let message = body["error"].as_str().unwrap_or("Unknown error");
+        anyhow::bail!("Admin request failed with {status}: {message}");
+    }
+
+    match args.command {
+        AdminCommand::Plugins | AdminCommand::Reload => print_plugins(&body),
+        AdminCommand::Routes => {
+            for route in body.as_array().into_iter().flatten() {
+                println!(
+                    "{:<40} {}",
+                    route["endpoint"].as_str().unwrap_or_default(),
+                    route["plugin_id"].as_str().unwrap_or_default()
+                );
+            }
+        }
+        AdminCommand::Health { .. }
+        | AdminCommand::Enable { .. }
+        | AdminCommand::Disable { .. } => {
+            println!("{}", serde_json::to_string_pretty(&body)?);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_plugins(body: &Value) {
+    for plugin in body.as_array().into_iter().flatten() {
+        println!(
+            "{:<24} {:<10} {:<24} {:<8} {}",
+            plugin["id"].as_str().unwrap_or_default(),
+            plugin["version"].as_str().unwrap_or_default(),
+            plugin["endpoint"].as_str().unwrap_or_default(),
+            if plugin["enabled"].as_bool().unwrap_or_default() {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            plugin["hash"].as_str().unwrap_or_default()
+        );
+    }
+}
@@ -5,17 +5,80 @@ use std::{
 };
 
 use anyhow::{Context as _, bail};
+use clap::Args;
 use serde::{Deserialize, Serialize};
 use subprocess::{Exec, Redirection};
+use wassel_server::{CoreDumpSettings, OutboundMode, SecretsSettings, ServerOptions};
+
+#[derive(Debug, Clone, Args)]
+pub struct ServeArgs {
+    /// Address to listen on instead of `host` from the server config
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Port to listen on instead of `port` from the server config
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Log filter like `debug` or `info,wassel=trace`, overrides `RUST_LOG`
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Record outbound HTTP requests of plugins to cassettes in this directory
+    #[arg(long, conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// Answer outbound HTTP requests of plugins from cassettes in this directory
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+
+    /// Answer failed plugin calls with the trap, wasm backtrace and guest
+    /// stderr. Needs `profile = "development"` in the server config.
+    #[arg(long)]
+    pub dev: bool,
+
+    /// Rebuild plugins when their sources change and swap them into the
+    /// running server
+    #[arg(long)]
+    pub watch: bool,
+}
+
+impl ServeArgs {
+    /// Options for serving the stack in `base_path`
+    pub fn server_options(&self, base_path: &Path) -> ServerOptions {
+        let outbound = match (&self.record, &self.replay) {
+            (Some(dir), _) => OutboundMode::Record(dir.clone()),
+            (None, Some(dir)) => OutboundMode::Replay(dir.clone()),
+            (None, None) => OutboundMode::Live,
+        };
+
+        ServerOptions {
+            base_path: Some(base_path.to_owned()),
+            host: self.host.clone(),
+            port: self.port,
+            log_level: self.log_level.clone(),
+            outbound,
+            dev_mode: self.dev,
+            reload: None,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WasselMeta {
     #[serde(default = "StackMeta::default")]
     pub stack: StackMeta,
+
+    #[serde(default = "SecretsSettings::default")]
+    pub secrets: SecretsSettings,
+
+    #[serde(default = "CoreDumpSettings::default")]
+    pub coredump: CoreDumpSettings,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct StackMeta {
+    /// Plugin source directories, relative to the directory of `wassel.toml`
     #[serde(default = "Vec::default")]
     pub plugins: Vec<PathBuf>,
 }
@@ -30,6 +93,17 @@ pub struct PluginMeta {
 
     #[serde(default = "default_data_folder")]
     pub data_folder: PathBuf,
+
+    #[serde(default = "Option::default")]
+    pub sql: Option<PluginMetaSql>,
+}
+
+/// Part of the `[sql]` section the build needs to know about
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginMetaSql {
+    /// Migrations directory, copied next to `plugin.toml` on build
+    #[serde(default = "Option::default")]
+    pub migrations: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,6 +121,7 @@ pub struct PluginBuildInfo {
     pub component: PathBuf,
     pub data_folder: PathBuf,
     pub data: HashMap<PathBuf, PathBuf>,
+    pub migrations: Option<PathBuf>,
 }
 
 /// Returns path to the built component
@@ -110,6 +185,7 @@ pub fn build_plugin_at(path: &Path) -> anyhow::Result<PluginBuildInfo> {
         component,
         data_folder: meta.data_folder,
         data: meta.build.map(|b| b.data).unwrap_or_default(),
+        migrations: meta.sql.and_then(|s| s.migrations),
     })
 }
 
@@ -128,6 +204,15 @@ pub fn copy_plugin_to_plugins_folder(
     )
     .context(format!("Copying plugin metadata `{id}`"))?;
 
+    if let Some(migrations) = &info.migrations {
+        let from = info.path.join(migrations);
+        let to = plugin_directory.join(migrations);
+        if to.exists() {
+            fs::remove_dir_all(&to).context("Removing old migrations")?;
+        }
+        copy_all(&from, &to).context(format!("Copying migrations `{from:?}` -> `{to:?}`"))?;
+    }
+
     fs::create_dir_all(plugin_directory.join(&info.data_folder))
         .context("Creating plugin data folder")?;
     for (source_path, destination_path) in &info.data {
@@ -141,6 +226,31 @@ pub fn copy_plugin_to_plugins_folder(
     Ok(())
 }
 
+/// Uninstalls plugins of the folder other than the given ones. Only their
+/// metadata and component are removed, data they kept stays in place.
+pub fn uninstall_other_plugins(plugins_folder: &Path, ids: &[&str]) -> anyhow::Result<()> {
+    if !plugins_folder.exists() {
+        return Ok(());
+    }
+    let entries = fs::read_dir(plugins_folder).context(format!(
+        "Reading plugins directory `{}`",
+        plugins_folder.to_string_lossy()
+    ))?;
+    for entry in entries {
+        let entry = entry.context("Reading plugins directory entry")?;
+        if !entry.path().is_dir() || ids.iter().any(|id| entry.file_name() == *id) {
+            continue;
+        }
+        for artifact in ["plugin.toml", "plugin.wasm"] {
+            let path = entry.path().join(artifact);
+            if path.exists() {
+                fs::remove_file(&path).context(format!("Removing `{}`", path.to_string_lossy()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
 fn copy_all(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
     let from = from.as_ref();
     let to = to.as_ref();
@@ -160,3 +270,26 @@ fn copy_all(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
 fn default_data_folder() -> PathBuf {
     PathBuf::from("data")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uninstalling_keeps_plugin_data() {
+        let dir = tempfile::tempdir().unwrap();
+        for id in ["kept", "removed"] {
+            let plugin = dir.path().join(id);
+            fs::create_dir_all(plugin.join("data")).unwrap();
+            fs::write(plugin.join("plugin.toml"), "").unwrap();
+            fs::write(plugin.join("plugin.wasm"), "").unwrap();
+            fs::write(plugin.join("data/app.db"), "rows").unwrap();
+        }
+
+        uninstall_other_plugins(dir.path(), &["kept"]).unwrap();
+        assert!(dir.path().join("kept/plugin.toml").exists());
+        assert!(!dir.path().join("removed/plugin.toml").exists());
+        assert!(!dir.path().join("removed/plugin.wasm").exists());
+        assert!(dir.path().join("removed/data/app.db").exists());
+    }
+}
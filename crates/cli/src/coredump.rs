@@ -0,0 +1,87 @@
+use std::{fs, path::Path};
+
+use anyhow::Context as _;
+use clap::{Args, Subcommand};
+use wassel_server::{CoreDumpInfo, CoreDumpSettings, CoreDumps};
+
+use crate::common;
+
+#[derive(Debug, Args)]
+pub struct CoreDumpArgs {
+    #[command(subcommand)]
+    command: CoreDumpCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CoreDumpCommand {
+    /// List dumps, newest first
+    List {
+        /// Only list dumps of this plugin
+        #[arg(long)]
+        plugin: Option<String>,
+    },
+
+    /// Show trap message, backtrace and details of a dump
+    Inspect {
+        /// File name of the dump as shown by `list`
+        file: String,
+    },
+}
+
+/// Lists or inspects dumps of the stack in `stack_path`, configured by its
+/// `wassel.toml` when there is one
+pub fn run(stack_path: &Path, args: CoreDumpArgs) -> anyhow::Result<()> {
+    let meta_path = stack_path.join("wassel.toml");
+    let mut settings = if meta_path.exists() {
+        let meta =
+            fs::read(&meta_path).context(format!("Reading wassel config at `{meta_path:?}`"))?;
+        let meta: common::WasselMeta = toml::from_slice(&meta)
+            .context(format!("Deserializing wassel config at `{meta_path:?}`"))?;
+        meta.coredump
+    } else {
+        CoreDumpSettings::default()
+    };
+    settings.directory = stack_path.join(&settings.directory);
+    let dumps = CoreDumps::list(&settings)?;
+
+    match args.command {
+        CoreDumpCommand::List { plugin } => {
+            let dumps = dumps
+                .iter()
+                .filter(|d| plugin.as_ref().is_none_or(|p| &d.plugin_id == p));
+            for dump in dumps {
+                println!(
+                    "{:<64} {:<24} {:<10} {:>10}  {}",
+                    dump.file, dump.plugin_id, dump.plugin_version, dump.size, dump.message
+                );
+            }
+        }
+        CoreDumpCommand::Inspect { file } => {
+            let dump = dumps
+                .iter()
+                .find(|d| d.file == file)
+                .context(format!("There is no core dump `{file}`"))?;
+            print_dump(dump, &settings.directory.join(&dump.file));
+        }
+    }
+
+    Ok(())
+}
+
+fn print_dump(dump: &CoreDumpInfo, path: &Path) {
+    println!("File:       {}", path.to_string_lossy());
+    println!("Plugin:     {} {}", dump.plugin_id, dump.plugin_version);
+    println!(
+        "Request id: {}",
+        dump.request_id.as_deref().unwrap_or("none")
+    );
+    println!("Time:       {}", dump.time);
+    println!("Size:       {} bytes", dump.size);
+    println!("Trap:       {}", dump.message);
+    if let Some(backtrace) = &dump.backtrace {
+        println!();
+        println!("{backtrace}");
+    }
+    println!();
+    println!("The dump is a Wasm core dump and can be opened with tools such as `wasmgdb`.");
+}
@@ -1,10 +1,15 @@
 use clap::{Parser, Subcommand};
 
-use crate::{plugin::PluginArgs, stack::StackArgs};
+use crate::{admin::AdminArgs, plugin::PluginArgs, stack::StackArgs};
 
+mod admin;
 mod common;
+mod coredump;
 mod plugin;
+mod secret;
 mod stack;
+mod template;
+mod watch;
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -19,6 +24,9 @@ enum Command {
 
     /// Operations on single plugin
     Plugin(PluginArgs),
+
+    /// Talk to admin API of running server
+    Admin(AdminArgs),
 }
 
 fn main() -> anyhow::Result<()> {
@@ -26,5 +34,6 @@ fn main() -> anyhow::Result<()> {
     match args.cmd {
         Command::Stack(stack_args) => stack::run(stack_args),
         Command::Plugin(plugin_args) => plugin::run(plugin_args),
+        Command::Admin(admin_args) => admin::run(admin_args),
     }
 }
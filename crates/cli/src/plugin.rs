@@ -5,8 +5,17 @@ use std::{
 
 use anyhow::Context as _;
 use clap::{Args, Subcommand};
+use wassel_server::{Method, ProfileRequest};
 
-use crate::common;
+use crate::{
+    common::{self, PluginBuildInfo, ServeArgs},
+    coredump::{self, CoreDumpArgs},
+    template::{self, Language, Project},
+    watch,
+};
+
+/// Stack serving a single plugin, relative to the plugin directory
+const SERVE_DIRECTORY: &str = ".wassel/serve";
 
 #[derive(Debug, Args)]
 pub struct PluginArgs {
@@ -19,44 +28,188 @@ pub struct PluginArgs {
 
 #[derive(Debug, Subcommand)]
 pub enum PluginCommand {
+    /// Create a new plugin project in a directory of the given name
+    New(NewArgs),
+
     /// Build plugin
     Build,
 
     /// Start Wassel application and serve using single plugin
-    Serve,
+    Serve(ServeArgs),
+
+    /// Send single request to the plugin and save its guest profile
+    Profile(ProfileArgs),
+
+    /// List and inspect core dumps written when the served plugin trapped
+    Coredump(CoreDumpArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct NewArgs {
+    /// Plugin id and name of the created directory, like `my-plugin`
+    name: String,
+
+    #[arg(long, short, value_enum, default_value = "rust")]
+    lang: Language,
+
+    /// Endpoint the plugin is served at, `/<name>/` by default
+    #[arg(long)]
+    endpoint: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ProfileArgs {
+    /// Path of the request, including the plugin endpoint
+    request_path: String,
+
+    #[arg(long, short = 'X', default_value = "GET")]
+    method: String,
+
+    /// Request header as `name: value`, can be repeated
+    #[arg(long = "header", short = 'H')]
+    headers: Vec<String>,
+
+    /// Request body
+    #[arg(long, short)]
+    data: Option<String>,
+
+    /// Where to copy the profile, which is kept in the profiles directory as well
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
 }
 
 pub fn run(args: PluginArgs) -> anyhow::Result<()> {
     match args.command {
+        PluginCommand::New(new_args) => cmd_new(&args.path, new_args),
         PluginCommand::Build => cmd_build(&args.path),
-        PluginCommand::Serve => cmd_serve(&args.path),
+        PluginCommand::Serve(serve_args) => cmd_serve(&args.path, &serve_args),
+        PluginCommand::Profile(profile_args) => cmd_profile(&args.path, profile_args),
+        PluginCommand::Coredump(coredump_args) => {
+            coredump::run(&args.path.join(SERVE_DIRECTORY), coredump_args)
+        }
     }
 }
 
+fn cmd_new(path: &Path, args: NewArgs) -> anyhow::Result<()> {
+    let project = Project::new(&args.name, args.endpoint)?;
+    let dir = path.join(&args.name);
+    template::generate(&dir, args.lang, &project)?;
+
+    println!(
+        "Created plugin `{}` serving `{}` in `{}`",
+        project.id,
+        project.endpoint,
+        dir.to_string_lossy()
+    );
+    println!("See its README.md for the tools needed to build it");
+    Ok(())
+}
+
 fn cmd_build(path: &Path) -> anyhow::Result<()> {
     common::build_plugin_at(path)?;
     Ok(())
 }
 
-fn cmd_serve(path: &Path) -> anyhow::Result<()> {
-    let info = common::build_plugin_at(path)?;
+fn cmd_serve(path: &Path, serve_args: &ServeArgs) -> anyhow::Result<()> {
+    install_plugin(path)?;
+    let stack_path = path.join(SERVE_DIRECTORY);
 
-    let plugins_path = Path::new("plugins");
-    let plugin_dir = plugins_path.join(&info.id);
-    if plugins_path.exists() {
-        fs::remove_dir_all(plugins_path).context(format!(
-            "Removing plugins directory at `{}`",
-            plugin_dir.to_string_lossy()
-        ))?;
+    let mut options = serve_args.server_options(&stack_path);
+    if serve_args.watch {
+        options.reload = Some(watch::spawn_watcher(
+            &[path.to_owned()],
+            &stack_path.join("plugins"),
+            install_plugin,
+        )?);
     }
 
-    common::copy_plugin_to_plugins_folder(plugins_path, &info)?;
-
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .context("Building tokio runtime")?
-        .block_on(wassel_server::run_server())?;
+        .block_on(wassel_server::run_server(options))?;
+
+    Ok(())
+}
+
+fn cmd_profile(path: &Path, args: ProfileArgs) -> anyhow::Result<()> {
+    install_plugin(path)?;
+    let stack_path = path.join(SERVE_DIRECTORY);
+
+    let method = Method::from_bytes(args.method.as_bytes())
+        .context(format!("Invalid method `{}`", args.method))?;
+    let headers = args
+        .headers
+        .iter()
+        .map(|header| {
+            let (name, value) = header
+                .split_once(':')
+                .context(format!("Header `{header}` is not `name: value`"))?;
+            Ok((name.trim().to_owned(), value.trim().to_owned()))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let request = ProfileRequest {
+        method,
+        path: args.request_path,
+        headers,
+        body: args.data.unwrap_or_default().into(),
+    };
+
+    let outcome = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Building tokio runtime")?
+        .block_on(wassel_server::run_profile(
+            args.serve.server_options(&stack_path),
+            request,
+        ))?;
+
+    println!("Plugin responded with {}", outcome.status);
+    let profile = match args.output {
+        Some(output) => {
+            fs::copy(&outcome.profile, &output)
+                .context(format!("Copying profile to `{}`", output.to_string_lossy()))?;
+            output
+        }
+        None => outcome.profile,
+    };
+    println!(
+        "Profile saved to `{}`, open it at https://profiler.firefox.com",
+        profile.to_string_lossy()
+    );
 
     Ok(())
 }
+
+/// Builds the plugin and makes it the only one of a stack kept inside the
+/// plugin directory. A `wassel.toml` next to `plugin.toml` configures that
+/// stack, which is synced on every install. Data the plugin kept stays.
+fn install_plugin(path: &Path) -> anyhow::Result<PluginBuildInfo> {
+    let info = common::build_plugin_at(path)?;
+
+    let stack_path = path.join(SERVE_DIRECTORY);
+    let plugins_path = stack_path.join("plugins");
+    common::copy_plugin_to_plugins_folder(&plugins_path, &info)?;
+    common::uninstall_other_plugins(&plugins_path, &[info.id.as_str()])?;
+
+    // Stack of a single plugin being served locally is a development one,
+    // unless the plugin brings its own config
+    let config_path = path.join("wassel.toml");
+    if config_path.exists() {
+        fs::copy(&config_path, stack_path.join("wassel.toml")).context(format!(
+            "Copying stack config `{}`",
+            config_path.to_string_lossy()
+        ))?;
+    } else {
+        fs::write(
+            stack_path.join("wassel.toml"),
+            "profile = \"development\"\n",
+        )
+        .context("Writing stack config")?;
+    }
+
+    Ok(info)
+}
@@ -0,0 +1,77 @@
+use std::{
+    fs,
+    io::{self, Read as _},
+    path::Path,
+};
+
+use anyhow::Context as _;
+use clap::{Args, Subcommand};
+use wassel_server::{Secret, SecretsFile};
+
+use crate::common;
+
+#[derive(Debug, Args)]
+pub struct SecretArgs {
+    #[command(subcommand)]
+    command: SecretCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SecretCommand {
+    /// Print a new random key for the secrets file
+    GenerateKey,
+
+    /// Encrypt value read from standard input and store it under the name
+    Set { name: String },
+
+    /// Remove secret from the file
+    Remove { name: String },
+
+    /// List names of stored secrets
+    List,
+}
+
+pub fn run(manifest_path: &Path, args: SecretArgs) -> anyhow::Result<()> {
+    if let SecretCommand::GenerateKey = args.command {
+        println!("{}", SecretsFile::generate_key());
+        return Ok(());
+    }
+
+    let meta_path = manifest_path.join("wassel.toml");
+    let meta = fs::read(&meta_path).context(format!("Reading wassel config at `{meta_path:?}`"))?;
+    let meta: common::WasselMeta = toml::from_slice(&meta)
+        .context(format!("Deserializing wassel config at `{meta_path:?}`"))?;
+
+    let mut settings = meta.secrets;
+    settings.file = manifest_path.join(&settings.file);
+    let mut file = SecretsFile::open(&settings)?;
+
+    match args.command {
+        SecretCommand::GenerateKey => unreachable!("Handled above"),
+        SecretCommand::Set { name } => {
+            let mut value = String::new();
+            io::stdin()
+                .read_to_string(&mut value)
+                .context("Reading secret from standard input")?;
+            let value = Secret::new(value.trim_end_matches(['\n', '\r']));
+            file.set(&name, &value)?;
+            file.save()?;
+            println!("Secret `{name}` stored");
+        }
+        SecretCommand::Remove { name } => {
+            if file.remove(&name) {
+                file.save()?;
+                println!("Secret `{name}` removed");
+            } else {
+                println!("There is no secret `{name}`");
+            }
+        }
+        SecretCommand::List => {
+            for name in file.names() {
+                println!("{name}");
+            }
+        }
+    }
+
+    Ok(())
+}
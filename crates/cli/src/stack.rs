@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     fs,
     path::{Path, PathBuf},
 };
@@ -6,8 +7,13 @@ use std::{
 use anyhow::Context as _;
 use clap::{Args, Subcommand};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator as _};
+use wassel_server::{LoadError, Stack};
 
-use crate::common::{self, build_plugin_at};
+use crate::{
+    common::{self, ServeArgs, build_plugin_at},
+    secret::{self, SecretArgs},
+    watch,
+};
 
 #[derive(Debug, Args)]
 pub struct StackArgs {
@@ -21,13 +27,21 @@ pub struct StackArgs {
 #[derive(Debug, Subcommand)]
 pub enum StackCommand {
     Build,
-    Serve,
+    Serve(ServeArgs),
+
+    /// Build and validate all plugins like the server would, failing on any error
+    Check,
+
+    /// Manage the encrypted secrets file of the stack
+    Secret(SecretArgs),
 }
 
 pub fn run(args: StackArgs) -> anyhow::Result<()> {
     match args.command {
         StackCommand::Build => cmd_build(&args.manifest_path),
-        StackCommand::Serve => cmd_serve(&args.manifest_path),
+        StackCommand::Serve(serve_args) => cmd_serve(&args.manifest_path, &serve_args),
+        StackCommand::Check => cmd_check(&args.manifest_path),
+        StackCommand::Secret(secret_args) => secret::run(&args.manifest_path, secret_args),
     }
 }
 
@@ -36,42 +50,124 @@ pub fn cmd_build(path: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn cmd_serve(path: &Path) -> anyhow::Result<()> {
-    build_entire_stack(path)?;
+pub fn cmd_serve(path: &Path, serve_args: &ServeArgs) -> anyhow::Result<()> {
+    let meta = build_entire_stack(path)?;
     println!("All plugins built successfully");
+
+    let mut options = serve_args.server_options(path);
+    if serve_args.watch {
+        let sources = meta
+            .stack
+            .plugins
+            .iter()
+            .map(|plugin| path.join(plugin))
+            .collect::<Vec<_>>();
+        let plugins_path = path.join("plugins");
+        let install_path = plugins_path.clone();
+        options.reload = Some(watch::spawn_watcher(
+            &sources,
+            &plugins_path,
+            move |source| {
+                let info = common::build_plugin_at(source)?;
+                common::copy_plugin_to_plugins_folder(&install_path, &info)?;
+                Ok(info)
+            },
+        )?);
+    }
+
     println!("Starting wassel server");
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .context("Building tokio runtime")?
-        .block_on(wassel_server::run_server())?;
+        .block_on(wassel_server::run_server(options))?;
     Ok(())
 }
 
-fn build_entire_stack(path: &Path) -> anyhow::Result<()> {
-    let meta_path = path.join("wassel.toml");
-    let meta = fs::read(&meta_path).context(format!("Reading wassel config at `{meta_path:?}`"))?;
-    let meta: common::WasselMeta = toml::from_slice(&meta)
-        .context(format!("Deserializing wassel config at `{meta_path:?}`"))?;
-
+/// Builds and installs every plugin the way `serve` does, then validates the
+/// stack without loading it, reporting all failures at once
+pub fn cmd_check(path: &Path) -> anyhow::Result<()> {
+    let meta = read_stack_meta(path)?;
     let plugins_path = path.join("plugins");
-    if plugins_path.exists() {
-        fs::remove_dir_all(&plugins_path).context("Removing plugins directory")?;
+    fs::create_dir_all(&plugins_path).context("Creating plugins directory")?;
+
+    let mut failures = BTreeMap::new();
+    let mut ids = Vec::new();
+    for (plugin, result) in meta.stack.plugins.iter().zip(build_plugins(path, &meta)) {
+        let result = result.and_then(|info| {
+            common::copy_plugin_to_plugins_folder(&plugins_path, &info)?;
+            Ok(info.id)
+        });
+        match result {
+            Ok(id) => ids.push(id),
+            Err(e) => {
+                failures.insert(
+                    plugin.to_string_lossy().into_owned(),
+                    format!("Building: {e:#}"),
+                );
+            }
+        }
     }
+    // Installs the same plugins as `serve`, so stale ones are not checked
+    let ids: Vec<_> = ids.iter().map(String::as_str).collect();
+    common::uninstall_other_plugins(&plugins_path, &ids)?;
 
-    fs::create_dir_all(&plugins_path).context("Creating plugins directory")?;
+    let check = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Building tokio runtime")?
+        .block_on(Stack::check(path))
+        .context("Checking stack")?;
+    failures.extend(check.failures);
 
-    let infos = meta
-        .stack
+    println!("{} plugins passed", check.plugins.len());
+    for (endpoint, plugin_id) in &check.routes {
+        println!("  {endpoint} -> {plugin_id}");
+    }
+    if !failures.is_empty() {
+        return Err(LoadError { failures }.into());
+    }
+    Ok(())
+}
+
+fn read_stack_meta(path: &Path) -> anyhow::Result<common::WasselMeta> {
+    let meta_path = path.join("wassel.toml");
+    let meta = fs::read(&meta_path).context(format!("Reading wassel config at `{meta_path:?}`"))?;
+    toml::from_slice(&meta).context(format!("Deserializing wassel config at `{meta_path:?}`"))
+}
+
+/// Builds every plugin of the stack, in the order they are listed
+fn build_plugins(
+    path: &Path,
+    meta: &common::WasselMeta,
+) -> Vec<anyhow::Result<common::PluginBuildInfo>> {
+    meta.stack
         .plugins
         .par_iter()
-        .map(|path| build_plugin_at(path).context(format!("Building plugin `{path:?}`")))
+        .map(|plugin| {
+            build_plugin_at(&path.join(plugin)).context(format!("Building plugin `{plugin:?}`"))
+        })
+        .collect()
+}
+
+/// Builds all plugins into the `plugins` directory and returns the stack config
+fn build_entire_stack(path: &Path) -> anyhow::Result<common::WasselMeta> {
+    let meta = read_stack_meta(path)?;
+
+    let plugins_path = path.join("plugins");
+    fs::create_dir_all(&plugins_path).context("Creating plugins directory")?;
+
+    let infos = build_plugins(path, &meta)
+        .into_iter()
         .collect::<Result<Vec<_>, _>>()
         .context("Building plugins")?;
 
-    for info in infos {
-        common::copy_plugin_to_plugins_folder(&plugins_path, &info)?;
+    // Plugins keep their runtime data across builds, only artifacts are synced
+    for info in &infos {
+        common::copy_plugin_to_plugins_folder(&plugins_path, info)?;
     }
+    let ids: Vec<_> = infos.iter().map(|info| info.id.as_str()).collect();
+    common::uninstall_other_plugins(&plugins_path, &ids)?;
 
-    Ok(())
+    Ok(meta)
 }
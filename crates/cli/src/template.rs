@@ -0,0 +1,201 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context as _, bail};
+use clap::ValueEnum;
+
+/// Languages `plugin new` can generate projects for
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Language {
+    Rust,
+    Python,
+    Js,
+    Go,
+}
+
+/// File of a template, its content has `{{placeholders}}` filled in
+struct TemplateFile {
+    path: &'static str,
+    content: &'static str,
+}
+
+macro_rules! template_file {
+    ($path:literal, $source:literal) => {
+        TemplateFile {
+            path: $path,
+            content: include_str!(concat!("../templates/", $source)),
+        }
+    };
+}
+
+macro_rules! wit_file {
+    ($path:literal) => {
+        TemplateFile {
+            path: concat!("wit/", $path),
+            content: include_str!(concat!("../templates/wit/", $path)),
+        }
+    };
+}
+
+const RUST: &[TemplateFile] = &[
+    template_file!("Cargo.toml", "rust/Cargo.toml.in"),
+    template_file!("src/lib.rs", "rust/src/lib.rs"),
+    template_file!("plugin.toml", "rust/plugin.toml"),
+    template_file!("justfile", "rust/justfile"),
+    template_file!(".gitignore", "rust/gitignore"),
+    template_file!("README.md", "rust/README.md"),
+];
+
+const PYTHON: &[TemplateFile] = &[
+    template_file!("app.py", "python/app.py"),
+    template_file!("plugin.toml", "python/plugin.toml"),
+    template_file!(".gitignore", "python/gitignore"),
+    template_file!("README.md", "python/README.md"),
+];
+
+const JS: &[TemplateFile] = &[
+    template_file!("app.js", "js/app.js"),
+    template_file!("package.json", "js/package.json"),
+    template_file!("plugin.toml", "js/plugin.toml"),
+    template_file!(".gitignore", "js/gitignore"),
+    template_file!("README.md", "js/README.md"),
+];
+
+const GO: &[TemplateFile] = &[
+    template_file!("main.go", "go/main.go"),
+    template_file!("go.mod", "go/go.mod"),
+    template_file!("plugin.toml", "go/plugin.toml"),
+    template_file!(".gitignore", "go/gitignore"),
+    template_file!("README.md", "go/README.md"),
+];
+
+/// WIT of the `http-plugin` world, copied into every project so guest
+/// tooling can generate bindings without fetching anything. Vendored from
+/// the workspace `wit` directory so the crate can be packaged on its own.
+const WIT: &[TemplateFile] = &[
+    wit_file!("wassel.wit"),
+    wit_file!("http-client.wit"),
+    wit_file!("deps/wasi-cli-0.2.10/package.wit"),
+    wit_file!("deps/wasi-clocks-0.2.10/package.wit"),
+    wit_file!("deps/wasi-config-0.2.0-rc.1/package.wit"),
+    wit_file!("deps/wasi-filesystem-0.2.10/package.wit"),
+    wit_file!("deps/wasi-http-0.2.10/package.wit"),
+    wit_file!("deps/wasi-io-0.2.10/package.wit"),
+    wit_file!("deps/wasi-keyvalue-0.2.0-draft/package.wit"),
+    wit_file!("deps/wasi-logging-0.1.0-draft/package.wit"),
+    wit_file!("deps/wasi-random-0.2.10/package.wit"),
+    wit_file!("deps/wasi-sockets-0.2.10/package.wit"),
+];
+
+impl Language {
+    fn files(self) -> &'static [TemplateFile] {
+        match self {
+            Language::Rust => RUST,
+            Language::Python => PYTHON,
+            Language::Js => JS,
+            Language::Go => GO,
+        }
+    }
+}
+
+/// Values filled into the template
+#[derive(Debug)]
+pub struct Project {
+    pub id: String,
+    pub name: String,
+    pub endpoint: String,
+}
+
+impl Project {
+    /// Derives the plugin metadata from a project name like `my-plugin`
+    pub fn new(id: &str, endpoint: Option<String>) -> anyhow::Result<Self> {
+        let valid = id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if id.is_empty() || !valid || !id.starts_with(|c: char| c.is_ascii_lowercase()) {
+            bail!(
+                "Plugin name `{id}` should start with a letter and contain only lowercase letters, digits and `-`"
+            );
+        }
+
+        let name = id
+            .split('-')
+            .filter(|word| !word.is_empty())
+            .map(|word| {
+                let mut chars = word.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let endpoint = endpoint.unwrap_or_else(|| format!("/{id}/"));
+        if !endpoint.starts_with('/') {
+            bail!("Endpoint `{endpoint}` should start with `/`");
+        }
+
+        Ok(Self {
+            id: id.to_owned(),
+            name,
+            endpoint,
+        })
+    }
+
+    fn render(&self, content: &str) -> String {
+        content
+            .replace("{{id}}", &self.id)
+            .replace("{{snake_id}}", &self.id.replace('-', "_"))
+            .replace("{{name}}", &self.name)
+            .replace("{{endpoint}}", &self.endpoint)
+    }
+}
+
+/// Writes a new project into `dir`, which has to be empty or missing
+pub fn generate(dir: &Path, language: Language, project: &Project) -> anyhow::Result<()> {
+    let occupied = fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some());
+    if occupied {
+        bail!(
+            "Directory `{}` already exists and is not empty",
+            dir.to_string_lossy()
+        );
+    }
+
+    for file in language.files() {
+        write_file(dir, file.path, &project.render(file.content))?;
+    }
+    for file in WIT {
+        write_file(dir, file.path, file.content)?;
+    }
+
+    Ok(())
+}
+
+fn write_file(dir: &Path, path: &str, content: &str) -> anyhow::Result<()> {
+    let path = dir.join(path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .context(format!("Creating directory `{}`", parent.to_string_lossy()))?;
+    }
+    fs::write(&path, content).context(format!("Writing `{}`", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vendored_wit_matches_workspace() {
+        let workspace = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../wit");
+        if !workspace.exists() {
+            return;
+        }
+        for file in WIT {
+            let path = file.path.strip_prefix("wit/").unwrap();
+            let expected = fs::read_to_string(workspace.join(path)).unwrap();
+            assert_eq!(
+                file.content, expected,
+                "`templates/wit/{path}` is out of date, copy it from `wit/`"
+            );
+        }
+    }
+}
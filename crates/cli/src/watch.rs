@@ -0,0 +1,151 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, mpsc},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use notify::{Event, RecursiveMode, Watcher as _};
+use wassel_server::PluginChanges;
+
+use crate::common::PluginBuildInfo;
+
+/// Time without further changes before a rebuild starts, so saving many
+/// files at once builds only once
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Directories holding build output and dependencies rather than sources
+const IGNORED_DIRECTORIES: &[&str] = &[
+    "target",
+    "node_modules",
+    ".git",
+    "__pycache__",
+    ".venv",
+    "gen",
+    ".wassel",
+];
+
+/// Watches plugin sources and reinstalls plugins with `install` as they
+/// change, ignoring changes inside `plugins_path` they are installed into.
+/// Every successfully installed plugin is pushed to the returned changes so
+/// the server can reload it.
+pub fn spawn_watcher<F>(
+    sources: &[PathBuf],
+    plugins_path: &Path,
+    install: F,
+) -> anyhow::Result<Arc<PluginChanges>>
+where
+    F: Fn(&Path) -> anyhow::Result<PluginBuildInfo> + Send + 'static,
+{
+    let sources = sources
+        .iter()
+        .map(|source| {
+            source.canonicalize().context(format!(
+                "Resolving plugin path `{}`",
+                source.to_string_lossy()
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Installing into a watched directory must not trigger another rebuild
+    std::fs::create_dir_all(plugins_path)
+        .context(format!("Creating `{}`", plugins_path.to_string_lossy()))?;
+    let plugins_path = plugins_path.canonicalize().context(format!(
+        "Resolving plugins path `{}`",
+        plugins_path.to_string_lossy()
+    ))?;
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).context("Creating file watcher")?;
+    for source in &sources {
+        watcher
+            .watch(source, RecursiveMode::Recursive)
+            .context(format!("Watching `{}`", source.to_string_lossy()))?;
+        println!("Watching `{}` for changes", source.to_string_lossy());
+    }
+
+    let changes = Arc::new(PluginChanges::default());
+    let pushed = changes.clone();
+    thread::Builder::new()
+        .name("wassel-watch".to_owned())
+        .spawn(move || {
+            // Dropping the watcher would stop the events
+            let _watcher = watcher;
+            while let Some(changed) = next_changes(&receiver, &sources, &plugins_path) {
+                for source in changed {
+                    rebuild(&source, &install, &pushed);
+                }
+            }
+        })
+        .context("Starting watch thread")?;
+
+    Ok(changes)
+}
+
+/// Blocks until some sources changed, returns `None` once the watcher is gone
+fn next_changes(
+    receiver: &mpsc::Receiver<notify::Result<Event>>,
+    sources: &[PathBuf],
+    plugins_path: &Path,
+) -> Option<Vec<PathBuf>> {
+    let mut changed = Vec::new();
+    while changed.is_empty() {
+        add_changes(&mut changed, receiver.recv().ok()?, sources, plugins_path);
+        while let Ok(event) = receiver.recv_timeout(DEBOUNCE) {
+            add_changes(&mut changed, event, sources, plugins_path);
+        }
+    }
+    Some(changed)
+}
+
+fn add_changes(
+    changed: &mut Vec<PathBuf>,
+    event: notify::Result<Event>,
+    sources: &[PathBuf],
+    plugins_path: &Path,
+) {
+    let event = match event {
+        Ok(event) if !event.kind.is_access() => event,
+        Ok(_) => return,
+        Err(e) => {
+            eprintln!("Error watching plugin sources: {e}");
+            return;
+        }
+    };
+
+    for path in event.paths.iter().filter(|p| !p.starts_with(plugins_path)) {
+        let source = sources
+            .iter()
+            .find(|source| path.starts_with(source) && !is_ignored(source, path));
+        if let Some(source) = source
+            && !changed.contains(source)
+        {
+            changed.push(source.clone());
+        }
+    }
+}
+
+fn is_ignored(source: &Path, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(source) else {
+        return true;
+    };
+    let in_ignored_directory = relative.components().any(|component| {
+        IGNORED_DIRECTORIES
+            .iter()
+            .any(|ignored| component.as_os_str() == *ignored)
+    });
+    in_ignored_directory || path.extension().is_some_and(|ext| ext == "wasm")
+}
+
+/// Builds and installs the plugin, keeping the installed version on failure
+fn rebuild<F>(source: &Path, install: &F, changes: &PluginChanges)
+where
+    F: Fn(&Path) -> anyhow::Result<PluginBuildInfo>,
+{
+    println!("Change in `{}`, rebuilding", source.to_string_lossy());
+    match install(source) {
+        Ok(info) => changes.push(&info.id),
+        Err(e) => eprintln!("Build failed, still serving the last good version: {e:#}"),
+    }
+}
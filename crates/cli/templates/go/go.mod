@@ -0,0 +1,7 @@
+module {{id}}
+
+go 1.24
+
+tool go.bytecodealliance.org/cmd/wit-bindgen-go
+
+require go.bytecodealliance.org/cm v0.3.0
@@ -0,0 +1,31 @@
+//go:generate go tool wit-bindgen-go generate --world http-plugin --out gen ./wit
+
+package main
+
+import (
+	httphandler "{{id}}/gen/wassel/foundation/http-handler"
+	"{{id}}/gen/wasi/http/types"
+
+	"go.bytecodealliance.org/cm"
+)
+
+func init() {
+	httphandler.Exports.HandleRequest = handleRequest
+}
+
+func handleRequest(request types.IncomingRequest, responseOut types.ResponseOutparam) {
+	path := request.PathWithQuery().Value()
+
+	headers := types.NewFields()
+	headers.Append("content-type", types.FieldValue(cm.ToList([]byte("text/plain; charset=utf-8"))))
+	response := types.NewOutgoingResponse(headers)
+	body := response.Body().OK()
+	types.ResponseOutparamSet(responseOut, cm.OK[cm.Result[types.ErrorCodeShape, types.OutgoingResponse, types.ErrorCode]](response))
+
+	stream := body.Write().OK()
+	stream.BlockingWriteAndFlush(cm.ToList([]byte("Hello from {{name}}, you requested `" + path + "`\n")))
+	stream.ResourceDrop()
+	types.OutgoingBodyFinish(*body, cm.None[types.Trailers]())
+}
+
+func main() {}
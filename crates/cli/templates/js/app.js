@@ -0,0 +1,27 @@
+import {
+  Fields,
+  OutgoingBody,
+  OutgoingResponse,
+  ResponseOutparam,
+} from "wasi:http/types@0.2.10";
+
+const encoder = new TextEncoder();
+
+export const httpHandler = {
+  handleRequest(request, responseOut) {
+    const path = request.pathWithQuery() ?? "";
+    const headers = Fields.fromList([
+      ["content-type", encoder.encode("text/plain; charset=utf-8")],
+    ]);
+    const response = new OutgoingResponse(headers);
+    const body = response.body();
+    ResponseOutparam.set(responseOut, { tag: "ok", val: response });
+
+    const stream = body.write();
+    stream.blockingWriteAndFlush(
+      encoder.encode(`Hello from {{name}}, you requested \`${path}\`\n`),
+    );
+    stream[Symbol.dispose]();
+    OutgoingBody.finish(body, undefined);
+  },
+};
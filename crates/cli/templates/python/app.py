@@ -0,0 +1,26 @@
+from componentize_py_types import Ok
+from wit_world import exports
+from wit_world.imports.types import (
+    Fields,
+    IncomingRequest,
+    OutgoingBody,
+    OutgoingResponse,
+    ResponseOutparam,
+)
+
+
+class HttpHandler(exports.HttpHandler):
+    def handle_request(
+        self, request: IncomingRequest, response_out: ResponseOutparam
+    ) -> None:
+        path = request.path_with_query() or ""
+        headers = Fields.from_list([("content-type", b"text/plain; charset=utf-8")])
+        response = OutgoingResponse(headers)
+        body = response.body()
+        ResponseOutparam.set(response_out, Ok(response))
+
+        with body.write() as stream:
+            stream.blocking_write_and_flush(
+                f"Hello from {{name}}, you requested `{path}`\n".encode()
+            )
+        OutgoingBody.finish(body, None)
@@ -0,0 +1,35 @@
+wit_bindgen::generate!({
+    path: "wit",
+    world: "wassel:foundation/http-plugin",
+    generate_all,
+});
+
+use exports::wassel::foundation::http_handler::Guest;
+use wasi::http::types::{Fields, IncomingRequest, OutgoingBody, OutgoingResponse, ResponseOutparam};
+
+struct Plugin;
+
+impl Guest for Plugin {
+    fn handle_request(request: IncomingRequest, response_out: ResponseOutparam) {
+        let path = request.path_with_query().unwrap_or_default();
+        let body = format!("Hello from {{name}}, you requested `{path}`\n");
+
+        let headers = Fields::new();
+        headers
+            .append("content-type", b"text/plain; charset=utf-8")
+            .expect("setting content type");
+        let response = OutgoingResponse::new(headers);
+        let response_body = response.body().expect("taking response body");
+        ResponseOutparam::set(response_out, Ok(response));
+
+        {
+            let stream = response_body.write().expect("taking body stream");
+            stream
+                .blocking_write_and_flush(body.as_bytes())
+                .expect("writing body");
+        }
+        OutgoingBody::finish(response_body, None).expect("finishing body");
+    }
+}
+
+export!(Plugin);
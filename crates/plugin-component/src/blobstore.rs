@@ -0,0 +1,715 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, Read as _, Write as _},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use wasmtime::component::Resource;
+use wassel_world::{
+    resources::{BlobContainer, BlobReader, BlobWriter},
+    wassel::foundation::blobstore::{self, Error, ObjectMetadata},
+};
+
+use crate::state::PluginState;
+
+/// Largest chunk returned by a single `read` call
+const MAX_READ_LENGTH: u64 = 1024 * 1024;
+
+/// Longest object name in bytes. Names are hashed into file names, so this
+/// only bounds the size of the metadata.
+const MAX_NAME_LENGTH: usize = 1024;
+
+/// Stack-wide settings of the blob store, read from `wassel.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobStoreSettings {
+    /// Directory with containers of all plugins, relative to the stack directory
+    #[serde(default = "default_blobstore_path")]
+    pub path: PathBuf,
+}
+
+impl Default for BlobStoreSettings {
+    fn default() -> Self {
+        Self {
+            path: default_blobstore_path(),
+        }
+    }
+}
+
+fn default_blobstore_path() -> PathBuf {
+    PathBuf::from(".wassel/blobs")
+}
+
+/// Containers a plugin is allowed to open, read from `plugin.toml`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PluginBlobStore {
+    #[serde(default = "HashMap::default")]
+    pub containers: HashMap<String, ContainerQuota>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContainerQuota {
+    #[serde(default = "Option::default")]
+    pub max_objects: Option<u64>,
+
+    /// Total size of all objects in the container
+    #[serde(default = "Option::default")]
+    pub max_bytes: Option<u64>,
+
+    #[serde(default = "Option::default")]
+    pub max_object_bytes: Option<u64>,
+}
+
+/// Containers of all plugins by directory, kept across stack reloads. The
+/// stack being replaced may still be finishing uploads, so a container is
+/// only opened, and left over uploads removed, when it is first used.
+#[derive(Clone, Default)]
+pub struct BlobContainers(Arc<Mutex<HashMap<PathBuf, Arc<ContainerStore>>>>);
+
+impl BlobContainers {
+    fn open(&self, dir: PathBuf) -> anyhow::Result<Arc<ContainerStore>> {
+        let mut containers = self
+            .0
+            .lock()
+            .expect("Blob containers lock should not be poisoned");
+        if let Some(container) = containers.get(&dir) {
+            return Ok(container.clone());
+        }
+        let container = Arc::new(ContainerStore::open(dir.clone())?);
+        containers.insert(dir, container.clone());
+        Ok(container)
+    }
+}
+
+/// Containers of a single plugin, shared between all of its instances
+#[derive(Default)]
+pub struct BlobStore {
+    containers: HashMap<String, Container>,
+}
+
+impl BlobStore {
+    pub fn new(
+        settings: &BlobStoreSettings,
+        opened: &BlobContainers,
+        plugin_id: &str,
+        plugin: &PluginBlobStore,
+    ) -> anyhow::Result<Self> {
+        let mut containers = HashMap::new();
+        for (name, quota) in &plugin.containers {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                anyhow::bail!(
+                    "Container name `{name}` may only contain ASCII letters, digits, `-` and `_`"
+                );
+            }
+            let store = opened
+                .open(settings.path.join(plugin_id).join(name))
+                .context(format!("Opening blob container `{name}`"))?;
+            let container = Container {
+                store,
+                quota: quota.clone(),
+            };
+            containers.insert(name.clone(), container);
+        }
+
+        Ok(Self { containers })
+    }
+
+    fn container(&self, name: &str) -> Result<&Container, Error> {
+        self.containers.get(name).ok_or(Error::NoSuchContainer)
+    }
+}
+
+/// Container as opened by a plugin, with the quota its current config sets
+struct Container {
+    store: Arc<ContainerStore>,
+    quota: ContainerQuota,
+}
+
+/// Metadata stored next to every object
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredMetadata {
+    name: String,
+    #[serde(default = "Option::default")]
+    content_type: Option<String>,
+    created_at: u64,
+}
+
+/// Objects are kept as `<name hash>.blob` with metadata holding the name in
+/// `<name hash>.meta`, uploads in progress as `<name hash>.<n>.part`
+struct ContainerStore {
+    dir: PathBuf,
+    objects: Mutex<Objects>,
+    /// Serializes changes to the container, held across their file operations
+    changes: tokio::sync::Mutex<()>,
+    next_upload: AtomicU64,
+}
+
+/// Metadata of stored objects along with their total size
+#[derive(Default)]
+struct Objects {
+    metadata: BTreeMap<String, ObjectMetadata>,
+    bytes: u64,
+}
+
+impl Objects {
+    fn insert(&mut self, metadata: ObjectMetadata) {
+        self.remove(&metadata.name);
+        self.bytes += metadata.size;
+        self.metadata.insert(metadata.name.clone(), metadata);
+    }
+
+    fn remove(&mut self, name: &str) -> Option<ObjectMetadata> {
+        let removed = self.metadata.remove(name)?;
+        self.bytes -= removed.size;
+        Some(removed)
+    }
+
+    fn check_count(&self, quota: &ContainerQuota, name: &str) -> Result<(), Error> {
+        if !self.metadata.contains_key(name)
+            && let Some(max_objects) = quota.max_objects
+            && self.metadata.len() as u64 >= max_objects
+        {
+            return Err(Error::QuotaExceeded(format!(
+                "Container object limit of {max_objects} exceeded"
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_size(&self, quota: &ContainerQuota, name: &str, size: u64) -> Result<(), Error> {
+        if let Some(max_object_bytes) = quota.max_object_bytes
+            && size > max_object_bytes
+        {
+            return Err(Error::QuotaExceeded(format!(
+                "Object size limit of {max_object_bytes} bytes exceeded"
+            )));
+        }
+
+        if let Some(max_bytes) = quota.max_bytes {
+            let old_size = self.metadata.get(name).map(|o| o.size).unwrap_or_default();
+            if self.bytes - old_size + size > max_bytes {
+                return Err(Error::QuotaExceeded(format!(
+                    "Container size limit of {max_bytes} bytes exceeded"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ContainerStore {
+    /// Reads the objects of the directory and removes unfinished uploads
+    fn open(dir: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).context(format!(
+            "Creating blob directory `{}`",
+            dir.to_string_lossy()
+        ))?;
+
+        let mut objects = Objects::default();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("part" | "tmp") => fs::remove_file(&path)?,
+                Some("meta") => {
+                    let stored: StoredMetadata = toml::from_slice(&fs::read(&path)?)
+                        .context(format!("Reading metadata `{}`", path.to_string_lossy()))?;
+                    let Ok(blob) = fs::metadata(path.with_extension("blob")) else {
+                        continue;
+                    };
+                    objects.insert(ObjectMetadata {
+                        name: stored.name,
+                        size: blob.len(),
+                        content_type: stored.content_type,
+                        created_at: stored.created_at,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            dir,
+            objects: Mutex::new(objects),
+            changes: tokio::sync::Mutex::new(()),
+            next_upload: AtomicU64::new(0),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Objects> {
+        self.objects
+            .lock()
+            .expect("Blob container lock should not be poisoned")
+    }
+
+    fn path(&self, name: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{extension}", encode_name(name)))
+    }
+
+    async fn begin_put(
+        &self,
+        quota: &ContainerQuota,
+        name: &str,
+    ) -> Result<(PathBuf, File), Error> {
+        validate_name(name)?;
+        self.lock().check_count(quota, name)?;
+
+        let upload = self.next_upload.fetch_add(1, Ordering::Relaxed);
+        let path = self.path(name, &format!("{upload}.part"));
+        let file = {
+            let path = path.clone();
+            blocking(move || File::create(path)).await?
+        };
+        Ok((path, file))
+    }
+
+    fn check_write(&self, quota: &ContainerQuota, name: &str, size: u64) -> Result<(), Error> {
+        self.lock().check_size(quota, name, size)
+    }
+
+    async fn finish_put(
+        &self,
+        quota: &ContainerQuota,
+        writer: &BlobWriter,
+    ) -> Result<ObjectMetadata, Error> {
+        let _changes = self.changes.lock().await;
+        {
+            let objects = self.lock();
+            objects.check_count(quota, &writer.name)?;
+            objects.check_size(quota, &writer.name, writer.written)?;
+        }
+
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let stored = StoredMetadata {
+            name: writer.name.clone(),
+            content_type: writer.content_type.clone(),
+            created_at,
+        };
+        let stored = toml::to_string(&stored)
+            .map_err(|e| Error::Other(format!("Serializing metadata: {e}")))?;
+
+        let meta_path = self.path(&writer.name, "meta");
+        let blob_path = self.path(&writer.name, "blob");
+        let part_path = writer.path.clone();
+        blocking(move || {
+            let meta_tmp = meta_path.with_extension("meta.tmp");
+            fs::write(&meta_tmp, stored)?;
+            fs::rename(&meta_tmp, &meta_path)?;
+            fs::rename(&part_path, blob_path)
+        })
+        .await?;
+
+        let metadata = ObjectMetadata {
+            name: writer.name.clone(),
+            size: writer.written,
+            content_type: writer.content_type.clone(),
+            created_at,
+        };
+        self.lock().insert(metadata.clone());
+        Ok(metadata)
+    }
+
+    async fn get(&self, name: &str) -> Result<BlobReader, Error> {
+        let metadata = self
+            .lock()
+            .metadata
+            .get(name)
+            .cloned()
+            .ok_or(Error::NotFound)?;
+        let path = self.path(name, "blob");
+        let file = blocking(move || File::open(path)).await?;
+        Ok(BlobReader { metadata, file })
+    }
+
+    fn metadata(&self, name: &str) -> Result<ObjectMetadata, Error> {
+        self.lock()
+            .metadata
+            .get(name)
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), Error> {
+        let _changes = self.changes.lock().await;
+        if !self.lock().metadata.contains_key(name) {
+            return Err(Error::NotFound);
+        }
+        let paths = [self.path(name, "blob"), self.path(name, "meta")];
+        blocking(move || {
+            for path in paths {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+        .await?;
+        self.lock().remove(name);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Vec<ObjectMetadata> {
+        self.lock()
+            .metadata
+            .range(prefix.to_owned()..)
+            .take_while(|(name, _)| name.starts_with(prefix))
+            .map(|(_, metadata)| metadata.clone())
+            .collect()
+    }
+}
+
+/// Runs file operations off the async runtime
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> Result<T, Error> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::Other(format!("Blob storage task failed: {e}")))?
+        .map_err(convert_io_error)
+}
+
+fn validate_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(Error::InvalidName);
+    }
+    Ok(())
+}
+
+/// Fixed-length file name of an object, whatever the length of its name
+fn encode_name(name: &str) -> String {
+    format!("{:x}", Sha256::digest(name.as_bytes()))
+}
+
+fn convert_io_error(e: io::Error) -> Error {
+    Error::Other(format!("Blob storage error: {e}"))
+}
+
+impl PluginState {
+    fn blob_container(&self, container: &Resource<BlobContainer>) -> Result<&Container, Error> {
+        let container = self
+            .table
+            .get(container)
+            .map_err(|e| Error::Other(format!("Could not get container resource: {e:?}")))?;
+        self.services.blobstore.container(&container.name)
+    }
+}
+
+impl blobstore::Host for PluginState {}
+
+impl blobstore::HostContainer for PluginState {
+    async fn open(&mut self, name: String) -> Result<Resource<BlobContainer>, Error> {
+        self.services.blobstore.container(&name)?;
+        self.table
+            .push(BlobContainer { name })
+            .map_err(|e| Error::Other(format!("Could not create container resource: {e:?}")))
+    }
+
+    async fn name(&mut self, container: Resource<BlobContainer>) -> String {
+        self.table
+            .get(&container)
+            .map(|c| c.name.clone())
+            .unwrap_or_default()
+    }
+
+    async fn put(
+        &mut self,
+        container: Resource<BlobContainer>,
+        name: String,
+        content_type: Option<String>,
+    ) -> Result<Resource<BlobWriter>, Error> {
+        let store = self.blob_container(&container)?;
+        let (path, file) = store.store.begin_put(&store.quota, &name).await?;
+        let container = self
+            .table
+            .get(&container)
+            .map_err(|e| Error::Other(format!("Could not get container resource: {e:?}")))?
+            .name
+            .clone();
+        let writer = BlobWriter {
+            container,
+            name,
+            content_type,
+            path,
+            file: Some(file),
+            written: 0,
+        };
+        self.table
+            .push(writer)
+            .map_err(|e| Error::Other(format!("Could not create writer resource: {e:?}")))
+    }
+
+    async fn get(
+        &mut self,
+        container: Resource<BlobContainer>,
+        name: String,
+    ) -> Result<Resource<BlobReader>, Error> {
+        let reader = self.blob_container(&container)?.store.get(&name).await?;
+        self.table
+            .push(reader)
+            .map_err(|e| Error::Other(format!("Could not create reader resource: {e:?}")))
+    }
+
+    async fn metadata(
+        &mut self,
+        container: Resource<BlobContainer>,
+        name: String,
+    ) -> Result<ObjectMetadata, Error> {
+        self.blob_container(&container)?.store.metadata(&name)
+    }
+
+    async fn delete(
+        &mut self,
+        container: Resource<BlobContainer>,
+        name: String,
+    ) -> Result<(), Error> {
+        self.blob_container(&container)?.store.delete(&name).await
+    }
+
+    async fn list_objects(
+        &mut self,
+        container: Resource<BlobContainer>,
+        prefix: String,
+    ) -> Result<Vec<ObjectMetadata>, Error> {
+        Ok(self.blob_container(&container)?.store.list(&prefix))
+    }
+
+    async fn drop(&mut self, container: Resource<BlobContainer>) -> wasmtime::Result<()> {
+        self.table.delete(container)?;
+        Ok(())
+    }
+}
+
+impl blobstore::HostBlobWriter for PluginState {
+    async fn write(&mut self, writer: Resource<BlobWriter>, chunk: Vec<u8>) -> Result<(), Error> {
+        let writer = self
+            .table
+            .get_mut(&writer)
+            .map_err(|e| Error::Other(format!("Could not get writer resource: {e:?}")))?;
+        let container = self.services.blobstore.container(&writer.container)?;
+        let Some(file) = &writer.file else {
+            return Err(Error::Other("Object is already finished".to_owned()));
+        };
+
+        let written = writer.written + chunk.len() as u64;
+        container
+            .store
+            .check_write(&container.quota, &writer.name, written)?;
+        // Shares the file offset, so writes continue where the last one ended
+        let mut file = file.try_clone().map_err(convert_io_error)?;
+        blocking(move || file.write_all(&chunk)).await?;
+        writer.written = written;
+        Ok(())
+    }
+
+    async fn finish(&mut self, writer: Resource<BlobWriter>) -> Result<ObjectMetadata, Error> {
+        let writer = self
+            .table
+            .get_mut(&writer)
+            .map_err(|e| Error::Other(format!("Could not get writer resource: {e:?}")))?;
+        let container = self.services.blobstore.container(&writer.container)?;
+        let Some(mut file) = writer.file.take() else {
+            return Err(Error::Other("Object is already finished".to_owned()));
+        };
+
+        blocking(move || file.flush()).await?;
+        let result = container.store.finish_put(&container.quota, writer).await;
+        if result.is_err() {
+            remove_upload(writer.path.clone()).await;
+        }
+        result
+    }
+
+    async fn drop(&mut self, writer: Resource<BlobWriter>) -> wasmtime::Result<()> {
+        let writer = self.table.delete(writer)?;
+        if writer.file.is_some() {
+            remove_upload(writer.path).await;
+        }
+        Ok(())
+    }
+}
+
+/// Removes the temporary file of an upload that did not finish
+async fn remove_upload(path: PathBuf) {
+    let _ = blocking(move || fs::remove_file(path)).await;
+}
+
+impl blobstore::HostBlobReader for PluginState {
+    async fn metadata(&mut self, reader: Resource<BlobReader>) -> ObjectMetadata {
+        self.table
+            .get(&reader)
+            .map(|r| r.metadata.clone())
+            .unwrap_or_else(|_| ObjectMetadata {
+                name: String::new(),
+                size: 0,
+                content_type: None,
+                created_at: 0,
+            })
+    }
+
+    async fn read(&mut self, reader: Resource<BlobReader>, len: u64) -> Result<Vec<u8>, Error> {
+        let reader = self
+            .table
+            .get_mut(&reader)
+            .map_err(|e| Error::Other(format!("Could not get reader resource: {e:?}")))?;
+        // Shares the file offset, so reads continue where the last one ended
+        let mut file = reader.file.try_clone().map_err(convert_io_error)?;
+        blocking(move || {
+            let mut buffer = vec![0; len.min(MAX_READ_LENGTH) as usize];
+            let read = file.read(&mut buffer)?;
+            buffer.truncate(read);
+            Ok(buffer)
+        })
+        .await
+    }
+
+    async fn drop(&mut self, reader: Resource<BlobReader>) -> wasmtime::Result<()> {
+        self.table.delete(reader)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn put(
+        store: &ContainerStore,
+        quota: &ContainerQuota,
+        name: &str,
+        data: &[u8],
+    ) -> Result<ObjectMetadata, Error> {
+        let (path, mut file) = store.begin_put(quota, name).await?;
+        store.check_write(quota, name, data.len() as u64)?;
+        file.write_all(data).unwrap();
+        drop(file);
+        store
+            .finish_put(
+                quota,
+                &BlobWriter {
+                    container: "test".to_owned(),
+                    name: name.to_owned(),
+                    content_type: Some("text/plain".to_owned()),
+                    path,
+                    file: None,
+                    written: data.len() as u64,
+                },
+            )
+            .await
+    }
+
+    async fn read(store: &ContainerStore, name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        store
+            .get(name)
+            .await
+            .unwrap()
+            .file
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn objects_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota = ContainerQuota::default();
+        let long_name = "nested/".repeat(100) + "object.txt";
+        {
+            let store = ContainerStore::open(dir.path().into()).unwrap();
+            put(&store, &quota, "a.txt", b"first").await.unwrap();
+            put(&store, &quota, &long_name, b"second").await.unwrap();
+            put(&store, &quota, "a.txt", b"replaced").await.unwrap();
+        }
+
+        let store = ContainerStore::open(dir.path().into()).unwrap();
+        assert_eq!(read(&store, "a.txt").await, b"replaced");
+        assert_eq!(read(&store, &long_name).await, b"second");
+        let metadata = store.metadata(&long_name).unwrap();
+        assert_eq!(metadata.size, 6);
+        assert_eq!(metadata.content_type.as_deref(), Some("text/plain"));
+
+        store.delete("a.txt").await.unwrap();
+        assert!(matches!(store.get("a.txt").await, Err(Error::NotFound)));
+        assert!(matches!(store.delete("a.txt").await, Err(Error::NotFound)));
+        assert_eq!(store.lock().bytes, 6);
+    }
+
+    #[tokio::test]
+    async fn list_filters_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota = ContainerQuota::default();
+        let store = ContainerStore::open(dir.path().into()).unwrap();
+        for name in ["img/a.png", "img/b.png", "doc/c.txt"] {
+            put(&store, &quota, name, b"").await.unwrap();
+        }
+
+        let names: Vec<String> = store.list("img/").into_iter().map(|o| o.name).collect();
+        assert_eq!(names, ["img/a.png", "img/b.png"]);
+        assert_eq!(store.list("").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn quotas_are_enforced() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota = ContainerQuota {
+            max_objects: Some(2),
+            max_bytes: Some(10),
+            max_object_bytes: Some(6),
+        };
+        let store = ContainerStore::open(dir.path().into()).unwrap();
+
+        put(&store, &quota, "a", b"12345").await.unwrap();
+        assert!(matches!(
+            put(&store, &quota, "b", b"1234567").await,
+            Err(Error::QuotaExceeded(_))
+        ));
+        put(&store, &quota, "b", b"12345").await.unwrap();
+        assert!(matches!(
+            put(&store, &quota, "c", b"").await,
+            Err(Error::QuotaExceeded(_))
+        ));
+        assert!(matches!(
+            put(&store, &quota, "a", b"123456").await,
+            Err(Error::QuotaExceeded(_))
+        ));
+        assert!(matches!(
+            put(&store, &quota, "", b"").await,
+            Err(Error::InvalidName)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unfinished_uploads_are_removed_on_first_open_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota = ContainerQuota::default();
+        {
+            let store = ContainerStore::open(dir.path().into()).unwrap();
+            store.begin_put(&quota, "pending").await.unwrap();
+        }
+
+        let containers = BlobContainers::default();
+        let store = containers.open(dir.path().into()).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // A reloaded stack shares the container and its uploads in progress
+        store.begin_put(&quota, "pending").await.unwrap();
+        let reopened = containers.open(dir.path().into()).unwrap();
+        assert!(Arc::ptr_eq(&store, &reopened));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
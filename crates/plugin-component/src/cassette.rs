@@ -0,0 +1,365 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context as _;
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::secrets::{PluginSecrets, REDACTED};
+
+/// How outbound HTTP traffic of plugins is handled
+#[derive(Debug, Clone, Default)]
+pub enum OutboundMode {
+    /// Requests are sent to the network
+    #[default]
+    Live,
+
+    /// Requests are sent to the network and saved to cassettes in the directory
+    Record(PathBuf),
+
+    /// Requests are served from cassettes in the directory, never touching the network
+    Replay(PathBuf),
+}
+
+/// Recorded outbound HTTP traffic of a single plugin
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    #[serde(default = "Vec::default")]
+    pub interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub uri: String,
+    #[serde(default = "Vec::default")]
+    pub headers: Vec<(String, String)>,
+    #[serde(flatten)]
+    pub body: RecordedBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default = "Vec::default")]
+    pub headers: Vec<(String, String)>,
+    #[serde(flatten)]
+    pub body: RecordedBody,
+}
+
+/// Body is kept readable in cassette when it is valid UTF-8
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedBody {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_base64: Option<String>,
+}
+
+impl RecordedBody {
+    pub fn new(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Self {
+                body: Some(s.to_owned()),
+                body_base64: None,
+            },
+            Err(_) => Self {
+                body: None,
+                body_base64: Some(BASE64_STANDARD.encode(bytes)),
+            },
+        }
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Bytes> {
+        if let Some(body) = &self.body {
+            return Ok(Bytes::copy_from_slice(body.as_bytes()));
+        }
+        if let Some(body) = &self.body_base64 {
+            let bytes = BASE64_STANDARD
+                .decode(body)
+                .context("Decoding recorded body")?;
+            return Ok(Bytes::from(bytes));
+        }
+        Ok(Bytes::new())
+    }
+}
+
+/// Cassette attached to a plugin, shared between all of its instances
+pub struct CassetteFile {
+    path: PathBuf,
+    mode: CassetteMode,
+    state: Mutex<CassetteState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CassetteMode {
+    Record,
+    Replay,
+}
+
+#[derive(Default)]
+struct CassetteState {
+    cassette: Cassette,
+    /// Number of times each interaction was replayed, so identical requests
+    /// are answered in the order they were recorded
+    played: HashMap<usize, usize>,
+}
+
+impl CassetteFile {
+    pub fn for_plugin(mode: &OutboundMode, plugin_id: &str) -> anyhow::Result<Option<Self>> {
+        let (dir, mode) = match mode {
+            OutboundMode::Live => return Ok(None),
+            OutboundMode::Record(dir) => (dir, CassetteMode::Record),
+            OutboundMode::Replay(dir) => (dir, CassetteMode::Replay),
+        };
+
+        let path = dir.join(format!("{plugin_id}.toml"));
+        let cassette = match mode {
+            CassetteMode::Record => {
+                fs::create_dir_all(dir).context(format!(
+                    "Creating cassette directory `{}`",
+                    dir.to_string_lossy()
+                ))?;
+                Cassette::default()
+            }
+            CassetteMode::Replay => read_cassette(&path)?,
+        };
+
+        Ok(Some(Self {
+            path,
+            mode,
+            state: Mutex::new(CassetteState {
+                cassette,
+                played: HashMap::new(),
+            }),
+        }))
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.mode == CassetteMode::Replay
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, interaction: Interaction) -> anyhow::Result<()> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("Cassette lock should not be poisoned");
+        state.cassette.interactions.push(interaction);
+        let contents = toml::to_string_pretty(&state.cassette).context("Serializing cassette")?;
+        fs::write(&self.path, contents).context(format!(
+            "Writing cassette `{}`",
+            self.path.to_string_lossy()
+        ))?;
+        Ok(())
+    }
+
+    /// Finds recorded response for the request. Identical requests get recorded
+    /// responses in order, the last one is repeated once they run out.
+    pub fn replay(
+        &self,
+        method: &Method,
+        uri: &str,
+        body: &RecordedBody,
+    ) -> Option<RecordedResponse> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("Cassette lock should not be poisoned");
+        let candidates: Vec<usize> = state
+            .cassette
+            .interactions
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| {
+                i.request.method == method.as_str()
+                    && i.request.uri == uri
+                    && i.request.body == *body
+            })
+            .map(|(index, _)| index)
+            .collect();
+
+        let played = candidates
+            .iter()
+            .map(|i| state.played.get(i).copied().unwrap_or_default())
+            .sum::<usize>();
+        let index = *candidates.get(played).or(candidates.last())?;
+        *state.played.entry(index).or_default() += 1;
+
+        Some(state.cassette.interactions[index].response.clone())
+    }
+}
+
+fn read_cassette(path: &Path) -> anyhow::Result<Cassette> {
+    if !path.exists() {
+        return Ok(Cassette::default());
+    }
+
+    let contents =
+        fs::read(path).context(format!("Reading cassette `{}`", path.to_string_lossy()))?;
+    toml::from_slice(&contents).context(format!(
+        "Deserializing cassette `{}`",
+        path.to_string_lossy()
+    ))
+}
+
+/// Headers carrying credentials, whose values never end up in cassettes
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+/// Parts of header names which mark them as carrying credentials
+const SENSITIVE_HEADER_PARTS: &[&str] = &["api-key", "apikey", "token", "secret", "password"];
+
+fn is_sensitive_header(name: &HeaderName) -> bool {
+    let name = name.as_str();
+    SENSITIVE_HEADERS.contains(&name) || SENSITIVE_HEADER_PARTS.iter().any(|p| name.contains(p))
+}
+
+pub fn headers_to_recorded(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_str().to_owned(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// Converts headers for a cassette, replacing credentials and secret values
+/// of the plugin with a placeholder
+pub fn redacted_headers_to_recorded(
+    headers: &HeaderMap,
+    secrets: &PluginSecrets,
+) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_sensitive_header(name) {
+                REDACTED.to_owned()
+            } else {
+                secrets
+                    .redact(&String::from_utf8_lossy(value.as_bytes()))
+                    .into_owned()
+            };
+            (name.as_str().to_owned(), value)
+        })
+        .collect()
+}
+
+pub fn recorded_to_headers(headers: &[(String, String)]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) else {
+            continue;
+        };
+        map.append(name, value);
+    }
+    map
+}
+
+pub fn recorded_status(status: u16) -> StatusCode {
+    StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interaction(uri: &str, response: &str) -> Interaction {
+        Interaction {
+            request: RecordedRequest {
+                method: "GET".to_owned(),
+                uri: uri.to_owned(),
+                headers: Vec::new(),
+                body: RecordedBody::new(b""),
+            },
+            response: RecordedResponse {
+                status: 200,
+                headers: Vec::new(),
+                body: RecordedBody::new(response.as_bytes()),
+            },
+        }
+    }
+
+    #[test]
+    fn replays_recorded_responses_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = CassetteFile::for_plugin(&OutboundMode::Record(dir.path().into()), "p")
+            .unwrap()
+            .unwrap();
+        recorder
+            .record(interaction("http://example.com/a", "first"))
+            .unwrap();
+        recorder
+            .record(interaction("http://example.com/a", "second"))
+            .unwrap();
+
+        let player = CassetteFile::for_plugin(&OutboundMode::Replay(dir.path().into()), "p")
+            .unwrap()
+            .unwrap();
+        let body = RecordedBody::new(b"");
+        let replay = || {
+            player
+                .replay(&Method::GET, "http://example.com/a", &body)
+                .unwrap()
+                .body
+                .body
+                .unwrap()
+        };
+        assert_eq!(replay(), "first");
+        assert_eq!(replay(), "second");
+        assert_eq!(replay(), "second");
+
+        assert!(
+            player
+                .replay(&Method::GET, "http://example.com/b", &body)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn redacts_credential_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer abc"));
+        headers.insert("x-api-key", HeaderValue::from_static("abc"));
+        headers.insert("accept", HeaderValue::from_static("text/plain"));
+        headers.insert("x-custom", HeaderValue::from_static("key s3cr3t"));
+        let secrets = PluginSecrets::with_values([("key", "s3cr3t")]);
+
+        let recorded = redacted_headers_to_recorded(&headers, &secrets);
+        let value = |name: &str| {
+            recorded
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(value("authorization"), Some(REDACTED));
+        assert_eq!(value("x-api-key"), Some(REDACTED));
+        assert_eq!(value("accept"), Some("text/plain"));
+        assert_eq!(value("x-custom"), Some("key [REDACTED]"));
+    }
+}
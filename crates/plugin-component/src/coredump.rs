@@ -0,0 +1,285 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use tracing::{Span, error, info, warn};
+use wasmtime::{Store, WasmBacktrace, WasmCoreDump};
+
+use crate::{profiling::file_name_part, state::PluginState};
+
+const DUMP_EXTENSION: &str = "coredump";
+const INFO_EXTENSION: &str = "json";
+
+/// `[coredump]` section of `wassel.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoreDumpSettings {
+    /// Captures a core dump on every trap, which makes traps slower
+    #[serde(default = "bool::default")]
+    pub enabled: bool,
+
+    /// Directory dumps are written to, relative to the stack directory
+    #[serde(default = "default_directory")]
+    pub directory: PathBuf,
+
+    /// Most dumps kept, the oldest ones are removed first
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+
+    /// Dumps older than this are removed
+    #[serde(default = "default_max_age_hours")]
+    pub max_age_hours: u64,
+}
+
+impl Default for CoreDumpSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: default_directory(),
+            max_files: default_max_files(),
+            max_age_hours: default_max_age_hours(),
+        }
+    }
+}
+
+fn default_directory() -> PathBuf {
+    PathBuf::from(".wassel/coredumps")
+}
+
+fn default_max_files() -> usize {
+    20
+}
+
+fn default_max_age_hours() -> u64 {
+    7 * 24
+}
+
+/// Details of a dump, stored next to it in a JSON file of the same name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoreDumpInfo {
+    /// File name of the dump itself
+    pub file: String,
+    pub plugin_id: String,
+    pub plugin_version: String,
+    pub request_id: Option<String>,
+    /// RFC 3339 time of the trap
+    pub time: String,
+    pub message: String,
+    pub backtrace: Option<String>,
+    pub size: u64,
+}
+
+/// Directory of core dumps shared by all plugins of a stack
+#[derive(Debug)]
+pub struct CoreDumps {
+    settings: CoreDumpSettings,
+}
+
+impl CoreDumps {
+    pub fn new(settings: CoreDumpSettings) -> Self {
+        Self { settings }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    pub fn for_plugin(self: &Arc<Self>, plugin_id: &str, plugin_version: &str) -> PluginCoreDumps {
+        PluginCoreDumps {
+            dumps: self.clone(),
+            plugin_id: Arc::from(plugin_id),
+            plugin_version: Arc::from(plugin_version),
+        }
+    }
+
+    /// Details of all dumps in the directory, newest first
+    pub fn list(settings: &CoreDumpSettings) -> anyhow::Result<Vec<CoreDumpInfo>> {
+        let dir = &settings.directory;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut dumps = Vec::new();
+        for entry in fs::read_dir(dir).context(format!(
+            "Reading core dump directory `{}`",
+            dir.to_string_lossy()
+        ))? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != INFO_EXTENSION) {
+                continue;
+            }
+            let info = fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(serde_json::from_slice::<CoreDumpInfo>(&bytes)?));
+            match info {
+                Ok(info) => dumps.push(info),
+                Err(e) => warn!(
+                    "Skipping unreadable core dump details `{}`: {e:#}",
+                    path.to_string_lossy()
+                ),
+            }
+        }
+        dumps.sort_by(|a, b| b.file.cmp(&a.file));
+        Ok(dumps)
+    }
+
+    fn write(&self, dump: &CoreDumpInfo, bytes: &[u8]) -> anyhow::Result<PathBuf> {
+        let dir = &self.settings.directory;
+        fs::create_dir_all(dir).context(format!(
+            "Creating core dump directory `{}`",
+            dir.to_string_lossy()
+        ))?;
+
+        let path = dir.join(&dump.file);
+        fs::write(&path, bytes)
+            .context(format!("Writing core dump `{}`", path.to_string_lossy()))?;
+        let info = serde_json::to_vec_pretty(dump).context("Serializing core dump details")?;
+        fs::write(path.with_extension(INFO_EXTENSION), info).context(format!(
+            "Writing details of core dump `{}`",
+            path.to_string_lossy()
+        ))?;
+        Ok(path)
+    }
+
+    /// Removes dumps beyond the retention limits
+    fn prune(&self) -> anyhow::Result<()> {
+        let dir = &self.settings.directory;
+        let mut dumps = fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        dumps.retain(|path| path.extension().is_some_and(|ext| ext == DUMP_EXTENSION));
+        // Names start with the time of the trap
+        dumps.sort();
+        dumps.reverse();
+
+        let max_age = Duration::from_secs(self.settings.max_age_hours * 60 * 60);
+        let now = SystemTime::now();
+        for (i, path) in dumps.iter().enumerate() {
+            let expired = fs::metadata(path)
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() > max_age);
+            if i >= self.settings.max_files || expired {
+                remove_dump(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn remove_dump(path: &Path) {
+    for path in [path.to_owned(), path.with_extension(INFO_EXTENSION)] {
+        if let Err(e) = fs::remove_file(&path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!(
+                "Could not remove core dump `{}`: {e}",
+                path.to_string_lossy()
+            );
+        }
+    }
+}
+
+/// Core dumps of a single plugin
+#[derive(Debug, Clone)]
+pub struct PluginCoreDumps {
+    dumps: Arc<CoreDumps>,
+    plugin_id: Arc<str>,
+    plugin_version: Arc<str>,
+}
+
+impl PluginCoreDumps {
+    /// Writes the dump attached to a trap, if there is one
+    pub(crate) fn record(&self, store: &mut Store<PluginState>, error: &wasmtime::Error) {
+        let Some(dump) = error.downcast_ref::<WasmCoreDump>() else {
+            return;
+        };
+
+        let now = chrono::Utc::now();
+        let request_id = store.data().request_id.clone();
+        let file = format!(
+            "{time}_{id}_{version}_{request}.{DUMP_EXTENSION}",
+            time = now.format("%Y%m%dT%H%M%S%.3fZ"),
+            id = file_name_part(&self.plugin_id),
+            version = file_name_part(&self.plugin_version),
+            request = file_name_part(request_id.as_deref().unwrap_or("none")),
+        );
+        let bytes = dump.serialize(&mut *store, &self.plugin_id);
+        let info = CoreDumpInfo {
+            file,
+            plugin_id: self.plugin_id.to_string(),
+            plugin_version: self.plugin_version.to_string(),
+            request_id,
+            time: now.to_rfc3339(),
+            message: error.root_cause().to_string(),
+            backtrace: error
+                .downcast_ref::<WasmBacktrace>()
+                .map(ToString::to_string),
+            size: bytes.len() as u64,
+        };
+
+        // Dumps can be large, so they are written off the async runtime
+        // while the failed request carries on
+        let dumps = self.dumps.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            match dumps.write(&info, &bytes) {
+                Ok(path) => info!("Wrote core dump to `{}`", path.to_string_lossy()),
+                Err(e) => error!("Could not write core dump of `{}`: {e:#}", info.plugin_id),
+            }
+            if let Err(e) = dumps.prune() {
+                warn!("Could not prune core dumps: {e:#}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(file: &str) -> CoreDumpInfo {
+        CoreDumpInfo {
+            file: file.to_owned(),
+            plugin_id: "p".to_owned(),
+            plugin_version: "1.0.0".to_owned(),
+            request_id: None,
+            time: String::new(),
+            message: "unreachable".to_owned(),
+            backtrace: None,
+            size: 4,
+        }
+    }
+
+    #[test]
+    fn oldest_dumps_are_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = CoreDumpSettings {
+            enabled: true,
+            directory: dir.path().into(),
+            max_files: 2,
+            ..Default::default()
+        };
+        let dumps = CoreDumps::new(settings.clone());
+        for time in ["20260101T000000", "20260101T000001", "20260101T000002"] {
+            dumps
+                .write(&dump(&format!("{time}_p.{DUMP_EXTENSION}")), b"dump")
+                .unwrap();
+        }
+
+        dumps.prune().unwrap();
+        let files: Vec<_> = CoreDumps::list(&settings)
+            .unwrap()
+            .into_iter()
+            .map(|d| d.file)
+            .collect();
+        assert_eq!(
+            files,
+            ["20260101T000002_p.coredump", "20260101T000001_p.coredump"]
+        );
+    }
+}
@@ -17,4 +17,20 @@ pub enum PluginHandleError {
 
     #[error("Could not create component guest")]
     Guest(wasmtime::Error),
+
+    /// Host failed to set up an instance, before any guest code ran
+    #[error("Could not instantiate plugin: {0:#}")]
+    Instantiate(anyhow::Error),
+
+    #[error("Plugin does not export `{0}`")]
+    MissingExport(&'static str),
+
+    #[error("Scheduled handler returned error: {0}")]
+    Scheduled(String),
+
+    #[error("Message handler returned error: {0}")]
+    Message(String),
+
+    #[error("Invalid WebSocket upgrade request")]
+    InvalidUpgrade,
 }
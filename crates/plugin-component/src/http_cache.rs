@@ -0,0 +1,740 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header, request};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tracing::{debug, error};
+
+use crate::cassette::{self, RecordedBody};
+
+/// Stack-wide settings of the outbound HTTP cache, read from `wassel.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpCacheSettings {
+    #[serde(default = "HttpCacheBackend::default")]
+    pub backend: HttpCacheBackend,
+
+    /// Directory for the disk backend, relative to the stack directory
+    #[serde(default = "default_cache_path")]
+    pub path: PathBuf,
+}
+
+impl Default for HttpCacheSettings {
+    fn default() -> Self {
+        Self {
+            backend: HttpCacheBackend::default(),
+            path: default_cache_path(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpCacheBackend {
+    #[default]
+    Memory,
+    Disk,
+}
+
+/// Per-plugin opt-in into the outbound HTTP cache, read from `plugin.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginHttpCache {
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_cache_path() -> PathBuf {
+    PathBuf::from(".wassel/http-cache")
+}
+
+fn default_max_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+/// Shared cache of outbound responses of a single plugin, following the
+/// rules RFC 9111 sets for shared caches
+pub struct HttpCache {
+    storage: Arc<dyn CacheStorage>,
+    max_bytes: u64,
+    index: Mutex<CacheIndex>,
+}
+
+#[derive(Default)]
+struct CacheIndex {
+    sizes: HashMap<String, u64>,
+    /// Keys from least to most recently used
+    order: VecDeque<String>,
+    total: u64,
+}
+
+impl CacheIndex {
+    fn touch(&mut self, key: &str) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(pos).expect("Position should be valid");
+            self.order.push_back(key);
+        }
+    }
+
+    fn insert(&mut self, key: &str, size: u64) {
+        self.remove(key);
+        self.sizes.insert(key.to_owned(), size);
+        self.order.push_back(key.to_owned());
+        self.total += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(size) = self.sizes.remove(key) {
+            self.total -= size;
+            self.order.retain(|k| k != key);
+        }
+    }
+}
+
+/// Stored response together with data needed to calculate its age
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(flatten)]
+    pub body: RecordedBody,
+    /// Values of request headers nominated by `Vary`
+    #[serde(default = "Vec::default")]
+    pub vary: Vec<VaryHeader>,
+    pub request_time: u64,
+    pub response_time: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VaryHeader {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+impl CacheEntry {
+    pub fn new(
+        request: &request::Parts,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &Bytes,
+        request_time: SystemTime,
+        response_time: SystemTime,
+    ) -> Self {
+        let vary = vary_names(headers)
+            .into_iter()
+            .map(|name| {
+                let value = request
+                    .headers
+                    .get(&name)
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+                VaryHeader { name, value }
+            })
+            .collect();
+
+        Self {
+            status: status.as_u16(),
+            headers: cassette::headers_to_recorded(headers),
+            body: RecordedBody::new(body),
+            vary,
+            request_time: unix_secs(request_time),
+            response_time: unix_secs(response_time),
+        }
+    }
+
+    pub fn headers(&self) -> HeaderMap {
+        cassette::recorded_to_headers(&self.headers)
+    }
+
+    fn size(&self) -> u64 {
+        let body = self.body.body.as_ref().or(self.body.body_base64.as_ref());
+        let headers: usize = self.headers.iter().map(|(n, v)| n.len() + v.len()).sum();
+        (body.map(String::len).unwrap_or_default() + headers) as u64
+    }
+
+    fn matches(&self, request: &request::Parts) -> bool {
+        self.vary.iter().all(|vary| {
+            let current = request
+                .headers
+                .get(&vary.name)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+            current == vary.value
+        })
+    }
+
+    /// Current age as described in RFC 9111 section 4.2.3
+    pub fn age(&self, now: SystemTime) -> Duration {
+        let headers = self.headers();
+        let response_time = from_unix_secs(self.response_time);
+        let request_time = from_unix_secs(self.request_time);
+
+        let age_value = headers
+            .get(header::AGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        let apparent_age = http_date(&headers, header::DATE)
+            .and_then(|date| response_time.duration_since(date).ok())
+            .unwrap_or_default();
+        let response_delay = response_time
+            .duration_since(request_time)
+            .unwrap_or_default();
+        let corrected_initial_age = apparent_age.max(age_value + response_delay);
+        let resident_time = now.duration_since(response_time).unwrap_or_default();
+
+        corrected_initial_age + resident_time
+    }
+
+    /// Freshness lifetime as described in RFC 9111 section 4.2.1
+    pub fn freshness_lifetime(&self) -> Duration {
+        let headers = self.headers();
+        let cc = CacheControl::parse(&headers);
+
+        if let Some(secs) = cc.s_maxage.or(cc.max_age) {
+            return Duration::from_secs(secs);
+        }
+
+        let date =
+            http_date(&headers, header::DATE).unwrap_or_else(|| from_unix_secs(self.response_time));
+        if headers.contains_key(header::EXPIRES) {
+            return http_date(&headers, header::EXPIRES)
+                .and_then(|expires| expires.duration_since(date).ok())
+                .unwrap_or_default();
+        }
+
+        // Heuristic freshness, 10% of the time since last modification
+        if is_heuristically_cacheable(self.status)
+            && let Some(last_modified) = http_date(&headers, header::LAST_MODIFIED)
+            && let Ok(since) = date.duration_since(last_modified)
+        {
+            return since / 10;
+        }
+
+        Duration::ZERO
+    }
+
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        let cc = CacheControl::parse(&self.headers());
+        !cc.no_cache && self.age(now) < self.freshness_lifetime()
+    }
+
+    /// Adds conditional headers so the origin can answer with `304 Not Modified`
+    pub fn add_validators(&self, request: &mut request::Parts) {
+        let headers = self.headers();
+        if let Some(etag) = headers.get(header::ETAG) {
+            request.headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = headers.get(header::LAST_MODIFIED) {
+            request
+                .headers
+                .insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    pub fn has_validators(&self) -> bool {
+        let headers = self.headers();
+        headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED)
+    }
+
+    /// Updates stored headers with the ones from a `304 Not Modified` response
+    pub fn freshen(
+        &mut self,
+        headers: &HeaderMap,
+        request_time: SystemTime,
+        response_time: SystemTime,
+    ) {
+        let mut stored = self.headers();
+        for name in headers.keys() {
+            if name == header::CONTENT_LENGTH {
+                continue;
+            }
+            stored.remove(name);
+            for value in headers.get_all(name) {
+                stored.append(name.clone(), value.clone());
+            }
+        }
+        self.headers = cassette::headers_to_recorded(&stored);
+        self.request_time = unix_secs(request_time);
+        self.response_time = unix_secs(response_time);
+    }
+
+    /// Headers to send to the guest, with `Age` reflecting time spent in cache
+    pub fn response_headers(&self, now: SystemTime) -> HeaderMap {
+        let mut headers = self.headers();
+        headers.insert(header::AGE, HeaderValue::from(self.age(now).as_secs()));
+        headers
+    }
+}
+
+impl HttpCache {
+    pub fn new(
+        settings: &HttpCacheSettings,
+        plugin_id: &str,
+        plugin: &PluginHttpCache,
+    ) -> anyhow::Result<Self> {
+        let mut index = CacheIndex::default();
+        let storage: Arc<dyn CacheStorage> = match settings.backend {
+            HttpCacheBackend::Memory => Arc::new(MemoryStorage::default()),
+            HttpCacheBackend::Disk => {
+                let storage = DiskStorage::new(settings.path.join(plugin_id))?;
+                storage.fill_index(&mut index)?;
+                Arc::new(storage)
+            }
+        };
+
+        Ok(Self {
+            storage,
+            max_bytes: plugin.max_bytes,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Largest size of the responses stored for a single request
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Whether request may be answered from the cache at all. Conditional
+    /// requests made by the guest itself are passed to the origin untouched.
+    pub fn is_cacheable_request(request: &request::Parts) -> bool {
+        request.method == Method::GET
+            && !CacheControl::parse(&request.headers).no_store
+            && !request.headers.contains_key(header::IF_NONE_MATCH)
+            && !request.headers.contains_key(header::IF_MODIFIED_SINCE)
+    }
+
+    /// Whether the guest asked not to be served a stored response without
+    /// checking with the origin first
+    pub fn requires_revalidation(request: &request::Parts) -> bool {
+        let cc = CacheControl::parse(&request.headers);
+        cc.no_cache || cc.max_age == Some(0)
+    }
+
+    pub async fn lookup(&self, request: &request::Parts) -> Option<CacheEntry> {
+        let key = cache_key(&request.method, &request.uri);
+        let entries = self
+            .with_storage(key.clone(), |s, key| s.load(&key))
+            .await?;
+        let entry = entries.into_iter().find(|e| e.matches(request))?;
+        self.lock_index().touch(&key);
+        Some(entry)
+    }
+
+    /// Stores response if it is allowed to be stored by a shared cache
+    pub async fn store(&self, request: &request::Parts, entry: CacheEntry) {
+        let headers = entry.headers();
+        if !is_storable(request, entry.status, &headers) {
+            return;
+        }
+
+        let key = cache_key(&request.method, &request.uri);
+        let mut entries = self
+            .with_storage(key.clone(), |s, key| s.load(&key))
+            .await
+            .unwrap_or_default();
+        entries.retain(|e| e.vary != entry.vary);
+        entries.push(entry);
+
+        let size = entries.iter().map(CacheEntry::size).sum::<u64>();
+        if size > self.max_bytes {
+            debug!("Response for `{key}` is too large to be cached");
+            return;
+        }
+
+        // Room is made in the index first, files follow without holding the lock
+        let mut evicted = Vec::new();
+        {
+            let mut index = self.lock_index();
+            index.remove(&key);
+            while index.total + size > self.max_bytes {
+                let Some(key) = index.order.pop_front() else {
+                    break;
+                };
+                index.remove(&key);
+                evicted.push(key);
+            }
+            index.insert(&key, size);
+        }
+
+        let stored = self
+            .with_storage(key.clone(), move |s, key| {
+                for evicted in &evicted {
+                    s.remove(evicted);
+                }
+                s.store(&key, &entries)
+            })
+            .await;
+        if let Err(e) = stored {
+            error!("Could not store response for `{key}` in HTTP cache: {e:#}");
+            self.lock_index().remove(&key);
+        }
+    }
+
+    /// Drops stored responses after an unsafe request got a non-error
+    /// response, RFC 9111 section 4.4
+    pub async fn invalidate(&self, request: &request::Parts) {
+        let key = cache_key(&Method::GET, &request.uri);
+        self.lock_index().remove(&key);
+        self.with_storage(key, |s, key| s.remove(&key)).await;
+    }
+
+    /// Runs storage access off the async runtime, the disk backend blocks
+    async fn with_storage<T: Send + 'static>(
+        &self,
+        key: String,
+        f: impl FnOnce(&dyn CacheStorage, String) -> T + Send + 'static,
+    ) -> T {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || f(storage.as_ref(), key))
+            .await
+            .expect("HTTP cache task should not panic")
+    }
+
+    fn lock_index(&self) -> std::sync::MutexGuard<'_, CacheIndex> {
+        self.index
+            .lock()
+            .expect("HTTP cache lock should not be poisoned")
+    }
+}
+
+trait CacheStorage: Send + Sync {
+    fn load(&self, key: &str) -> Option<Vec<CacheEntry>>;
+    fn store(&self, key: &str, entries: &[CacheEntry]) -> anyhow::Result<()>;
+    fn remove(&self, key: &str);
+}
+
+#[derive(Default)]
+struct MemoryStorage {
+    entries: Mutex<HashMap<String, Vec<CacheEntry>>>,
+}
+
+impl CacheStorage for MemoryStorage {
+    fn load(&self, key: &str) -> Option<Vec<CacheEntry>> {
+        self.entries
+            .lock()
+            .expect("HTTP cache lock should not be poisoned")
+            .get(key)
+            .cloned()
+    }
+
+    fn store(&self, key: &str, entries: &[CacheEntry]) -> anyhow::Result<()> {
+        self.entries
+            .lock()
+            .expect("HTTP cache lock should not be poisoned")
+            .insert(key.to_owned(), entries.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) {
+        self.entries
+            .lock()
+            .expect("HTTP cache lock should not be poisoned")
+            .remove(key);
+    }
+}
+
+struct DiskStorage {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    key: String,
+    entries: Vec<CacheEntry>,
+}
+
+impl DiskStorage {
+    fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).context(format!(
+            "Creating HTTP cache directory `{}`",
+            dir.to_string_lossy()
+        ))?;
+        Ok(Self { dir })
+    }
+
+    /// File of the key, named by a digest that stays the same across builds.
+    /// The key is stored inside too, so loads can tell entries apart.
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{:x}.toml", Sha256::digest(key.as_bytes())))
+    }
+
+    fn read(path: &Path) -> anyhow::Result<DiskEntry> {
+        let contents = fs::read(path)?;
+        Ok(toml::from_slice(&contents)?)
+    }
+
+    /// Registers responses stored by previous runs so they count towards the limit
+    fn fill_index(&self, index: &mut CacheIndex) -> anyhow::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            match Self::read(&path) {
+                Ok(disk_entry) => {
+                    let size = disk_entry.entries.iter().map(CacheEntry::size).sum();
+                    index.insert(&disk_entry.key, size);
+                }
+                Err(e) => {
+                    debug!(
+                        "Removing unreadable HTTP cache file `{}`: {e:#}",
+                        path.to_string_lossy()
+                    );
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CacheStorage for DiskStorage {
+    fn load(&self, key: &str) -> Option<Vec<CacheEntry>> {
+        let entry = Self::read(&self.path(key)).ok()?;
+        if entry.key != key {
+            debug!("HTTP cache file for `{key}` holds `{}`", entry.key);
+            return None;
+        }
+        Some(entry.entries)
+    }
+
+    fn store(&self, key: &str, entries: &[CacheEntry]) -> anyhow::Result<()> {
+        let entry = DiskEntry {
+            key: key.to_owned(),
+            entries: entries.to_vec(),
+        };
+        let contents = toml::to_string(&entry).context("Serializing cache entry")?;
+        fs::write(self.path(key), contents).context("Writing cache entry")?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) {
+        let _ = fs::remove_file(self.path(key));
+    }
+}
+
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = Self::default();
+        for value in headers.get_all(header::CACHE_CONTROL) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for directive in value.split(',') {
+                let (name, arg) = match directive.split_once('=') {
+                    Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+                    None => (directive.trim(), None),
+                };
+                let secs = arg.and_then(|a| a.parse::<u64>().ok());
+                match name.to_ascii_lowercase().as_str() {
+                    "no-store" => cc.no_store = true,
+                    "no-cache" => cc.no_cache = true,
+                    "private" => cc.private = true,
+                    "public" => cc.public = true,
+                    "must-revalidate" | "proxy-revalidate" => cc.must_revalidate = true,
+                    "max-age" => cc.max_age = secs,
+                    "s-maxage" => cc.s_maxage = secs,
+                    _ => {}
+                }
+            }
+        }
+        cc
+    }
+}
+
+/// Whether a shared cache may store the response, RFC 9111 section 3
+fn is_storable(request: &request::Parts, status: u16, headers: &HeaderMap) -> bool {
+    let request_cc = CacheControl::parse(&request.headers);
+    let cc = CacheControl::parse(headers);
+
+    if request.method != Method::GET || request_cc.no_store || cc.no_store || cc.private {
+        return false;
+    }
+
+    if vary_names(headers).iter().any(|name| name == "*") {
+        return false;
+    }
+
+    if request.headers.contains_key(header::AUTHORIZATION)
+        && !(cc.public || cc.must_revalidate || cc.s_maxage.is_some())
+    {
+        return false;
+    }
+
+    cc.public
+        || cc.max_age.is_some()
+        || cc.s_maxage.is_some()
+        || headers.contains_key(header::EXPIRES)
+        || is_heuristically_cacheable(status)
+}
+
+fn is_heuristically_cacheable(status: u16) -> bool {
+    matches!(
+        status,
+        200 | 203 | 204 | 206 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501
+    )
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+fn cache_key(method: &Method, uri: &Uri) -> String {
+    format!("{method} {uri}")
+}
+
+fn http_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    let value = headers.get(name)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn from_unix_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str) -> request::Parts {
+        http::Request::get(uri).body(()).unwrap().into_parts().0
+    }
+
+    fn entry(headers: &[(&str, &str)], response_time: SystemTime) -> CacheEntry {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        CacheEntry::new(
+            &request("http://example.com/"),
+            StatusCode::OK,
+            &map,
+            &Bytes::from_static(b"body"),
+            response_time,
+            response_time,
+        )
+    }
+
+    #[test]
+    fn max_age_sets_freshness() {
+        let now = SystemTime::now();
+        let entry = entry(&[("cache-control", "max-age=60")], now);
+        assert_eq!(entry.freshness_lifetime(), Duration::from_secs(60));
+        assert!(entry.is_fresh(now + Duration::from_secs(30)));
+        assert!(!entry.is_fresh(now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn age_header_counts_towards_age() {
+        let now = SystemTime::now();
+        let entry = entry(&[("cache-control", "max-age=60"), ("age", "50")], now);
+        assert!(!entry.is_fresh(now + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn expires_is_relative_to_date() {
+        let now = SystemTime::now();
+        let date = httpdate::fmt_http_date(now);
+        let expires = httpdate::fmt_http_date(now + Duration::from_secs(120));
+        let entry = entry(&[("date", &date), ("expires", &expires)], now);
+        let lifetime = entry.freshness_lifetime().as_secs();
+        assert!((119..=120).contains(&lifetime));
+    }
+
+    #[test]
+    fn heuristic_freshness_uses_last_modified() {
+        let now = SystemTime::now();
+        let date = httpdate::fmt_http_date(now);
+        let modified = httpdate::fmt_http_date(now - Duration::from_secs(1000));
+        let entry = entry(&[("date", &date), ("last-modified", &modified)], now);
+        assert_eq!(entry.freshness_lifetime(), Duration::from_secs(100));
+    }
+
+    #[test]
+    fn no_cache_is_never_fresh() {
+        let now = SystemTime::now();
+        let entry = entry(&[("cache-control", "max-age=60, no-cache")], now);
+        assert!(!entry.is_fresh(now));
+    }
+
+    #[tokio::test]
+    async fn disk_entries_survive_restarts_and_invalidation_removes_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = HttpCacheSettings {
+            backend: HttpCacheBackend::Disk,
+            path: dir.path().to_owned(),
+        };
+        let plugin = PluginHttpCache {
+            max_bytes: default_max_bytes(),
+        };
+        let request = request("http://example.com/a");
+        let now = SystemTime::now();
+
+        let cache = HttpCache::new(&settings, "p", &plugin).unwrap();
+        cache
+            .store(&request, entry(&[("cache-control", "max-age=60")], now))
+            .await;
+
+        let cache = HttpCache::new(&settings, "p", &plugin).unwrap();
+        let stored = cache.lookup(&request).await.unwrap();
+        assert_eq!(stored.body.body.as_deref(), Some("body"));
+
+        cache.invalidate(&request).await;
+        assert!(cache.lookup(&request).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn private_responses_are_not_stored() {
+        let cache = HttpCache::new(
+            &HttpCacheSettings::default(),
+            "p",
+            &PluginHttpCache {
+                max_bytes: default_max_bytes(),
+            },
+        )
+        .unwrap();
+        let request = request("http://example.com/a");
+        cache
+            .store(
+                &request,
+                entry(
+                    &[("cache-control", "private, max-age=60")],
+                    SystemTime::now(),
+                ),
+            )
+            .await;
+        assert!(cache.lookup(&request).await.is_none());
+    }
+}
@@ -1,20 +1,159 @@
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Context as _;
-use tokio::sync::Mutex;
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tracing::{Instrument as _, info_span};
 use wasmtime::{
-    Engine,
+    Engine, GuestProfiler, UpdateDeadline,
     component::{Component, HasSelf, InstancePre},
 };
 use wasmtime_wasi_config::WasiConfig;
-use wassel_world::wassel::foundation;
+use wassel_world::{
+    wasi::{keyvalue, logging},
+    wassel::foundation,
+};
+
+use crate::{
+    instance::{ActiveInstance, PluginInstance},
+    meta::PluginMeta,
+    metrics::PluginMetrics,
+    services::PluginServices,
+    state::PluginState,
+};
 
-use crate::{instance::PluginInstance, meta::PluginMeta, state::PluginState};
+pub(crate) const HTTP_HANDLER_EXPORT: &str = "wassel:foundation/http-handler";
+pub(crate) const SCHEDULED_HANDLER_EXPORT: &str = "wassel:foundation/scheduled-handler";
+pub(crate) const WEBSOCKET_HANDLER_EXPORT: &str = "wassel:foundation/websocket-handler";
+pub(crate) const MESSAGE_HANDLER_EXPORT: &str = "wassel:foundation/message-handler";
+
+/// Epochs an instance which is not profiled may run for, in effect forever
+const UNPROFILED_EPOCH_DEADLINE: u64 = u64::MAX / 2;
 
 pub struct PluginImage {
     pre: InstancePre<PluginState>,
     meta: PluginMeta,
     data_dir: PathBuf,
+    services: PluginServices,
+    has_http_handler: bool,
+    has_websocket_handler: bool,
+    websocket_connections: Arc<Semaphore>,
+    /// SHA-256 of the component, hex encoded
+    hash: String,
+    enabled: AtomicBool,
+    instances: Arc<AtomicUsize>,
+    instantiations: AtomicU64,
+}
+
+/// Runtime statistics of a loaded plugin
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginStats {
+    /// Instances currently alive, each serving a request, session or job
+    pub instances: usize,
+    /// Instances created since the plugin was loaded
+    pub instantiations: u64,
+    pub websocket_connections: usize,
+    pub max_websocket_connections: usize,
+    /// Open connections to the plugin's database, if it has one
+    pub sql_connections: Option<usize>,
+    pub max_sql_connections: Option<usize>,
+    /// Calls into the plugin that trapped since it was loaded
+    pub traps: u64,
+}
+
+/// Handlers a plugin exports which are served on its endpoint
+#[derive(Debug, Clone, Copy)]
+pub struct PluginExports {
+    pub http_handler: bool,
+    pub websocket_handler: bool,
+}
+
+impl PluginExports {
+    pub fn serves_endpoint(&self) -> bool {
+        self.http_handler || self.websocket_handler
+    }
+}
+
+/// Compiles the component and links it against the host interfaces
+fn link(
+    engine: &Engine,
+    bytes: &[u8],
+    meta: &PluginMeta,
+) -> anyhow::Result<(InstancePre<PluginState>, PluginExports)> {
+    let component = Component::new(engine, bytes).context("Creating WASM component")?;
+
+    let mut linker = wasmtime::component::Linker::<PluginState>::new(engine);
+
+    foundation::http_client::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
+        .context("Could not add wassel:foundation/http-client to linker")?;
+    foundation::websocket::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
+        .context("Could not add wassel:foundation/websocket to linker")?;
+    foundation::pubsub::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
+        .context("Could not add wassel:foundation/pubsub to linker")?;
+    foundation::sql::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
+        .context("Could not add wassel:foundation/sql to linker")?;
+    foundation::secrets::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
+        .context("Could not add wassel:foundation/secrets to linker")?;
+    foundation::blobstore::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
+        .context("Could not add wassel:foundation/blobstore to linker")?;
+    foundation::metrics::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
+        .context("Could not add wassel:foundation/metrics to linker")?;
+    logging::logging::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
+        .context("Adding WASI logging to linker")?;
+    keyvalue::store::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
+        .context("Adding WASI key-value store to linker")?;
+    keyvalue::atomics::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
+        .context("Adding WASI key-value atomics to linker")?;
+
+    wasmtime_wasi::p2::add_to_linker_async(&mut linker)
+        .context("Adding WASIp2 exports to linker")?;
+    wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)
+        .context("Adding WASI HTTP tp linker")?;
+    wasmtime_wasi_config::add_to_linker(&mut linker, |c| WasiConfig::from(c.config_vars()))
+        .context("Adding WASI config to linker")?;
+
+    let has_http_handler = component.get_export(None, HTTP_HANDLER_EXPORT).is_some();
+    let has_scheduled_handler = component
+        .get_export(None, SCHEDULED_HANDLER_EXPORT)
+        .is_some();
+    let has_websocket_handler = component
+        .get_export(None, WEBSOCKET_HANDLER_EXPORT)
+        .is_some();
+    let has_message_handler = component.get_export(None, MESSAGE_HANDLER_EXPORT).is_some();
+    if !has_http_handler && !has_scheduled_handler && !has_websocket_handler && !has_message_handler
+    {
+        anyhow::bail!(
+            "There is no '{HTTP_HANDLER_EXPORT}', '{SCHEDULED_HANDLER_EXPORT}', '{WEBSOCKET_HANDLER_EXPORT}' or '{MESSAGE_HANDLER_EXPORT}' export"
+        );
+    }
+    if !meta.schedules.is_empty() && !has_scheduled_handler {
+        anyhow::bail!(
+            "Plugin declares schedules but there is no '{SCHEDULED_HANDLER_EXPORT}' export"
+        );
+    }
+    if !meta.subscriptions.is_empty() && !has_message_handler {
+        anyhow::bail!(
+            "Plugin declares subscriptions but there is no '{MESSAGE_HANDLER_EXPORT}' export"
+        );
+    }
+
+    let pre = linker
+        .instantiate_pre(&component)
+        .context("Pre-instantiating plugin")?;
+
+    let exports = PluginExports {
+        http_handler: has_http_handler,
+        websocket_handler: has_websocket_handler,
+    };
+    Ok((pre, exports))
 }
 
 impl PluginImage {
@@ -23,46 +162,104 @@ impl PluginImage {
         bytes: &[u8],
         meta: PluginMeta,
         data_dir: impl Into<PathBuf>,
+        services: PluginServices,
     ) -> anyhow::Result<Self> {
-        let component = Component::new(engine, bytes).context("Creating WASM component")?;
-
-        let mut linker = wasmtime::component::Linker::<PluginState>::new(engine);
-
-        foundation::http_client::add_to_linker::<_, HasSelf<PluginState>>(&mut linker, |s| s)
-            .context("Could not add wassel:foundation/http-client to linker")?;
-
-        wasmtime_wasi::p2::add_to_linker_async(&mut linker)
-            .context("Adding WASIp2 exports to linker")?;
-        wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)
-            .context("Adding WASI HTTP tp linker")?;
-        wasmtime_wasi_config::add_to_linker(&mut linker, |c| WasiConfig::from(c.config_vars()))
-            .context("Adding WASI config to linker")?;
-
-        let export = "wassel:foundation/http-handler";
-        if component.get_export(None, export).is_none() {
-            anyhow::bail!("There is no '{export}' export");
-        }
-
-        let pre = linker
-            .instantiate_pre(&component)
-            .context("Pre-instantiating plugin")?;
+        let (pre, exports) = link(engine, bytes, &meta)?;
 
+        let websocket_connections = Arc::new(Semaphore::new(meta.websocket.max_connections));
         let image = Self {
             pre,
             meta,
             data_dir: data_dir.into(),
+            services,
+            has_http_handler: exports.http_handler,
+            has_websocket_handler: exports.websocket_handler,
+            websocket_connections,
+            hash: format!("{:x}", Sha256::digest(bytes)),
+            enabled: AtomicBool::new(true),
+            instances: Arc::new(AtomicUsize::new(0)),
+            instantiations: AtomicU64::new(0),
         };
 
         Ok(image)
     }
 
+    /// Compiles and links the plugin like [`PluginImage::load`] without
+    /// preparing any of its host services
+    pub fn check(
+        engine: &Engine,
+        bytes: &[u8],
+        meta: &PluginMeta,
+    ) -> anyhow::Result<PluginExports> {
+        link(engine, bytes, meta).map(|(_, exports)| exports)
+    }
+
+    /// Creates a new instance. Guest output and logs are reported inside a
+    /// span carrying the plugin id, nested in the span current at the call.
     pub async fn instantiate(&self, engine: &Engine) -> anyhow::Result<PluginInstance> {
-        let mut store = wasmtime::Store::new(engine, PluginState::new(&self.data_dir)?);
-        let instance = self.pre.instantiate_async(&mut store).await?;
+        self.instantiate_with_profiler(engine, None).await
+    }
+
+    /// Creates a new instance sampled by the guest profiler every time the
+    /// engine's epoch advances. The profile is taken with
+    /// [`PluginInstance::take_profile`].
+    pub async fn instantiate_profiled(
+        &self,
+        engine: &Engine,
+        interval: Duration,
+    ) -> anyhow::Result<PluginInstance> {
+        self.instantiate_with_profiler(engine, Some(interval)).await
+    }
+
+    async fn instantiate_with_profiler(
+        &self,
+        engine: &Engine,
+        profile_interval: Option<Duration>,
+    ) -> anyhow::Result<PluginInstance> {
+        let started = Instant::now();
+        let span = info_span!("plugin", id = %self.meta.id, version = %self.meta.version);
+        let mut store = wasmtime::Store::new(
+            engine,
+            PluginState::new(&self.data_dir, self.services.clone(), span.clone())?,
+        );
+        match profile_interval {
+            Some(interval) => {
+                let profiler = GuestProfiler::new_component(
+                    engine,
+                    &self.meta.id,
+                    interval,
+                    self.pre.component().clone(),
+                    [],
+                )
+                .context("Creating guest profiler")?;
+                store.data_mut().profiler = Some(profiler);
+                store.set_epoch_deadline(1);
+                store.epoch_deadline_callback(move |mut store| {
+                    if let Some(mut profiler) = store.data_mut().profiler.take() {
+                        profiler.sample(&store, interval);
+                        store.data_mut().profiler = Some(profiler);
+                    }
+                    Ok(UpdateDeadline::Continue(1))
+                });
+            }
+            // Only matters when the engine interrupts on epochs for profiling
+            None => store.set_epoch_deadline(UNPROFILED_EPOCH_DEADLINE),
+        }
+        let instance = self
+            .pre
+            .instantiate_async(&mut store)
+            .instrument(info_span!("instantiate", plugin_id = %self.meta.id))
+            .await?;
+        self.services
+            .metrics
+            .record_instantiation(started.elapsed());
+        self.instantiations.fetch_add(1, Ordering::Relaxed);
         Ok(PluginInstance::new(
             instance,
             Mutex::new(store),
             self.meta.endpoint.clone(),
+            span,
+            ActiveInstance::new(self.instances.clone()),
         ))
     }
 
@@ -73,4 +270,52 @@ impl PluginImage {
     pub fn meta(&self) -> &PluginMeta {
         &self.meta
     }
+
+    pub fn metrics(&self) -> &PluginMetrics {
+        &self.services.metrics
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Disabled plugins keep their state but receive no requests, scheduled
+    /// runs or messages
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> PluginStats {
+        let max_websocket_connections = self.meta.websocket.max_connections;
+        PluginStats {
+            instances: self.instances.load(Ordering::Relaxed),
+            instantiations: self.instantiations.load(Ordering::Relaxed),
+            websocket_connections: max_websocket_connections
+                .saturating_sub(self.websocket_connections.available_permits()),
+            max_websocket_connections,
+            sql_connections: self.services.sql.as_ref().map(|s| s.open_connections()),
+            max_sql_connections: self.services.sql.as_ref().map(|s| s.max_connections()),
+            traps: self.services.metrics.traps(),
+        }
+    }
+
+    /// Whether plugin handles HTTP requests and should be given a route
+    pub fn has_http_handler(&self) -> bool {
+        self.has_http_handler
+    }
+
+    /// Whether plugin accepts WebSocket sessions
+    pub fn has_websocket_handler(&self) -> bool {
+        self.has_websocket_handler
+    }
+
+    /// Reserves one of the plugin's WebSocket connections, `None` when the
+    /// limit is reached. Connection slot is freed when permit is dropped.
+    pub fn try_acquire_websocket(&self) -> Option<OwnedSemaphorePermit> {
+        self.websocket_connections.clone().try_acquire_owned().ok()
+    }
 }
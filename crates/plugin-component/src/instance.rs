@@ -1,27 +1,77 @@
-use std::{ops::DerefMut as _, str::FromStr};
+use std::{
+    ops::DerefMut as _,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
 
-use http::{Uri, uri::PathAndQuery};
+use http::{StatusCode, Uri, header, uri::PathAndQuery};
+use http_body_util::{BodyExt as _, Empty};
 use hyper::{Request, Response, body::Incoming};
-use tokio::sync::{Mutex, MutexGuard};
-use wasmtime::{Store, component::Instance};
+use tokio::sync::{Mutex, MutexGuard, OwnedSemaphorePermit, oneshot};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tracing::{Instrument as _, Span, debug, error, info_span};
+use wasmtime::{
+    GuestProfiler, Store,
+    component::{ComponentNamedList, Instance, Lift, Lower, Resource, TypedFunc},
+};
 use wasmtime_wasi_http::{
     WasiHttpView as _, bindings::http::types::Scheme, body::HyperOutgoingBody,
 };
+use wassel_world::resources::WebSocketSession;
+
+use crate::{
+    errors::PluginHandleError,
+    image::{MESSAGE_HANDLER_EXPORT, SCHEDULED_HANDLER_EXPORT, WEBSOCKET_HANDLER_EXPORT},
+    request_id::read_request_id,
+    state::PluginState,
+    websocket::WebSocketState,
+};
 
-use crate::{errors::PluginHandleError, state::PluginState};
+/// Time the guest has to accept or reject a WebSocket upgrade
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct PluginInstance {
     instance: Instance,
     store: Mutex<Store<PluginState>>,
     endpoint: String,
+    span: Span,
+    _active: ActiveInstance,
+}
+
+/// Counts an instance as active until it is dropped
+pub(crate) struct ActiveInstance(Arc<AtomicUsize>);
+
+impl ActiveInstance {
+    pub(crate) fn new(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for ActiveInstance {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl PluginInstance {
-    pub fn new(instance: Instance, store: Mutex<Store<PluginState>>, endpoint: String) -> Self {
+    pub(crate) fn new(
+        instance: Instance,
+        store: Mutex<Store<PluginState>>,
+        endpoint: String,
+        span: Span,
+        active: ActiveInstance,
+    ) -> Self {
         Self {
             instance,
             store,
             endpoint,
+            span,
+            _active: active,
         }
     }
 
@@ -29,26 +79,13 @@ impl PluginInstance {
         &self,
         mut req: Request<Incoming>,
     ) -> Result<Response<HyperOutgoingBody>, PluginHandleError> {
-        let (sender, reciever) = tokio::sync::oneshot::channel();
+        let (sender, receiver) = tokio::sync::oneshot::channel();
 
         let mut store_guard = self.store.lock().await;
         let mut store = MutexGuard::deref_mut(&mut store_guard);
 
-        let mut parts = req.uri().clone().into_parts();
-        let paq = parts
-            .path_and_query
-            .expect("Path and query should be present in request");
-        let paq = paq
-            .as_str()
-            .strip_prefix(&self.endpoint)
-            .expect("URI must start with the plugin prefix");
-        let paq = "/".to_owned() + paq;
-        parts.path_and_query = Some(
-            PathAndQuery::from_str(&paq)
-                .expect("Parts and query should still be valid after stripping prefix"),
-        );
-        *req.uri_mut() =
-            Uri::from_parts(parts).expect("URI should still be valid after stripping prefix");
+        *req.uri_mut() = self.relative_uri(req.uri());
+        store.data_mut().request_id = read_request_id(req.headers()).map(str::to_owned);
 
         let req = store
             .data_mut()
@@ -66,11 +103,259 @@ impl PluginInstance {
         proxy
             .wassel_foundation_http_handler()
             .call_handle_request(&mut store, req, out)
+            .instrument(self.guest_span("handle-request"))
             .await
-            .map_err(PluginHandleError::CallingHandleMethod)?;
+            .map_err(|e| trapped(store, e))?;
 
-        let response = reciever.await??;
+        let response = receiver.await??;
 
         Ok(response)
     }
+
+    /// Calls `handle-scheduled` export for the schedule with the given name
+    pub async fn handle_scheduled(&self, schedule: &str) -> Result<(), PluginHandleError> {
+        let mut store_guard = self.store.lock().await;
+        let store = MutexGuard::deref_mut(&mut store_guard);
+
+        let func = self.handler_func::<(String,), (Result<(), String>,)>(
+            store,
+            SCHEDULED_HANDLER_EXPORT,
+            "handle-scheduled",
+        )?;
+
+        let (result,) = func
+            .call_async(&mut *store, (schedule.to_owned(),))
+            .instrument(self.guest_span("handle-scheduled"))
+            .await
+            .map_err(|e| trapped(store, e))?;
+        func.post_return_async(&mut *store)
+            .await
+            .map_err(|e| trapped(store, e))?;
+
+        result.map_err(PluginHandleError::Scheduled)
+    }
+
+    /// Calls `handle-message` export with a message published to the topic
+    pub async fn handle_message(
+        &self,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<(), PluginHandleError> {
+        let mut store_guard = self.store.lock().await;
+        let store = MutexGuard::deref_mut(&mut store_guard);
+
+        let func = self.handler_func::<(String, Vec<u8>), (Result<(), String>,)>(
+            store,
+            MESSAGE_HANDLER_EXPORT,
+            "handle-message",
+        )?;
+
+        let (result,) = func
+            .call_async(&mut *store, (topic.to_owned(), payload.to_vec()))
+            .instrument(self.guest_span("handle-message"))
+            .await
+            .map_err(|e| trapped(store, e))?;
+        func.post_return_async(&mut *store)
+            .await
+            .map_err(|e| trapped(store, e))?;
+
+        result.map_err(PluginHandleError::Message)
+    }
+
+    /// Offers WebSocket upgrade request to the plugin. The session is served
+    /// by a background task owning the instance and the connection permit.
+    /// Returned response completes the handshake once the guest accepts the
+    /// session, or rejects it when the handler returns without accepting.
+    pub async fn handle_websocket(
+        self,
+        mut req: Request<Incoming>,
+        idle_timeout: Duration,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<Response<HyperOutgoingBody>, PluginHandleError> {
+        let accept_key = req
+            .headers()
+            .get(header::SEC_WEBSOCKET_KEY)
+            .map(|key| derive_accept_key(key.as_bytes()))
+            .ok_or(PluginHandleError::InvalidUpgrade)?;
+
+        let (sender, receiver) = oneshot::channel();
+        let upgrade = hyper::upgrade::on(&mut req);
+        let request_id = read_request_id(req.headers()).map(str::to_owned);
+        let path_with_query = self
+            .relative_uri(req.uri())
+            .path_and_query()
+            .map(|paq| paq.as_str().to_owned())
+            .unwrap_or_else(|| "/".to_owned());
+        let state = WebSocketState::new(
+            path_with_query,
+            req.headers().clone(),
+            sender,
+            upgrade,
+            idle_timeout,
+        );
+
+        let span = self.span.clone();
+        tokio::spawn(
+            async move {
+                let _permit = permit;
+                if let Err(e) = self.serve_websocket(state, request_id).await {
+                    error!("WebSocket session failed: {e}");
+                }
+            }
+            .instrument(span),
+        );
+
+        let empty_body = || Empty::new().map_err(|e| match e {}).boxed_unsync();
+        let reject = |status| {
+            let mut response = Response::new(empty_body());
+            *response.status_mut() = status;
+            Ok(response)
+        };
+        let protocol = match tokio::time::timeout(ACCEPT_TIMEOUT, receiver).await {
+            Ok(Ok(protocol)) => protocol,
+            Ok(Err(_)) => return reject(StatusCode::FORBIDDEN),
+            Err(_) => return reject(StatusCode::GATEWAY_TIMEOUT),
+        };
+
+        let mut response = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_ACCEPT, accept_key);
+        if let Some(protocol) = protocol {
+            response = response.header(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+        }
+        response
+            .body(empty_body())
+            .map_err(|_| PluginHandleError::InvalidUpgrade)
+    }
+
+    async fn serve_websocket(
+        &self,
+        state: WebSocketState,
+        request_id: Option<String>,
+    ) -> Result<(), PluginHandleError> {
+        let mut store_guard = self.store.lock().await;
+        let store = MutexGuard::deref_mut(&mut store_guard);
+
+        let idle = state.idle_timer();
+        store.data_mut().websocket = Some(state);
+        store.data_mut().request_id = request_id;
+        let session = store
+            .data_mut()
+            .table
+            .push(WebSocketSession)
+            .map_err(|e| PluginHandleError::CreateResource(e.into()))?;
+
+        // Stuck guest is abandoned together with the instance serving it
+        let result = tokio::select! {
+            result = self.call_websocket_handler(store, session) => Some(result),
+            () = idle.expired() => None,
+        };
+
+        if let Some(mut state) = store.data_mut().websocket.take() {
+            match result {
+                Some(_) => state.close().await,
+                None => state.close_idle().await,
+            }
+        }
+
+        result.unwrap_or_else(|| {
+            debug!("Closed idle WebSocket session");
+            Ok(())
+        })
+    }
+
+    async fn call_websocket_handler(
+        &self,
+        store: &mut Store<PluginState>,
+        session: Resource<WebSocketSession>,
+    ) -> Result<(), PluginHandleError> {
+        let func = self.handler_func::<(Resource<WebSocketSession>,), ()>(
+            store,
+            WEBSOCKET_HANDLER_EXPORT,
+            "handle-session",
+        )?;
+
+        func.call_async(&mut *store, (session,))
+            .instrument(self.guest_span("handle-session"))
+            .await
+            .map_err(|e| trapped(store, e))?;
+        func.post_return_async(&mut *store)
+            .await
+            .map_err(|e| trapped(store, e))?;
+
+        Ok(())
+    }
+
+    fn handler_func<Params, Results>(
+        &self,
+        store: &mut Store<PluginState>,
+        interface: &'static str,
+        name: &str,
+    ) -> Result<TypedFunc<Params, Results>, PluginHandleError>
+    where
+        Params: ComponentNamedList + Lower,
+        Results: ComponentNamedList + Lift,
+    {
+        let index = self
+            .instance
+            .get_export_index(&mut *store, None, interface)
+            .ok_or(PluginHandleError::MissingExport(interface))?;
+        let func = self
+            .instance
+            .get_export_index(&mut *store, Some(&index), name)
+            .ok_or(PluginHandleError::MissingExport(interface))?;
+        self.instance
+            .get_typed_func::<Params, Results>(&mut *store, &func)
+            .map_err(PluginHandleError::Guest)
+    }
+
+    /// Guest stderr written so far, if the services capture it
+    pub async fn captured_stderr(&self) -> Option<String> {
+        self.store
+            .lock()
+            .await
+            .data()
+            .stderr
+            .as_ref()
+            .map(|s| s.contents())
+    }
+
+    /// Takes the profile collected so far if the instance is profiled
+    pub async fn take_profile(&self) -> Option<GuestProfiler> {
+        self.store.lock().await.data_mut().profiler.take()
+    }
+
+    /// Span of a single call into the guest
+    fn guest_span(&self, export: &'static str) -> Span {
+        info_span!(parent: &self.span, "guest", export)
+    }
+
+    /// URI of the request relative to the plugin endpoint
+    fn relative_uri(&self, uri: &Uri) -> Uri {
+        let mut parts = uri.clone().into_parts();
+        let paq = parts
+            .path_and_query
+            .expect("Path and query should be present in request");
+        let paq = paq
+            .as_str()
+            .strip_prefix(&self.endpoint)
+            .expect("URI must start with the plugin prefix");
+        let paq = "/".to_owned() + paq;
+        parts.path_and_query = Some(
+            PathAndQuery::from_str(&paq)
+                .expect("Parts and query should still be valid after stripping prefix"),
+        );
+        Uri::from_parts(parts).expect("URI should still be valid after stripping prefix")
+    }
+}
+
+/// Failed call into the guest, counted as a trap of the plugin. Its core
+/// dump is written when the engine captures them.
+fn trapped(store: &mut Store<PluginState>, e: wasmtime::Error) -> PluginHandleError {
+    store.data().services.metrics.record_trap();
+    let coredumps = store.data().services.coredumps.clone();
+    coredumps.record(store, &e);
+    PluginHandleError::CallingHandleMethod(e)
 }
@@ -0,0 +1,756 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use rusqlite::{Connection, OptionalExtension as _, Transaction, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use wasmtime::component::Resource;
+use wassel_world::{
+    resources::Bucket,
+    wasi::keyvalue::{
+        atomics,
+        store::{self, Error, KeyResponse},
+    },
+};
+
+use crate::state::PluginState;
+
+/// Maximum number of keys returned by a single `list-keys` call
+const LIST_KEYS_PAGE_SIZE: usize = 1000;
+
+/// Stack-wide settings of the key-value store, read from `wassel.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyValueSettings {
+    #[serde(default = "KeyValueBackend::default")]
+    pub backend: KeyValueBackend,
+
+    /// Directory with a database per plugin for the disk backend, relative to
+    /// the stack directory
+    #[serde(default = "default_keyvalue_path")]
+    pub path: PathBuf,
+}
+
+impl Default for KeyValueSettings {
+    fn default() -> Self {
+        Self {
+            backend: KeyValueBackend::default(),
+            path: default_keyvalue_path(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyValueBackend {
+    #[default]
+    Disk,
+    Memory,
+}
+
+/// Buckets a plugin is allowed to open, read from `plugin.toml`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PluginKeyValue {
+    #[serde(default = "HashMap::default")]
+    pub buckets: HashMap<String, BucketQuota>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BucketQuota {
+    #[serde(default = "Option::default")]
+    pub max_keys: Option<u64>,
+
+    #[serde(default = "Option::default")]
+    pub max_bytes: Option<u64>,
+}
+
+fn default_keyvalue_path() -> PathBuf {
+    PathBuf::from(".wassel/keyvalue")
+}
+
+/// Buckets of the memory backend by plugin id and bucket name, kept across
+/// stack reloads so reloading does not empty them
+#[derive(Clone, Default)]
+pub struct MemoryBuckets(Arc<Mutex<HashMap<BucketKey, Arc<MemoryStorage>>>>);
+
+/// Plugin id and bucket name
+type BucketKey = (String, String);
+
+impl MemoryBuckets {
+    fn get(&self, plugin_id: &str, bucket: &str) -> Arc<MemoryStorage> {
+        self.0
+            .lock()
+            .expect("Memory buckets lock should not be poisoned")
+            .entry((plugin_id.to_owned(), bucket.to_owned()))
+            .or_default()
+            .clone()
+    }
+}
+
+/// Buckets of a single plugin, shared between all of its instances
+#[derive(Default)]
+pub struct KeyValue {
+    buckets: HashMap<String, BucketStore>,
+}
+
+impl KeyValue {
+    pub fn new(
+        settings: &KeyValueSettings,
+        memory: &MemoryBuckets,
+        plugin_id: &str,
+        plugin: &PluginKeyValue,
+    ) -> anyhow::Result<Self> {
+        let mut buckets = HashMap::new();
+        let connection = match settings.backend {
+            KeyValueBackend::Disk if !plugin.buckets.is_empty() => Some(DiskStorage::open(
+                &settings.path.join(format!("{plugin_id}.sqlite")),
+            )?),
+            _ => None,
+        };
+        for (name, quota) in &plugin.buckets {
+            let storage: Arc<dyn KeyValueStorage> = match &connection {
+                Some(connection) => Arc::new(DiskStorage {
+                    connection: connection.clone(),
+                    bucket: name.clone(),
+                }),
+                None => memory.get(plugin_id, name),
+            };
+            let bucket = BucketStore {
+                storage,
+                quota: Arc::new(quota.clone()),
+            };
+            buckets.insert(name.clone(), bucket);
+        }
+
+        Ok(Self { buckets })
+    }
+
+    fn bucket(&self, name: &str) -> Result<&BucketStore, Error> {
+        self.buckets.get(name).ok_or(Error::NoSuchStore)
+    }
+}
+
+/// Bucket answering from its storage only, so stacks sharing the storage
+/// across a reload agree on its contents
+struct BucketStore {
+    storage: Arc<dyn KeyValueStorage>,
+    quota: Arc<BucketQuota>,
+}
+
+impl BucketStore {
+    /// Runs storage access off the async runtime, the disk backend blocks
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn KeyValueStorage, &BucketQuota) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let storage = self.storage.clone();
+        let quota = self.quota.clone();
+        tokio::task::spawn_blocking(move || f(storage.as_ref(), &quota))
+            .await
+            .map_err(|e| Error::Other(format!("Key-value task failed: {e}")))?
+    }
+
+    async fn get(&self, key: String) -> Result<Option<Vec<u8>>, Error> {
+        self.blocking(move |storage, _| storage.get(&key)).await
+    }
+
+    async fn set(&self, key: String, value: Vec<u8>) -> Result<(), Error> {
+        self.blocking(move |storage, quota| storage.update(&key, quota, Box::new(|_| Ok(value))))
+            .await
+    }
+
+    async fn delete(&self, key: String) -> Result<(), Error> {
+        self.blocking(move |storage, _| storage.delete(&key)).await
+    }
+
+    async fn exists(&self, key: String) -> Result<bool, Error> {
+        self.blocking(move |storage, _| storage.exists(&key)).await
+    }
+
+    async fn list_keys(&self, cursor: Option<u64>) -> Result<KeyResponse, Error> {
+        self.blocking(move |storage, _| {
+            let start = cursor.unwrap_or_default();
+            // One more than a page tells whether there is another one
+            let mut keys = storage.list_keys(start, LIST_KEYS_PAGE_SIZE + 1)?;
+            let cursor =
+                (keys.len() > LIST_KEYS_PAGE_SIZE).then_some(start + LIST_KEYS_PAGE_SIZE as u64);
+            keys.truncate(LIST_KEYS_PAGE_SIZE);
+            Ok(KeyResponse { keys, cursor })
+        })
+        .await
+    }
+
+    async fn increment(&self, key: String, delta: u64) -> Result<u64, Error> {
+        self.blocking(move |storage, quota| {
+            let mut value = 0;
+            storage.update(
+                &key,
+                quota,
+                Box::new(|current| {
+                    let current = match current {
+                        Some(current) => std::str::from_utf8(current)
+                            .ok()
+                            .and_then(|v| v.trim().parse::<u64>().ok())
+                            .ok_or_else(|| {
+                                Error::Other(format!("Value of `{key}` is not a number"))
+                            })?,
+                        None => 0,
+                    };
+                    value = current.wrapping_add(delta);
+                    Ok(value.to_string().into_bytes())
+                }),
+            )?;
+            Ok(value)
+        })
+        .await
+    }
+}
+
+/// Keys and bytes stored in a bucket, counting both keys and values
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    keys: u64,
+    bytes: u64,
+}
+
+impl Usage {
+    /// Usage after replacing a value of `old_size` with one of `new_size`,
+    /// unless that exceeds the quota
+    fn replace(
+        self,
+        quota: &BucketQuota,
+        old_size: Option<u64>,
+        new_size: u64,
+    ) -> Result<Self, Error> {
+        let keys = self.keys + u64::from(old_size.is_none());
+        if let Some(max_keys) = quota.max_keys
+            && keys > max_keys
+        {
+            return Err(Error::Other(format!(
+                "Bucket key limit of {max_keys} exceeded"
+            )));
+        }
+
+        let bytes = self.bytes - old_size.unwrap_or_default() + new_size;
+        if let Some(max_bytes) = quota.max_bytes
+            && bytes > max_bytes
+        {
+            return Err(Error::Other(format!(
+                "Bucket size limit of {max_bytes} bytes exceeded"
+            )));
+        }
+
+        Ok(Self { keys, bytes })
+    }
+
+    fn remove(self, size: u64) -> Self {
+        Self {
+            keys: self.keys.saturating_sub(1),
+            bytes: self.bytes.saturating_sub(size),
+        }
+    }
+}
+
+/// Computes the new value of a key from its current one
+type Update<'a> = Box<dyn FnOnce(Option<&[u8]>) -> Result<Vec<u8>, Error> + 'a>;
+
+/// Blocking storage of a bucket. Every write checks the quota and applies
+/// atomically, also against other stacks using the same storage.
+trait KeyValueStorage: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+    fn update(&self, key: &str, quota: &BucketQuota, update: Update<'_>) -> Result<(), Error>;
+    fn delete(&self, key: &str) -> Result<(), Error>;
+    fn exists(&self, key: &str) -> Result<bool, Error>;
+    /// Keys in order, starting at the `start`th one
+    fn list_keys(&self, start: u64, limit: usize) -> Result<Vec<String>, Error>;
+}
+
+fn entry_size(key: &str, value: &[u8]) -> u64 {
+    (key.len() + value.len()) as u64
+}
+
+/// Keeps values in memory, mostly useful for tests
+#[derive(Default)]
+struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    values: BTreeMap<String, Vec<u8>>,
+    usage: Usage,
+}
+
+impl MemoryStorage {
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state
+            .lock()
+            .expect("Key-value storage lock should not be poisoned")
+    }
+}
+
+impl KeyValueStorage for MemoryStorage {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.lock().values.get(key).cloned())
+    }
+
+    fn update(&self, key: &str, quota: &BucketQuota, update: Update<'_>) -> Result<(), Error> {
+        let mut state = self.lock();
+        let current = state.values.get(key);
+        let old_size = current.map(|v| entry_size(key, v));
+        let value = update(current.map(Vec::as_slice))?;
+        state.usage = state
+            .usage
+            .replace(quota, old_size, entry_size(key, &value))?;
+        state.values.insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        let mut state = self.lock();
+        if let Some(value) = state.values.remove(key) {
+            state.usage = state.usage.remove(entry_size(key, &value));
+        }
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.lock().values.contains_key(key))
+    }
+
+    fn list_keys(&self, start: u64, limit: usize) -> Result<Vec<String>, Error> {
+        Ok(self
+            .lock()
+            .values
+            .keys()
+            .skip(start as usize)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Stores values of every bucket of a plugin in a single SQLite database,
+/// keeping the usage of each bucket next to them
+struct DiskStorage {
+    connection: Arc<Mutex<Connection>>,
+    bucket: String,
+}
+
+/// Time a write waits for another connection to the same database, e.g. of
+/// the stack being replaced during a reload
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl DiskStorage {
+    fn open(path: &Path) -> anyhow::Result<Arc<Mutex<Connection>>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context(format!(
+                "Creating key-value directory `{}`",
+                dir.to_string_lossy()
+            ))?;
+        }
+        let connection = Connection::open(path).context(format!(
+            "Opening key-value database `{}`",
+            path.to_string_lossy()
+        ))?;
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .context("Setting key-value busy timeout")?;
+        // Usage is filled in from the entries of databases written before it
+        // was tracked
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS entries (
+                    bucket TEXT NOT NULL,
+                    key TEXT NOT NULL,
+                    value BLOB NOT NULL,
+                    PRIMARY KEY (bucket, key)
+                );
+                CREATE TABLE IF NOT EXISTS usage (
+                    bucket TEXT PRIMARY KEY,
+                    keys INTEGER NOT NULL,
+                    bytes INTEGER NOT NULL
+                );
+                INSERT OR IGNORE INTO usage (bucket, keys, bytes)
+                    SELECT bucket, COUNT(*), SUM(length(CAST(key AS BLOB)) + length(value))
+                    FROM entries GROUP BY bucket;",
+            )
+            .context("Creating key-value tables")?;
+        Ok(Arc::new(Mutex::new(connection)))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .expect("Key-value database lock should not be poisoned")
+    }
+
+    fn usage(&self, transaction: &Transaction<'_>) -> rusqlite::Result<Usage> {
+        let usage = transaction
+            .query_row(
+                "SELECT keys, bytes FROM usage WHERE bucket = ?1",
+                [&self.bucket],
+                |row| {
+                    Ok(Usage {
+                        keys: row.get::<_, i64>(0)? as u64,
+                        bytes: row.get::<_, i64>(1)? as u64,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(usage.unwrap_or_default())
+    }
+
+    fn set_usage(&self, transaction: &Transaction<'_>, usage: Usage) -> rusqlite::Result<()> {
+        transaction.execute(
+            "INSERT INTO usage (bucket, keys, bytes) VALUES (?1, ?2, ?3)
+            ON CONFLICT (bucket) DO UPDATE SET keys = excluded.keys, bytes = excluded.bytes",
+            (&self.bucket, usage.keys as i64, usage.bytes as i64),
+        )?;
+        Ok(())
+    }
+
+    fn current(
+        &self,
+        transaction: &Transaction<'_>,
+        key: &str,
+    ) -> rusqlite::Result<Option<Vec<u8>>> {
+        transaction
+            .query_row(
+                "SELECT value FROM entries WHERE bucket = ?1 AND key = ?2",
+                (&self.bucket, key),
+                |row| row.get(0),
+            )
+            .optional()
+    }
+}
+
+impl KeyValueStorage for DiskStorage {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.lock()
+            .query_row(
+                "SELECT value FROM entries WHERE bucket = ?1 AND key = ?2",
+                (&self.bucket, key),
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(convert_sqlite_error)
+    }
+
+    fn update(&self, key: &str, quota: &BucketQuota, update: Update<'_>) -> Result<(), Error> {
+        let mut connection = self.lock();
+        let transaction = connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(convert_sqlite_error)?;
+
+        let current = self
+            .current(&transaction, key)
+            .map_err(convert_sqlite_error)?;
+        let old_size = current.as_deref().map(|v| entry_size(key, v));
+        let value = update(current.as_deref())?;
+        let usage = self
+            .usage(&transaction)
+            .map_err(convert_sqlite_error)?
+            .replace(quota, old_size, entry_size(key, &value))?;
+
+        transaction
+            .execute(
+                "INSERT INTO entries (bucket, key, value) VALUES (?1, ?2, ?3)
+                ON CONFLICT (bucket, key) DO UPDATE SET value = excluded.value",
+                (&self.bucket, key, &value),
+            )
+            .map_err(convert_sqlite_error)?;
+        self.set_usage(&transaction, usage)
+            .map_err(convert_sqlite_error)?;
+        transaction.commit().map_err(convert_sqlite_error)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Error> {
+        let mut connection = self.lock();
+        let transaction = connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(convert_sqlite_error)?;
+
+        if let Some(value) = self
+            .current(&transaction, key)
+            .map_err(convert_sqlite_error)?
+        {
+            transaction
+                .execute(
+                    "DELETE FROM entries WHERE bucket = ?1 AND key = ?2",
+                    (&self.bucket, key),
+                )
+                .map_err(convert_sqlite_error)?;
+            let usage = self
+                .usage(&transaction)
+                .map_err(convert_sqlite_error)?
+                .remove(entry_size(key, &value));
+            self.set_usage(&transaction, usage)
+                .map_err(convert_sqlite_error)?;
+        }
+        transaction.commit().map_err(convert_sqlite_error)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, Error> {
+        self.lock()
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM entries WHERE bucket = ?1 AND key = ?2)",
+                (&self.bucket, key),
+                |row| row.get(0),
+            )
+            .map_err(convert_sqlite_error)
+    }
+
+    fn list_keys(&self, start: u64, limit: usize) -> Result<Vec<String>, Error> {
+        let connection = self.lock();
+        let mut statement = connection
+            .prepare_cached(
+                "SELECT key FROM entries WHERE bucket = ?1 ORDER BY key LIMIT ?2 OFFSET ?3",
+            )
+            .map_err(convert_sqlite_error)?;
+        statement
+            .query_map((&self.bucket, limit as i64, start as i64), |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(convert_sqlite_error)
+    }
+}
+
+fn convert_sqlite_error(e: rusqlite::Error) -> Error {
+    Error::Other(format!("Key-value storage error: {e}"))
+}
+
+impl PluginState {
+    fn keyvalue_bucket(&self, bucket: &Resource<Bucket>) -> Result<&BucketStore, Error> {
+        let bucket = self
+            .table
+            .get(bucket)
+            .map_err(|e| Error::Other(format!("Could not get bucket resource: {e:?}")))?;
+        self.services.keyvalue.bucket(&bucket.name)
+    }
+}
+
+impl store::Host for PluginState {
+    async fn open(&mut self, identifier: String) -> Result<Resource<Bucket>, Error> {
+        self.services.keyvalue.bucket(&identifier)?;
+        self.table
+            .push(Bucket { name: identifier })
+            .map_err(|e| Error::Other(format!("Could not create bucket resource: {e:?}")))
+    }
+}
+
+impl store::HostBucket for PluginState {
+    async fn get(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.keyvalue_bucket(&bucket)?.get(key).await
+    }
+
+    async fn set(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+        value: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.keyvalue_bucket(&bucket)?.set(key, value).await
+    }
+
+    async fn delete(&mut self, bucket: Resource<Bucket>, key: String) -> Result<(), Error> {
+        self.keyvalue_bucket(&bucket)?.delete(key).await
+    }
+
+    async fn exists(&mut self, bucket: Resource<Bucket>, key: String) -> Result<bool, Error> {
+        self.keyvalue_bucket(&bucket)?.exists(key).await
+    }
+
+    async fn list_keys(
+        &mut self,
+        bucket: Resource<Bucket>,
+        cursor: Option<u64>,
+    ) -> Result<KeyResponse, Error> {
+        self.keyvalue_bucket(&bucket)?.list_keys(cursor).await
+    }
+
+    async fn drop(&mut self, bucket: Resource<Bucket>) -> wasmtime::Result<()> {
+        self.table.delete(bucket)?;
+        Ok(())
+    }
+}
+
+impl atomics::Host for PluginState {
+    async fn increment(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+        delta: u64,
+    ) -> Result<u64, Error> {
+        self.keyvalue_bucket(&bucket)?.increment(key, delta).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(
+        backend: KeyValueBackend,
+        path: &Path,
+        memory: &MemoryBuckets,
+        quota: BucketQuota,
+    ) -> KeyValue {
+        let settings = KeyValueSettings {
+            backend,
+            path: path.to_owned(),
+        };
+        let plugin = PluginKeyValue {
+            buckets: HashMap::from([("cache".to_owned(), quota)]),
+        };
+        KeyValue::new(&settings, memory, "test", &plugin).unwrap()
+    }
+
+    fn memory(quota: BucketQuota) -> KeyValue {
+        open(
+            KeyValueBackend::Memory,
+            Path::new(""),
+            &MemoryBuckets::default(),
+            quota,
+        )
+    }
+
+    fn key(key: &str) -> String {
+        key.to_owned()
+    }
+
+    #[tokio::test]
+    async fn set_get_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        for backend in [KeyValueBackend::Memory, KeyValueBackend::Disk] {
+            let keyvalue = open(
+                backend,
+                dir.path(),
+                &MemoryBuckets::default(),
+                BucketQuota::default(),
+            );
+            let bucket = keyvalue.bucket("cache").unwrap();
+
+            bucket.set(key("a"), b"1".to_vec()).await.unwrap();
+            assert_eq!(bucket.get(key("a")).await.unwrap(), Some(b"1".to_vec()));
+            assert!(bucket.exists(key("a")).await.unwrap());
+
+            bucket.delete(key("a")).await.unwrap();
+            assert_eq!(bucket.get(key("a")).await.unwrap(), None);
+            assert!(!bucket.exists(key("a")).await.unwrap());
+            assert!(matches!(keyvalue.bucket("other"), Err(Error::NoSuchStore)));
+        }
+    }
+
+    #[tokio::test]
+    async fn disk_values_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory = MemoryBuckets::default();
+        let long_key = "k".repeat(4096);
+        {
+            let keyvalue = open(
+                KeyValueBackend::Disk,
+                dir.path(),
+                &memory,
+                BucketQuota::default(),
+            );
+            let bucket = keyvalue.bucket("cache").unwrap();
+            bucket
+                .set(long_key.clone(), b"value".to_vec())
+                .await
+                .unwrap();
+            bucket.increment(key("counter"), 5).await.unwrap();
+        }
+
+        let keyvalue = open(
+            KeyValueBackend::Disk,
+            dir.path(),
+            &memory,
+            BucketQuota::default(),
+        );
+        let bucket = keyvalue.bucket("cache").unwrap();
+        assert_eq!(bucket.get(long_key).await.unwrap(), Some(b"value".to_vec()));
+        assert_eq!(bucket.increment(key("counter"), 2).await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn loads_sharing_storage_agree_on_keys_and_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        for backend in [KeyValueBackend::Memory, KeyValueBackend::Disk] {
+            let memory = MemoryBuckets::default();
+            let quota = BucketQuota {
+                max_keys: Some(2),
+                max_bytes: None,
+            };
+            let old = open(backend, dir.path(), &memory, quota.clone());
+            let new = open(backend, dir.path(), &memory, quota);
+            let old = old.bucket("cache").unwrap();
+            let new = new.bucket("cache").unwrap();
+
+            old.set(key("a"), b"1".to_vec()).await.unwrap();
+            assert!(new.exists(key("a")).await.unwrap());
+            new.set(key("b"), b"1".to_vec()).await.unwrap();
+            assert_eq!(old.list_keys(None).await.unwrap().keys, ["a", "b"]);
+            assert!(old.set(key("c"), b"1".to_vec()).await.is_err());
+
+            old.delete(key("a")).await.unwrap();
+            new.delete(key("b")).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn quotas_are_enforced() {
+        let dir = tempfile::tempdir().unwrap();
+        for backend in [KeyValueBackend::Memory, KeyValueBackend::Disk] {
+            let keyvalue = open(
+                backend,
+                dir.path(),
+                &MemoryBuckets::default(),
+                BucketQuota {
+                    max_keys: Some(2),
+                    max_bytes: Some(10),
+                },
+            );
+            let bucket = keyvalue.bucket("cache").unwrap();
+
+            bucket.set(key("a"), b"1".to_vec()).await.unwrap();
+            bucket.set(key("b"), b"1".to_vec()).await.unwrap();
+            assert!(bucket.set(key("c"), b"1".to_vec()).await.is_err());
+            bucket.set(key("a"), b"12345".to_vec()).await.unwrap();
+            assert!(bucket.set(key("a"), b"123456789".to_vec()).await.is_err());
+            assert_eq!(bucket.get(key("a")).await.unwrap(), Some(b"12345".to_vec()));
+        }
+    }
+
+    #[tokio::test]
+    async fn list_keys_pages_in_order() {
+        let keyvalue = memory(BucketQuota::default());
+        let bucket = keyvalue.bucket("cache").unwrap();
+        for i in 0..LIST_KEYS_PAGE_SIZE + 5 {
+            bucket.set(format!("{i:05}"), Vec::new()).await.unwrap();
+        }
+
+        let first = bucket.list_keys(None).await.unwrap();
+        assert_eq!(first.keys.len(), LIST_KEYS_PAGE_SIZE);
+        assert_eq!(first.keys[0], "00000");
+        let second = bucket.list_keys(first.cursor).await.unwrap();
+        assert_eq!(second.keys.len(), 5);
+        assert_eq!(second.cursor, None);
+    }
+
+    #[tokio::test]
+    async fn increment_rejects_non_numbers() {
+        let keyvalue = memory(BucketQuota::default());
+        let bucket = keyvalue.bucket("cache").unwrap();
+        assert_eq!(bucket.increment(key("n"), 3).await.unwrap(), 3);
+        bucket.set(key("s"), b"text".to_vec()).await.unwrap();
+        assert!(bucket.increment(key("s"), 1).await.is_err());
+    }
+}
@@ -1,12 +1,49 @@
+mod blobstore;
+mod cassette;
+mod coredump;
 // mod config;
 mod errors;
+mod http_cache;
 mod image;
 mod instance;
+mod keyvalue;
+mod logging;
 mod meta;
+mod metrics;
+mod outbound;
+mod profiling;
+mod pubsub;
+mod request_id;
+mod secrets;
+mod services;
+mod sql;
 mod state;
+mod trace_context;
+mod websocket;
 
+pub use blobstore::{
+    BlobContainers, BlobStore, BlobStoreSettings, ContainerQuota, PluginBlobStore,
+};
+pub use cassette::OutboundMode;
+pub use coredump::{CoreDumpInfo, CoreDumpSettings, CoreDumps, PluginCoreDumps};
 // pub use config::PluginConfig;
 pub use errors::PluginHandleError;
-pub use image::PluginImage;
+pub use http_cache::{HttpCacheBackend, HttpCacheSettings, PluginHttpCache};
+pub use image::{PluginExports, PluginImage, PluginStats};
 pub use instance::PluginInstance;
-pub use meta::PluginMeta;
+pub use keyvalue::{
+    BucketQuota, KeyValue, KeyValueBackend, KeyValueSettings, MemoryBuckets, PluginKeyValue,
+};
+pub use meta::{PluginMeta, ScheduleMeta};
+pub use metrics::{
+    DEFAULT_BUCKETS, MetricsError, MetricsRegistry, PluginMetrics, PluginMetricsSettings,
+};
+pub use outbound::OutboundHttp;
+pub use profiling::{GuestProfiling, ProfilingSettings, constant_time_eq};
+pub use pubsub::{PubSubMessage, Publisher, SubscriptionMeta};
+pub use request_id::{REQUEST_ID_HEADER, generate_request_id, read_request_id};
+pub use secrets::{PluginSecrets, Secret, SecretSource, SecretsFile, SecretsSettings};
+pub use services::PluginServices;
+pub use sql::{PluginSql, SqlConnectionLimits, SqlDatabase, SqlSettings};
+pub use trace_context::set_remote_parent;
+pub use websocket::{PluginWebSocket, is_upgrade_request};
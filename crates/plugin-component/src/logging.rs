@@ -0,0 +1,153 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use tokio::io::AsyncWrite;
+use tracing::{Span, debug, error, info, trace, warn};
+use wassel_world::wasi::logging::logging::{self, Level};
+
+use crate::{secrets::PluginSecrets, state::PluginState};
+
+/// Longest line of guest output kept in memory before it is logged anyway
+const MAX_LINE_LENGTH: usize = 16 * 1024;
+
+/// Most recent guest output kept by [`CapturedOutput`]
+const MAX_CAPTURED_LENGTH: usize = 64 * 1024;
+
+impl logging::Host for PluginState {
+    async fn log(&mut self, level: Level, context: String, message: String) {
+        let secrets = &self.services.secrets;
+        let context = secrets.redact(&context);
+        let message = secrets.redact(&message);
+        self.span.in_scope(|| match level {
+            Level::Trace => trace!(context = %context, "{message}"),
+            Level::Debug => debug!(context = %context, "{message}"),
+            Level::Info => info!(context = %context, "{message}"),
+            Level::Warn => warn!(context = %context, "{message}"),
+            Level::Error => error!(context = %context, "{message}"),
+            Level::Critical => error!(context = %context, critical = true, "{message}"),
+        });
+    }
+}
+
+/// Guest output kept for debug error pages, limited to the most recent lines
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CapturedOutput(Arc<Mutex<String>>);
+
+impl CapturedOutput {
+    fn push_line(&self, line: &str) {
+        let mut output = self.0.lock().expect("Output lock should not be poisoned");
+        output.push_str(line);
+        output.push('\n');
+        if output.len() > MAX_CAPTURED_LENGTH {
+            let mut start = output.len() - MAX_CAPTURED_LENGTH;
+            while !output.is_char_boundary(start) {
+                start += 1;
+            }
+            output.drain(..start);
+        }
+    }
+
+    pub(crate) fn contents(&self) -> String {
+        self.0
+            .lock()
+            .expect("Output lock should not be poisoned")
+            .clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Collects guest stdout or stderr and emits every line as a tracing event
+/// inside the span of the plugin instance, with secret values redacted
+pub struct LogWriter {
+    stream: OutputStream,
+    span: Span,
+    secrets: Arc<PluginSecrets>,
+    buffer: Vec<u8>,
+    /// Also keeps the lines when set
+    capture: Option<CapturedOutput>,
+}
+
+impl LogWriter {
+    pub fn new(stream: OutputStream, span: Span, secrets: Arc<PluginSecrets>) -> Self {
+        Self {
+            stream,
+            span,
+            secrets,
+            buffer: Vec::new(),
+            capture: None,
+        }
+    }
+
+    pub(crate) fn capturing(mut self, capture: CapturedOutput) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    fn emit(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = self.secrets.redact(line.trim_end_matches('\r'));
+        if let Some(capture) = &self.capture {
+            capture.push_line(&line);
+        }
+        self.span.in_scope(|| match self.stream {
+            OutputStream::Stdout => info!(stream = "stdout", "{line}"),
+            OutputStream::Stderr => warn!(stream = "stderr", "{line}"),
+        });
+    }
+
+    fn emit_lines(&mut self) {
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            self.emit(&line[..line.len() - 1]);
+        }
+
+        if self.buffer.len() > MAX_LINE_LENGTH {
+            let line = std::mem::take(&mut self.buffer);
+            self.emit(&line);
+        }
+    }
+
+    fn emit_rest(&mut self) {
+        if !self.buffer.is_empty() {
+            let line = std::mem::take(&mut self.buffer);
+            self.emit(&line);
+        }
+    }
+}
+
+impl AsyncWrite for LogWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.buffer.extend_from_slice(buf);
+        this.emit_lines();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().emit_rest();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        self.emit_rest();
+    }
+}
@@ -2,6 +2,12 @@ use std::{collections::HashMap, path::PathBuf, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    blobstore::PluginBlobStore, http_cache::PluginHttpCache, keyvalue::PluginKeyValue,
+    metrics::PluginMetricsSettings, pubsub::SubscriptionMeta, secrets::SecretSource,
+    sql::PluginSql, websocket::PluginWebSocket,
+};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PluginMeta {
     pub id: String,
@@ -18,11 +24,58 @@ pub struct PluginMeta {
     #[serde(default = "HashMap::default")]
     pub variables: HashMap<String, String>,
 
+    /// Secrets exposed through `wassel:foundation/secrets`, by name
+    #[serde(default = "HashMap::default")]
+    pub secrets: HashMap<String, SecretSource>,
+
     #[serde(default = "default_data_dir")]
     pub data_dir: PathBuf,
 
     #[serde(default = "default_endpoint")]
     pub endpoint: String,
+
+    /// Opts the plugin into the shared outbound HTTP cache
+    #[serde(default = "Option::default")]
+    pub http_cache: Option<PluginHttpCache>,
+
+    /// Key-value buckets the plugin can open and their quotas
+    #[serde(default = "PluginKeyValue::default")]
+    pub keyvalue: PluginKeyValue,
+
+    /// Blob containers the plugin can open and their quotas
+    #[serde(default = "PluginBlobStore::default")]
+    pub blobstore: PluginBlobStore,
+
+    #[serde(default = "Vec::default")]
+    pub schedules: Vec<ScheduleMeta>,
+
+    #[serde(default = "Vec::default")]
+    pub subscriptions: Vec<SubscriptionMeta>,
+
+    /// Enables the SQLite database of the plugin
+    #[serde(default = "Option::default")]
+    pub sql: Option<PluginSql>,
+
+    #[serde(default = "PluginWebSocket::default")]
+    pub websocket: PluginWebSocket,
+
+    /// Limits of the metrics emitted through `wassel:foundation/metrics`
+    #[serde(default = "PluginMetricsSettings::default")]
+    pub metrics: PluginMetricsSettings,
+}
+
+/// Periodic invocation of the plugin's `scheduled-handler` export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleMeta {
+    /// Passed to the handler so it can tell schedules apart
+    pub name: String,
+
+    /// Cron expression, either standard five fields or with leading seconds
+    pub cron: String,
+
+    /// Upper bound of random delay added to every run
+    #[serde(default = "u64::default")]
+    pub jitter_secs: u64,
 }
 
 fn default_version() -> String {
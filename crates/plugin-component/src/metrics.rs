@@ -0,0 +1,732 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use wassel_world::wassel::foundation::metrics::{self, Error};
+
+use crate::state::PluginState;
+
+/// Upper bounds of histogram buckets used unless configured otherwise
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Prefix of all metrics emitted by guests, keeping them apart from host
+/// metrics. Plugins using the same name share a family and are told apart by
+/// the `plugin_id` label.
+const PLUGIN_METRIC_PREFIX: &str = "wassel_plugin_";
+
+/// Labels added by the host to every guest metric
+const RESERVED_LABELS: &[&str] = &["plugin_id", "plugin_version"];
+
+/// Metric limits of a plugin, read from `plugin.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginMetricsSettings {
+    /// Label combinations a single metric may have, further ones are rejected
+    #[serde(default = "default_max_series")]
+    pub max_series: usize,
+
+    /// Distinct metric names the plugin may use, further ones are rejected
+    #[serde(default = "default_max_families")]
+    pub max_families: usize,
+
+    /// Upper bounds of histogram buckets, sorted when the plugin is loaded
+    #[serde(default = "default_buckets")]
+    pub buckets: Vec<f64>,
+}
+
+impl Default for PluginMetricsSettings {
+    fn default() -> Self {
+        Self {
+            max_series: default_max_series(),
+            max_families: default_max_families(),
+            buckets: default_buckets(),
+        }
+    }
+}
+
+fn default_max_series() -> usize {
+    100
+}
+
+fn default_max_families() -> usize {
+    50
+}
+
+fn default_buckets() -> Vec<f64> {
+    DEFAULT_BUCKETS.to_vec()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+            Self::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetricsError {
+    #[error("Metric `{0}` is already registered as {1}")]
+    KindMismatch(String, &'static str),
+
+    #[error("Metric `{0}` reached its limit of {1} label combinations")]
+    CardinalityLimit(String, usize),
+
+    #[error("Plugin `{0}` reached its limit of {1} metrics")]
+    FamilyLimit(String, usize),
+}
+
+type Labels = Vec<(String, String)>;
+
+enum Series {
+    Value(f64),
+    Histogram {
+        bounds: Vec<f64>,
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+struct Family {
+    kind: MetricKind,
+    help: String,
+    series: BTreeMap<Labels, Series>,
+    /// Number of series of each plugin in the family
+    per_plugin: HashMap<String, usize>,
+}
+
+#[derive(Default)]
+struct Families {
+    by_name: BTreeMap<String, Family>,
+    /// Number of families each plugin has series in
+    per_plugin: HashMap<String, usize>,
+}
+
+/// Identifies a series and the family it belongs to
+struct SeriesKey<'a> {
+    name: &'a str,
+    help: &'a str,
+    kind: MetricKind,
+    labels: &'a [(&'a str, &'a str)],
+    /// Plugin recording the series, `None` for host metrics
+    plugin: Option<SeriesLimits<'a>>,
+}
+
+/// Limits of a plugin recording a guest metric
+struct SeriesLimits<'a> {
+    plugin_id: &'a str,
+    /// Label combinations of the plugin in a single family
+    max_series: usize,
+    /// Families the plugin has series in
+    max_families: usize,
+}
+
+impl<'a> SeriesKey<'a> {
+    fn new(
+        name: &'a str,
+        help: &'a str,
+        kind: MetricKind,
+        labels: &'a [(&'a str, &'a str)],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            kind,
+            labels,
+            plugin: None,
+        }
+    }
+}
+
+/// Aggregated metrics of the host and all plugins, rendered in Prometheus
+/// text exposition format
+#[derive(Default)]
+pub struct MetricsRegistry {
+    families: Mutex<Families>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn counter_add(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) -> Result<(), MetricsError> {
+        let key = SeriesKey::new(name, help, MetricKind::Counter, labels);
+        self.update(key, |v| v + value)
+    }
+
+    pub fn gauge_set(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) -> Result<(), MetricsError> {
+        let key = SeriesKey::new(name, help, MetricKind::Gauge, labels);
+        self.update(key, |_| value)
+    }
+
+    pub fn gauge_add(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        delta: f64,
+    ) -> Result<(), MetricsError> {
+        let key = SeriesKey::new(name, help, MetricKind::Gauge, labels);
+        self.update(key, |v| v + delta)
+    }
+
+    pub fn histogram_observe(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+        value: f64,
+    ) -> Result<(), MetricsError> {
+        let key = SeriesKey::new(name, help, MetricKind::Histogram, labels);
+        self.observe(key, buckets, value)
+    }
+
+    fn update(&self, key: SeriesKey<'_>, f: impl FnOnce(f64) -> f64) -> Result<(), MetricsError> {
+        self.update_series(
+            key,
+            || Series::Value(0.0),
+            |series| {
+                if let Series::Value(v) = series {
+                    *v = f(*v);
+                }
+            },
+        )
+    }
+
+    fn observe(&self, key: SeriesKey<'_>, buckets: &[f64], value: f64) -> Result<(), MetricsError> {
+        let new_series = || Series::Histogram {
+            bounds: buckets.to_vec(),
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        };
+        self.update_series(key, new_series, |series| {
+            if let Series::Histogram {
+                bounds,
+                counts,
+                sum,
+                count,
+            } = series
+            {
+                for (bound, bucket) in bounds.iter().zip(counts.iter_mut()) {
+                    if value <= *bound {
+                        *bucket += 1;
+                    }
+                }
+                *sum += value;
+                *count += 1;
+            }
+        })
+    }
+
+    fn update_series(
+        &self,
+        key: SeriesKey<'_>,
+        new_series: impl FnOnce() -> Series,
+        f: impl FnOnce(&mut Series),
+    ) -> Result<(), MetricsError> {
+        let mut labels: Labels = key
+            .labels
+            .iter()
+            .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+            .collect();
+        labels.sort();
+
+        let mut families = self
+            .families
+            .lock()
+            .expect("Metrics lock should not be poisoned");
+        let Families {
+            by_name,
+            per_plugin,
+        } = &mut *families;
+        if let Some(family) = by_name.get(key.name)
+            && family.kind != key.kind
+        {
+            return Err(MetricsError::KindMismatch(
+                key.name.to_owned(),
+                family.kind.as_str(),
+            ));
+        }
+
+        let series_count = by_name
+            .get(key.name)
+            .zip(key.plugin.as_ref())
+            .and_then(|(family, limits)| family.per_plugin.get(limits.plugin_id))
+            .copied()
+            .unwrap_or_default();
+        let is_new = !by_name
+            .get(key.name)
+            .is_some_and(|family| family.series.contains_key(&labels));
+        if is_new && let Some(limits) = &key.plugin {
+            if series_count >= limits.max_series {
+                return Err(MetricsError::CardinalityLimit(
+                    key.name.to_owned(),
+                    limits.max_series,
+                ));
+            }
+            let family_count = per_plugin.entry(limits.plugin_id.to_owned()).or_default();
+            if series_count == 0 {
+                if *family_count >= limits.max_families {
+                    return Err(MetricsError::FamilyLimit(
+                        limits.plugin_id.to_owned(),
+                        limits.max_families,
+                    ));
+                }
+                *family_count += 1;
+            }
+        }
+
+        let family = by_name
+            .entry(key.name.to_owned())
+            .or_insert_with(|| Family {
+                kind: key.kind,
+                help: key.help.to_owned(),
+                series: BTreeMap::new(),
+                per_plugin: HashMap::new(),
+            });
+        if is_new && let Some(limits) = &key.plugin {
+            *family
+                .per_plugin
+                .entry(limits.plugin_id.to_owned())
+                .or_default() += 1;
+        }
+        f(family.series.entry(labels).or_insert_with(new_series));
+        Ok(())
+    }
+
+    /// Renders all metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let families = self
+            .families
+            .lock()
+            .expect("Metrics lock should not be poisoned");
+
+        let mut out = String::new();
+        for (name, family) in &families.by_name {
+            if !family.help.is_empty() {
+                let _ = writeln!(out, "# HELP {name} {}", escape_help(&family.help));
+            }
+            let _ = writeln!(out, "# TYPE {name} {}", family.kind.as_str());
+
+            for (labels, series) in &family.series {
+                match series {
+                    Series::Value(value) => {
+                        let _ = writeln!(out, "{name}{} {value}", format_labels(labels, None));
+                    }
+                    Series::Histogram {
+                        bounds,
+                        counts,
+                        sum,
+                        count,
+                    } => {
+                        for (bound, bucket) in bounds.iter().zip(counts) {
+                            let le = bound.to_string();
+                            let _ = writeln!(
+                                out,
+                                "{name}_bucket{} {bucket}",
+                                format_labels(labels, Some(&le))
+                            );
+                        }
+                        let _ = writeln!(
+                            out,
+                            "{name}_bucket{} {count}",
+                            format_labels(labels, Some("+Inf"))
+                        );
+                        let _ = writeln!(out, "{name}_sum{} {sum}", format_labels(labels, None));
+                        let _ =
+                            writeln!(out, "{name}_count{} {count}", format_labels(labels, None));
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut parts: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{k}=\"{}\"", escape_label_value(v)))
+        .collect();
+    if let Some(le) = le {
+        parts.push(format!("le=\"{le}\""));
+    }
+
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Registry handle of a single plugin which namespaces guest metrics and
+/// labels them with plugin id and version
+#[derive(Clone)]
+pub struct PluginMetrics {
+    registry: Arc<MetricsRegistry>,
+    plugin_id: Arc<str>,
+    plugin_version: Arc<str>,
+    settings: Arc<PluginMetricsSettings>,
+    /// Traps since the plugin was loaded, kept apart from the registry for
+    /// health reporting
+    traps: Arc<AtomicU64>,
+}
+
+impl PluginMetrics {
+    pub fn new(
+        registry: Arc<MetricsRegistry>,
+        plugin_id: &str,
+        plugin_version: &str,
+        mut settings: PluginMetricsSettings,
+    ) -> anyhow::Result<Self> {
+        if settings.buckets.is_empty() {
+            anyhow::bail!("Histogram buckets may not be empty");
+        }
+        if let Some(bound) = settings.buckets.iter().find(|b| !b.is_finite()) {
+            anyhow::bail!("Histogram bucket `{bound}` is not a finite number");
+        }
+        settings.buckets.sort_by(f64::total_cmp);
+        settings.buckets.dedup();
+
+        Ok(Self {
+            registry,
+            plugin_id: Arc::from(plugin_id),
+            plugin_version: Arc::from(plugin_version),
+            settings: Arc::new(settings),
+            traps: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn registry(&self) -> &Arc<MetricsRegistry> {
+        &self.registry
+    }
+
+    /// Records an HTTP request routed to the plugin
+    pub fn record_request(&self, status: StatusCode, duration: Duration) {
+        let status = status.as_str();
+        self.host(self.registry.counter_add(
+            "wassel_http_requests_total",
+            "HTTP requests handled by a plugin, by response status",
+            &[("plugin_id", &*self.plugin_id), ("status", status)],
+            1.0,
+        ));
+        self.host(self.registry.histogram_observe(
+            "wassel_http_request_duration_seconds",
+            "Time from routing an HTTP request to a plugin until its response head",
+            &[("plugin_id", &*self.plugin_id)],
+            DEFAULT_BUCKETS,
+            duration.as_secs_f64(),
+        ));
+    }
+
+    pub fn record_instantiation(&self, duration: Duration) {
+        self.host(self.registry.histogram_observe(
+            "wassel_instantiation_duration_seconds",
+            "Time taken to instantiate a plugin",
+            &[("plugin_id", &*self.plugin_id)],
+            DEFAULT_BUCKETS,
+            duration.as_secs_f64(),
+        ));
+    }
+
+    /// Records a call into the guest that trapped
+    pub fn record_trap(&self) {
+        self.traps.fetch_add(1, Ordering::Relaxed);
+        self.host(self.registry.counter_add(
+            "wassel_traps_total",
+            "Calls into a plugin that ended in a trap",
+            &[("plugin_id", &*self.plugin_id)],
+            1.0,
+        ));
+    }
+
+    pub fn traps(&self) -> u64 {
+        self.traps.load(Ordering::Relaxed)
+    }
+
+    /// Records an outbound HTTP request, `None` when no response was received
+    pub fn record_outbound(&self, status: Option<StatusCode>) {
+        let status = status.as_ref().map_or("error", StatusCode::as_str);
+        self.host(self.registry.counter_add(
+            "wassel_outbound_requests_total",
+            "Outbound HTTP requests made by a plugin, by response status",
+            &[("plugin_id", &*self.plugin_id), ("status", status)],
+            1.0,
+        ));
+    }
+
+    fn host(&self, result: Result<(), MetricsError>) {
+        if let Err(e) = result {
+            warn!(
+                "Could not record metric of plugin `{}`: {e}",
+                self.plugin_id
+            );
+        }
+    }
+
+    fn record(
+        &self,
+        name: &str,
+        kind: MetricKind,
+        labels: &[(String, String)],
+        value: f64,
+        add: bool,
+    ) -> Result<(), Error> {
+        if !is_valid_name(name) {
+            return Err(Error::InvalidName(name.to_owned()));
+        }
+        for (label, _) in labels {
+            if !is_valid_name(label) || label == "le" || RESERVED_LABELS.contains(&label.as_str()) {
+                return Err(Error::InvalidLabel(label.clone()));
+            }
+        }
+        if !value.is_finite() {
+            return Err(Error::InvalidValue);
+        }
+        if kind == MetricKind::Counter && value < 0.0 {
+            return Err(Error::InvalidValue);
+        }
+
+        let name = format!("{PLUGIN_METRIC_PREFIX}{name}");
+        let mut all_labels: Vec<(&str, &str)> = vec![
+            ("plugin_id", &*self.plugin_id),
+            ("plugin_version", &*self.plugin_version),
+        ];
+        all_labels.extend(labels.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+
+        let mut key = SeriesKey::new(&name, "", kind, &all_labels);
+        key.plugin = Some(SeriesLimits {
+            plugin_id: &self.plugin_id,
+            max_series: self.settings.max_series,
+            max_families: self.settings.max_families,
+        });
+        let result = match kind {
+            MetricKind::Counter => self.registry.update(key, |v| v + value),
+            MetricKind::Gauge if add => self.registry.update(key, |v| v + value),
+            MetricKind::Gauge => self.registry.update(key, |_| value),
+            MetricKind::Histogram => self.registry.observe(key, &self.settings.buckets, value),
+        };
+
+        result.map_err(|e| match e {
+            MetricsError::KindMismatch(..) => Error::KindMismatch,
+            MetricsError::CardinalityLimit(..) | MetricsError::FamilyLimit(..) => {
+                Error::CardinalityLimit
+            }
+        })
+    }
+}
+
+impl metrics::Host for PluginState {
+    async fn counter_add(
+        &mut self,
+        name: String,
+        value: f64,
+        labels: Vec<(String, String)>,
+    ) -> Result<(), Error> {
+        self.services
+            .metrics
+            .record(&name, MetricKind::Counter, &labels, value, true)
+    }
+
+    async fn gauge_set(
+        &mut self,
+        name: String,
+        value: f64,
+        labels: Vec<(String, String)>,
+    ) -> Result<(), Error> {
+        self.services
+            .metrics
+            .record(&name, MetricKind::Gauge, &labels, value, false)
+    }
+
+    async fn gauge_add(
+        &mut self,
+        name: String,
+        delta: f64,
+        labels: Vec<(String, String)>,
+    ) -> Result<(), Error> {
+        self.services
+            .metrics
+            .record(&name, MetricKind::Gauge, &labels, delta, true)
+    }
+
+    async fn histogram_observe(
+        &mut self,
+        name: String,
+        value: f64,
+        labels: Vec<(String, String)>,
+    ) -> Result<(), Error> {
+        self.services
+            .metrics
+            .record(&name, MetricKind::Histogram, &labels, value, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(
+        registry: &Arc<MetricsRegistry>,
+        id: &str,
+        settings: PluginMetricsSettings,
+    ) -> PluginMetrics {
+        PluginMetrics::new(registry.clone(), id, "1.0.0", settings).unwrap()
+    }
+
+    #[test]
+    fn plugins_share_families_but_not_limits() {
+        let registry = Arc::new(MetricsRegistry::new());
+        let settings = PluginMetricsSettings {
+            max_series: 1,
+            max_families: 1,
+            ..Default::default()
+        };
+        let first = plugin(&registry, "a-b", settings.clone());
+        let second = plugin(&registry, "a_b", settings);
+
+        first
+            .record("jobs", MetricKind::Counter, &[], 1.0, true)
+            .unwrap();
+        second
+            .record("jobs", MetricKind::Counter, &[], 5.0, true)
+            .unwrap();
+        assert!(matches!(
+            second.record("other", MetricKind::Counter, &[], 1.0, true),
+            Err(Error::CardinalityLimit)
+        ));
+
+        let rendered = registry.render();
+        assert_eq!(
+            rendered
+                .matches("# TYPE wassel_plugin_jobs counter")
+                .count(),
+            1
+        );
+        assert!(
+            rendered.contains("wassel_plugin_jobs{plugin_id=\"a-b\",plugin_version=\"1.0.0\"} 1")
+        );
+        assert!(
+            rendered.contains("wassel_plugin_jobs{plugin_id=\"a_b\",plugin_version=\"1.0.0\"} 5")
+        );
+    }
+
+    #[test]
+    fn buckets_are_validated() {
+        let registry = Arc::new(MetricsRegistry::new());
+        let buckets = |buckets: &[f64]| PluginMetricsSettings {
+            buckets: buckets.to_vec(),
+            ..Default::default()
+        };
+
+        for invalid in [&[][..], &[1.0, f64::NAN]] {
+            assert!(
+                PluginMetrics::new(registry.clone(), "test", "1.0.0", buckets(invalid)).is_err()
+            );
+        }
+        let plugin = plugin(&registry, "test", buckets(&[5.0, 1.0, 5.0]));
+        assert_eq!(plugin.settings.buckets, [1.0, 5.0]);
+    }
+
+    #[test]
+    fn limits_are_enforced_per_plugin() {
+        let registry = Arc::new(MetricsRegistry::new());
+        let settings = PluginMetricsSettings {
+            max_series: 1,
+            max_families: 1,
+            ..Default::default()
+        };
+        let plugin = plugin(&registry, "test", settings);
+        let labels = |v: &str| vec![("kind".to_owned(), v.to_owned())];
+
+        plugin
+            .record("jobs", MetricKind::Counter, &labels("a"), 1.0, true)
+            .unwrap();
+        assert!(matches!(
+            plugin.record("jobs", MetricKind::Counter, &labels("b"), 1.0, true),
+            Err(Error::CardinalityLimit)
+        ));
+        assert!(matches!(
+            plugin.record("other", MetricKind::Counter, &[], 1.0, true),
+            Err(Error::CardinalityLimit)
+        ));
+        assert!(matches!(
+            plugin.record("jobs", MetricKind::Gauge, &labels("a"), 1.0, true),
+            Err(Error::KindMismatch)
+        ));
+    }
+
+    #[test]
+    fn invalid_input_is_rejected() {
+        let registry = Arc::new(MetricsRegistry::new());
+        let plugin = plugin(&registry, "test", PluginMetricsSettings::default());
+        let reserved = vec![("plugin_id".to_owned(), "x".to_owned())];
+
+        assert!(matches!(
+            plugin.record("1jobs", MetricKind::Counter, &[], 1.0, true),
+            Err(Error::InvalidName(_))
+        ));
+        assert!(matches!(
+            plugin.record("jobs", MetricKind::Counter, &reserved, 1.0, true),
+            Err(Error::InvalidLabel(_))
+        ));
+        assert!(matches!(
+            plugin.record("jobs", MetricKind::Counter, &[], -1.0, true),
+            Err(Error::InvalidValue)
+        ));
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use wasmtime::{Engine, GuestProfiler};
+
+/// `[profiling]` section of `wassel.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfilingSettings {
+    /// Turns on epoch interruption in the engine, which slows down every
+    /// request a little, profiled or not
+    #[serde(default = "bool::default")]
+    pub enabled: bool,
+
+    /// Requests carrying this header are always profiled. Outside dev mode
+    /// its value has to be the admin token, otherwise it is ignored.
+    #[serde(default = "default_header")]
+    pub header: String,
+
+    /// Share of all other requests which is profiled, from 0 to 1
+    #[serde(default = "f64::default")]
+    pub sample_rate: f64,
+
+    /// Time between two samples of a profiled guest
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+
+    /// Directory profiles are written to, relative to the stack directory.
+    /// Every plugin gets a subdirectory.
+    #[serde(default = "default_directory")]
+    pub directory: PathBuf,
+
+    /// Most profiles kept per plugin, the oldest ones are removed first
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+impl Default for ProfilingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            header: default_header(),
+            sample_rate: 0.0,
+            interval_ms: default_interval_ms(),
+            directory: default_directory(),
+            max_files: default_max_files(),
+        }
+    }
+}
+
+fn default_header() -> String {
+    "x-wassel-profile".to_owned()
+}
+
+fn default_interval_ms() -> u64 {
+    1
+}
+
+fn default_directory() -> PathBuf {
+    PathBuf::from(".wassel/profiles")
+}
+
+fn default_max_files() -> usize {
+    50
+}
+
+/// Picks the requests to profile and writes their profiles
+#[derive(Debug)]
+pub struct GuestProfiling {
+    settings: ProfilingSettings,
+    /// Any request with the header is profiled, whatever its value
+    dev_mode: bool,
+    /// Value the header has to carry outside dev mode
+    header_token: Option<String>,
+    /// Requests seen so far, used to profile an exact share of them
+    requests: AtomicU64,
+}
+
+impl GuestProfiling {
+    /// Profiling where the header is honoured in dev mode, or when it carries
+    /// the token. Without either, only sampling picks requests.
+    pub fn new(settings: ProfilingSettings, dev_mode: bool, header_token: Option<String>) -> Self {
+        Self {
+            settings,
+            dev_mode,
+            header_token,
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.settings.interval_ms.max(1))
+    }
+
+    pub fn should_profile(&self, headers: &HeaderMap) -> bool {
+        if !self.settings.enabled {
+            return false;
+        }
+        if let Some(value) = headers.get(self.settings.header.as_str()) {
+            let allowed = self.dev_mode
+                || self
+                    .header_token
+                    .as_ref()
+                    .is_some_and(|token| constant_time_eq(value.as_bytes(), token.as_bytes()));
+            if allowed {
+                return true;
+            }
+            debug!("Ignoring profiling header without a valid token");
+        }
+
+        let rate = self.settings.sample_rate.clamp(0.0, 1.0);
+        if rate == 0.0 {
+            return false;
+        }
+        let n = self.requests.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * rate).floor() > (n * rate).floor()
+    }
+
+    /// Keeps advancing the engine's epoch so profiled instances get
+    /// interrupted to take samples. Stops once the engine is dropped.
+    pub fn start_ticker(&self, engine: &Engine) -> anyhow::Result<()> {
+        let engine = engine.weak();
+        let interval = self.interval();
+        thread::Builder::new()
+            .name("wassel-profiling".to_owned())
+            .spawn(move || {
+                while let Some(engine) = engine.upgrade() {
+                    engine.increment_epoch();
+                    drop(engine);
+                    thread::sleep(interval);
+                }
+            })
+            .context("Starting profiling thread")?;
+        Ok(())
+    }
+
+    /// Newest profile written for a request to the plugin
+    pub fn find_profile(&self, plugin_id: &str, request_id: &str) -> Option<PathBuf> {
+        let marker = format!("_{}_", file_name_part(request_id));
+        let mut profiles = self.profiles(plugin_id).ok()?;
+        profiles.retain(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().contains(&marker))
+        });
+        profiles.pop()
+    }
+
+    /// Writes the profile in the Firefox Profiler format and removes the
+    /// oldest profiles of the plugin beyond the limit. Blocks on file I/O.
+    pub fn save(
+        &self,
+        plugin_id: &str,
+        request_id: &str,
+        profiler: GuestProfiler,
+    ) -> anyhow::Result<PathBuf> {
+        let dir = self.settings.directory.join(plugin_id);
+        fs::create_dir_all(&dir).context(format!(
+            "Creating profile directory `{}`",
+            dir.to_string_lossy()
+        ))?;
+        // Request ids may repeat, so the time and a random part keep every
+        // profile in its own file
+        let unique = uuid::Uuid::new_v4().simple().to_string();
+        let path = dir.join(format!(
+            "{time}_{request}_{unique}.json",
+            time = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+            request = file_name_part(request_id),
+            unique = &unique[..8],
+        ));
+        let file = fs::File::create(&path)
+            .context(format!("Creating profile `{}`", path.to_string_lossy()))?;
+        profiler
+            .finish(std::io::BufWriter::new(file))
+            .context(format!("Writing profile `{}`", path.to_string_lossy()))?;
+
+        if let Err(e) = self.prune(plugin_id) {
+            warn!("Could not prune profiles of {plugin_id}: {e:#}");
+        }
+        Ok(path)
+    }
+
+    /// Profiles of the plugin, oldest first
+    fn profiles(&self, plugin_id: &str) -> std::io::Result<Vec<PathBuf>> {
+        let mut profiles = fs::read_dir(self.settings.directory.join(plugin_id))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        profiles.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+        // Names start with the time the profile was written
+        profiles.sort();
+        Ok(profiles)
+    }
+
+    fn prune(&self, plugin_id: &str) -> std::io::Result<()> {
+        let profiles = self.profiles(plugin_id)?;
+        let excess = profiles.len().saturating_sub(self.settings.max_files);
+        for path in &profiles[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps file names portable whatever ids contain. Leaves no `_`, which
+/// separates the parts of profile and core dump file names.
+pub(crate) fn file_name_part(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Compares tokens without leaking the position of the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn settings(directory: PathBuf) -> ProfilingSettings {
+        ProfilingSettings {
+            enabled: true,
+            directory,
+            max_files: 2,
+            ..Default::default()
+        }
+    }
+
+    fn headers(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-wassel-profile", HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn header_needs_token_outside_dev_mode() {
+        let profiling = GuestProfiling::new(settings(PathBuf::new()), false, Some("s3cr3t".into()));
+        assert!(profiling.should_profile(&headers("s3cr3t")));
+        assert!(!profiling.should_profile(&headers("1")));
+
+        let profiling = GuestProfiling::new(settings(PathBuf::new()), false, None);
+        assert!(!profiling.should_profile(&headers("1")));
+
+        let profiling = GuestProfiling::new(settings(PathBuf::new()), true, None);
+        assert!(profiling.should_profile(&headers("1")));
+    }
+
+    #[test]
+    fn oldest_profiles_are_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let profiling = GuestProfiling::new(settings(dir.path().into()), true, None);
+        let plugin_dir = dir.path().join("p");
+        fs::create_dir_all(&plugin_dir).unwrap();
+        for name in [
+            "20260101T000000.000Z_req_aaaaaaaa.json",
+            "20260101T000001.000Z_req_bbbbbbbb.json",
+            "20260101T000002.000Z_other_cccccccc.json",
+        ] {
+            fs::write(plugin_dir.join(name), "{}").unwrap();
+        }
+
+        profiling.prune("p").unwrap();
+        assert_eq!(profiling.profiles("p").unwrap().len(), 2);
+        let found = profiling.find_profile("p", "req").unwrap();
+        assert!(found.ends_with("20260101T000001.000Z_req_bbbbbbbb.json"));
+        assert!(profiling.find_profile("p", "missing").is_none());
+    }
+}
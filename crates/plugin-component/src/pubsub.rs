@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use wassel_world::wassel::foundation::pubsub;
+
+use crate::state::PluginState;
+
+/// Number of published messages the broker can fall behind before
+/// publishers have to wait
+const QUEUE_CAPACITY: usize = 1024;
+
+/// `[[subscriptions]]` entry of `plugin.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionMeta {
+    pub topic: String,
+
+    /// Maximum number of messages of the topic handled at the same time
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+
+    /// Deliveries of a message before it is dropped, including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+
+    /// Delay before the first redelivery, doubled for every next one
+    #[serde(default = "default_retry_delay_ms")]
+    pub retry_delay_ms: u64,
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_retry_delay_ms() -> u64 {
+    1000
+}
+
+#[derive(Debug, Clone)]
+pub struct PubSubMessage {
+    pub topic: String,
+    pub payload: Bytes,
+    /// Id of the publishing plugin
+    pub source: Arc<str>,
+}
+
+/// Sending side of the broker queue, attached to a plugin
+#[derive(Clone)]
+pub struct Publisher {
+    sender: mpsc::Sender<PubSubMessage>,
+    plugin_id: Arc<str>,
+}
+
+impl Publisher {
+    /// Creates publisher not yet attached to any plugin and the queue the
+    /// broker reads published messages from
+    pub fn channel() -> (Self, mpsc::Receiver<PubSubMessage>) {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let publisher = Self {
+            sender,
+            plugin_id: Arc::from(""),
+        };
+        (publisher, receiver)
+    }
+
+    pub fn for_plugin(&self, plugin_id: &str) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            plugin_id: Arc::from(plugin_id),
+        }
+    }
+
+    pub async fn publish(&self, topic: String, payload: Bytes) -> Result<(), String> {
+        if topic.is_empty() {
+            return Err("Topic must not be empty".to_owned());
+        }
+
+        let message = PubSubMessage {
+            topic,
+            payload,
+            source: self.plugin_id.clone(),
+        };
+        self.sender
+            .send(message)
+            .await
+            .map_err(|_| "Message broker is not running".to_owned())
+    }
+}
+
+impl pubsub::Host for PluginState {
+    async fn publish(&mut self, topic: String, payload: Vec<u8>) -> Result<(), String> {
+        self.services
+            .publisher
+            .publish(topic, Bytes::from(payload))
+            .await
+    }
+}
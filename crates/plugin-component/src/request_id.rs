@@ -0,0 +1,23 @@
+use http::HeaderMap;
+
+/// Header correlating a request across logs, plugins and outbound requests
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest incoming request id that is kept
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+pub fn generate_request_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Request id in the headers, unless it is missing or malformed
+pub fn read_request_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+}
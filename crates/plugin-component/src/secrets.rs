@@ -0,0 +1,302 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use chacha20poly1305::{
+    AeadCore as _, ChaCha20Poly1305, KeyInit as _, Nonce,
+    aead::{Aead as _, OsRng, Payload},
+};
+use serde::{Deserialize, Serialize, Serializer};
+use wassel_world::wassel::foundation::secrets::{self, Error};
+
+use crate::state::PluginState;
+
+/// Shown instead of secret values in logs and dumps
+pub(crate) const REDACTED: &str = "[REDACTED]";
+
+const NONCE_LENGTH: usize = 12;
+
+/// Stack-wide settings of the encrypted secrets file, read from `wassel.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecretsSettings {
+    /// Encrypted secrets file, relative to the stack directory
+    #[serde(default = "default_secrets_file")]
+    pub file: PathBuf,
+
+    /// Environment variable holding the base64 encoded 32 byte key of the file
+    #[serde(default = "default_key_env")]
+    pub key_env: String,
+}
+
+impl Default for SecretsSettings {
+    fn default() -> Self {
+        Self {
+            file: default_secrets_file(),
+            key_env: default_key_env(),
+        }
+    }
+}
+
+fn default_secrets_file() -> PathBuf {
+    PathBuf::from(".wassel/secrets.toml")
+}
+
+fn default_key_env() -> String {
+    "WASSEL_SECRETS_KEY".to_owned()
+}
+
+/// Where the value of a secret declared in `plugin.toml` comes from. Only
+/// the reference is stored in `plugin.toml`, never the value itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+    /// Environment variable of the server process
+    Env(String),
+    /// File whose contents, without trailing newline, are the value.
+    /// Relative paths start at the stack directory.
+    File(PathBuf),
+    /// Entry of the stack's encrypted secrets file
+    Encrypted(String),
+}
+
+/// Secret value that never shows up in `Debug`, `Display` or serialized output
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SecretsFileContents {
+    #[serde(default = "BTreeMap::default")]
+    secrets: BTreeMap<String, String>,
+}
+
+/// Stack's encrypted secrets file. Every entry is encrypted separately with
+/// ChaCha20-Poly1305, bound to its name so entries cannot be swapped.
+pub struct SecretsFile {
+    path: PathBuf,
+    key_env: String,
+    contents: SecretsFileContents,
+}
+
+impl SecretsFile {
+    /// Reads the file if it exists. The key is only needed once an entry is
+    /// decrypted or written.
+    pub fn open(settings: &SecretsSettings) -> anyhow::Result<Self> {
+        let contents = if settings.file.exists() {
+            let bytes = fs::read(&settings.file).context(format!(
+                "Reading secrets file `{}`",
+                settings.file.to_string_lossy()
+            ))?;
+            toml::from_slice(&bytes).context(format!(
+                "Deserializing secrets file `{}`",
+                settings.file.to_string_lossy()
+            ))?
+        } else {
+            SecretsFileContents::default()
+        };
+
+        Ok(Self {
+            path: settings.file.clone(),
+            key_env: settings.key_env.clone(),
+            contents,
+        })
+    }
+
+    /// Generates a new random key, base64 encoded as expected in the key variable
+    pub fn generate_key() -> String {
+        BASE64_STANDARD.encode(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.contents.secrets.keys().map(String::as_str)
+    }
+
+    pub fn get(&self, name: &str) -> anyhow::Result<Option<Secret>> {
+        let Some(entry) = self.contents.secrets.get(name) else {
+            return Ok(None);
+        };
+
+        let bytes = BASE64_STANDARD
+            .decode(entry)
+            .context(format!("Decoding secret `{name}`"))?;
+        if bytes.len() < NONCE_LENGTH {
+            anyhow::bail!("Secret `{name}` is too short");
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+        let plaintext = self
+            .cipher()?
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::format_err!("Decrypting secret `{name}`, is the key right?"))?;
+        let value =
+            String::from_utf8(plaintext).context(format!("Secret `{name}` is not UTF-8"))?;
+
+        Ok(Some(Secret(value)))
+    }
+
+    pub fn set(&mut self, name: &str, value: &Secret) -> anyhow::Result<()> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()?
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value.expose().as_bytes(),
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::format_err!("Encrypting secret `{name}`"))?;
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&ciphertext);
+        self.contents
+            .secrets
+            .insert(name.to_owned(), BASE64_STANDARD.encode(bytes));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.contents.secrets.remove(name).is_some()
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).context(format!(
+                "Creating secrets directory `{}`",
+                dir.to_string_lossy()
+            ))?;
+        }
+        let contents =
+            toml::to_string_pretty(&self.contents).context("Serializing secrets file")?;
+        fs::write(&self.path, contents).context(format!(
+            "Writing secrets file `{}`",
+            self.path.to_string_lossy()
+        ))
+    }
+
+    fn cipher(&self) -> anyhow::Result<ChaCha20Poly1305> {
+        let key = env::var(&self.key_env)
+            .context(format!("Reading secrets key from `{}`", self.key_env))?;
+        let key = BASE64_STANDARD
+            .decode(key.trim())
+            .context(format!("Decoding secrets key in `{}`", self.key_env))?;
+        ChaCha20Poly1305::new_from_slice(&key)
+            .map_err(|_| anyhow::format_err!("Secrets key in `{}` must be 32 bytes", self.key_env))
+    }
+}
+
+/// Resolved secrets of a single plugin
+#[derive(Debug, Default)]
+pub struct PluginSecrets {
+    values: HashMap<String, Secret>,
+}
+
+impl PluginSecrets {
+    pub fn resolve(
+        sources: &HashMap<String, SecretSource>,
+        base_path: impl AsRef<Path>,
+        file: &SecretsFile,
+    ) -> anyhow::Result<Self> {
+        let values = sources
+            .iter()
+            .map(|(name, source)| {
+                let value = resolve_source(source, base_path.as_ref(), file)
+                    .context(format!("Resolving secret `{name}`"))?;
+                Ok((name.clone(), value))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { values })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_values<'a>(values: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let values = values
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), Secret::new(value)))
+            .collect();
+        Self { values }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Secret> {
+        self.values.get(name)
+    }
+
+    /// Replaces every secret value occurring in the text
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for secret in self.values.values() {
+            if !secret.0.is_empty() && text.contains(&secret.0) {
+                text = Cow::Owned(text.replace(&secret.0, REDACTED));
+            }
+        }
+        text
+    }
+}
+
+fn resolve_source(
+    source: &SecretSource,
+    base_path: &Path,
+    file: &SecretsFile,
+) -> anyhow::Result<Secret> {
+    match source {
+        SecretSource::Env(var) => env::var(var)
+            .map(Secret)
+            .context(format!("Reading environment variable `{var}`")),
+        SecretSource::File(path) => {
+            let path = base_path.join(path);
+            let value = fs::read_to_string(&path)
+                .context(format!("Reading secret file `{}`", path.to_string_lossy()))?;
+            Ok(Secret(value.trim_end_matches(['\n', '\r']).to_owned()))
+        }
+        SecretSource::Encrypted(name) => file
+            .get(name)?
+            .context(format!("There is no `{name}` in the secrets file")),
+    }
+}
+
+impl secrets::Host for PluginState {
+    async fn get(&mut self, name: String) -> Result<String, Error> {
+        self.services
+            .secrets
+            .get(&name)
+            .map(|s| s.expose().to_owned())
+            .ok_or(Error::NotFound)
+    }
+}
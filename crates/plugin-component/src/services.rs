@@ -0,0 +1,22 @@
+use std::sync::Arc;
+
+use crate::{
+    blobstore::BlobStore, coredump::PluginCoreDumps, keyvalue::KeyValue, metrics::PluginMetrics,
+    outbound::OutboundHttp, pubsub::Publisher, secrets::PluginSecrets, sql::SqlDatabase,
+};
+
+/// Host-side services of a plugin, shared by all of its instances
+#[derive(Clone)]
+pub struct PluginServices {
+    pub outbound: OutboundHttp,
+    pub keyvalue: Arc<KeyValue>,
+    pub publisher: Publisher,
+    /// Present when the plugin declares `[sql]`
+    pub sql: Option<Arc<SqlDatabase>>,
+    pub secrets: Arc<PluginSecrets>,
+    pub blobstore: Arc<BlobStore>,
+    pub metrics: PluginMetrics,
+    pub coredumps: PluginCoreDumps,
+    /// Keep guest stderr of every instance for debug error pages
+    pub capture_stderr: bool,
+}
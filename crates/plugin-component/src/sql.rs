@@ -0,0 +1,634 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use rusqlite::{Connection, params_from_iter, types::Value as SqliteValue};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::info;
+use wasmtime::component::Resource;
+use wassel_world::{
+    resources::{SqlConnection, SqlStatement},
+    wassel::foundation::sql::{self, Error, Rows, Value},
+};
+
+use crate::state::PluginState;
+
+/// Table recording which migrations were already applied
+const MIGRATIONS_TABLE: &str = "_wassel_migrations";
+
+/// Stack-wide settings of plugin databases, read from `wassel.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SqlSettings {
+    /// Directory with a subdirectory per plugin holding its database,
+    /// relative to the stack directory.
+    ///
+    /// Databases are kept here rather than in the plugin's `data_dir`: that
+    /// directory is preopened read-write for the guest, which could then
+    /// change or lock the file behind the host, and build `data` is synced
+    /// into it.
+    #[serde(default = "default_sql_path")]
+    pub path: PathBuf,
+}
+
+impl Default for SqlSettings {
+    fn default() -> Self {
+        Self {
+            path: default_sql_path(),
+        }
+    }
+}
+
+fn default_sql_path() -> PathBuf {
+    PathBuf::from(".wassel/sql")
+}
+
+/// `[sql]` section of `plugin.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginSql {
+    /// Database file, relative to the plugin's directory under the stack's
+    /// SQL path. Absolute paths and `..` are rejected.
+    #[serde(default = "default_database")]
+    pub database: PathBuf,
+
+    /// Directory with `*.sql` migrations applied in file name order on load,
+    /// relative to the plugin directory
+    #[serde(default = "Option::default")]
+    pub migrations: Option<PathBuf>,
+
+    /// Maximum number of connections open at the same time across all
+    /// instances, at least 1
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+
+    /// How long a statement waits for a lock held by another connection
+    #[serde(default = "default_busy_timeout_ms")]
+    pub busy_timeout_ms: u64,
+}
+
+fn default_database() -> PathBuf {
+    PathBuf::from("database.sqlite")
+}
+
+fn default_max_connections() -> usize {
+    4
+}
+
+fn default_busy_timeout_ms() -> u64 {
+    5000
+}
+
+/// Connection limits by plugin id, kept across stack reloads so the stack
+/// being replaced and the new one together stay within a plugin's limit
+#[derive(Clone, Default)]
+pub struct SqlConnectionLimits(Arc<Mutex<HashMap<String, ConnectionLimit>>>);
+
+struct ConnectionLimit {
+    max_connections: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl SqlConnectionLimits {
+    fn get(&self, plugin_id: &str, max_connections: usize) -> Arc<Semaphore> {
+        let mut limits = self
+            .0
+            .lock()
+            .expect("Connection limits lock should not be poisoned");
+        match limits.get(plugin_id) {
+            Some(limit) if limit.max_connections == max_connections => limit.permits.clone(),
+            // Changed limit only counts connections opened from now on
+            _ => {
+                let permits = Arc::new(Semaphore::new(max_connections));
+                limits.insert(
+                    plugin_id.to_owned(),
+                    ConnectionLimit {
+                        max_connections,
+                        permits: permits.clone(),
+                    },
+                );
+                permits
+            }
+        }
+    }
+}
+
+/// SQLite database of a single plugin, shared by all of its instances
+pub struct SqlDatabase {
+    path: PathBuf,
+    busy_timeout: Duration,
+    connections: Arc<Semaphore>,
+    max_connections: usize,
+}
+
+impl SqlDatabase {
+    /// Opens the database and applies pending migrations
+    pub fn new(
+        settings: &SqlSettings,
+        limits: &SqlConnectionLimits,
+        plugin_id: &str,
+        plugin_dir: impl AsRef<Path>,
+        plugin: &PluginSql,
+    ) -> anyhow::Result<Self> {
+        if !is_contained(&plugin.database) {
+            anyhow::bail!(
+                "Database `{}` has to be a relative path without `..`",
+                plugin.database.to_string_lossy()
+            );
+        }
+        if plugin.max_connections == 0 {
+            anyhow::bail!("`max_connections` has to be at least 1");
+        }
+
+        let dir = settings.path.join(plugin_id);
+        fs::create_dir_all(&dir).context(format!(
+            "Creating database directory `{}`",
+            dir.to_string_lossy()
+        ))?;
+        let database = Self {
+            path: dir.join(&plugin.database),
+            busy_timeout: Duration::from_millis(plugin.busy_timeout_ms),
+            connections: limits.get(plugin_id, plugin.max_connections),
+            max_connections: plugin.max_connections,
+        };
+
+        let mut connection = database.connect().context(format!(
+            "Opening database `{}`",
+            database.path.to_string_lossy()
+        ))?;
+        if let Some(migrations) = &plugin.migrations {
+            run_migrations(&mut connection, &plugin_dir.as_ref().join(migrations))?;
+        }
+
+        Ok(database)
+    }
+
+    /// Connections currently held by instances
+    pub fn open_connections(&self) -> usize {
+        self.max_connections - self.connections.available_permits()
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    fn connect(&self) -> rusqlite::Result<Connection> {
+        let connection = Connection::open(&self.path)?;
+        connection.busy_timeout(self.busy_timeout)?;
+        Ok(connection)
+    }
+
+    fn open(&self) -> Result<OpenConnection, Error> {
+        let permit = self
+            .connections
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Error::ConnectionLimit)?;
+        let connection = self.connect().map_err(convert_sqlite_error)?;
+        Ok(OpenConnection {
+            connection: Arc::new(Mutex::new(connection)),
+            _permit: permit,
+        })
+    }
+}
+
+/// Whether the path stays inside the directory it is joined to
+fn is_contained(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        && path.components().any(|c| matches!(c, Component::Normal(_)))
+}
+
+fn run_migrations(connection: &mut Connection, dir: &Path) -> anyhow::Result<()> {
+    let mut files = fs::read_dir(dir)
+        .context(format!(
+            "Reading migrations directory `{}`",
+            dir.to_string_lossy()
+        ))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|path| path.extension().is_some_and(|ext| ext == "sql"));
+    files.sort();
+
+    connection
+        .execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {MIGRATIONS_TABLE} (
+                name TEXT PRIMARY KEY,
+                applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )"
+        ))
+        .context("Creating migrations table")?;
+
+    for path in files {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let transaction = connection.transaction()?;
+        let applied: bool = transaction.query_row(
+            &format!("SELECT EXISTS (SELECT 1 FROM {MIGRATIONS_TABLE} WHERE name = ?1)"),
+            [&name],
+            |row| row.get(0),
+        )?;
+        if applied {
+            continue;
+        }
+
+        let sql = fs::read_to_string(&path)
+            .context(format!("Reading migration `{}`", path.to_string_lossy()))?;
+        transaction
+            .execute_batch(&sql)
+            .context(format!("Applying migration `{name}`"))?;
+        transaction.execute(
+            &format!("INSERT INTO {MIGRATIONS_TABLE} (name) VALUES (?1)"),
+            [&name],
+        )?;
+        transaction.commit()?;
+        info!("Applied migration `{name}`");
+    }
+
+    Ok(())
+}
+
+/// Connection counted against the plugin's limit until it is dropped
+pub(crate) struct OpenConnection {
+    connection: Arc<Mutex<Connection>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Connections opened by an instance, referred to by the ids in resources
+#[derive(Default)]
+pub(crate) struct SqlConnections {
+    next_id: u64,
+    open: HashMap<u64, OpenConnection>,
+}
+
+impl SqlConnections {
+    fn insert(&mut self, connection: OpenConnection) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.open.insert(id, connection);
+        id
+    }
+
+    fn get(&self, id: u64) -> Result<Arc<Mutex<Connection>>, Error> {
+        self.open
+            .get(&id)
+            .map(|c| c.connection.clone())
+            .ok_or(Error::Closed)
+    }
+}
+
+/// Runs blocking SQLite call off the async runtime
+async fn with_connection<T: Send + 'static>(
+    connection: Arc<Mutex<Connection>>,
+    f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> Result<T, Error> {
+    tokio::task::spawn_blocking(move || {
+        let connection = connection
+            .lock()
+            .expect("Connection lock should not be poisoned");
+        f(&connection)
+    })
+    .await
+    .map_err(|e| Error::Database(format!("Database task failed: {e}")))?
+    .map_err(convert_sqlite_error)
+}
+
+fn execute(connection: &Connection, sql: &str, params: Vec<Value>) -> rusqlite::Result<u64> {
+    let mut statement = connection.prepare_cached(sql)?;
+    let changed = statement.execute(params_from_iter(params.into_iter().map(to_sqlite)))?;
+    Ok(changed as u64)
+}
+
+fn query(connection: &Connection, sql: &str, params: Vec<Value>) -> rusqlite::Result<Rows> {
+    let mut statement = connection.prepare_cached(sql)?;
+    let columns: Vec<String> = statement
+        .column_names()
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+
+    let mut result = Vec::new();
+    let mut rows = statement.query(params_from_iter(params.into_iter().map(to_sqlite)))?;
+    while let Some(row) = rows.next()? {
+        let row = (0..columns.len())
+            .map(|i| row.get_ref(i).map(|v| from_sqlite(v.into())))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        result.push(row);
+    }
+
+    Ok(Rows {
+        columns,
+        rows: result,
+    })
+}
+
+fn to_sqlite(value: Value) -> SqliteValue {
+    match value {
+        Value::Null => SqliteValue::Null,
+        Value::Integer(v) => SqliteValue::Integer(v),
+        Value::Real(v) => SqliteValue::Real(v),
+        Value::Text(v) => SqliteValue::Text(v),
+        Value::Blob(v) => SqliteValue::Blob(v),
+    }
+}
+
+fn from_sqlite(value: SqliteValue) -> Value {
+    match value {
+        SqliteValue::Null => Value::Null,
+        SqliteValue::Integer(v) => Value::Integer(v),
+        SqliteValue::Real(v) => Value::Real(v),
+        SqliteValue::Text(v) => Value::Text(v),
+        SqliteValue::Blob(v) => Value::Blob(v),
+    }
+}
+
+fn convert_sqlite_error(e: rusqlite::Error) -> Error {
+    Error::Database(e.to_string())
+}
+
+impl PluginState {
+    fn sql_connection(
+        &self,
+        connection: &Resource<SqlConnection>,
+    ) -> Result<Arc<Mutex<Connection>>, Error> {
+        let connection = self
+            .table
+            .get(connection)
+            .map_err(|e| Error::Database(format!("Could not get connection resource: {e:?}")))?;
+        self.sql.get(connection.id)
+    }
+
+    fn sql_statement(
+        &self,
+        statement: &Resource<SqlStatement>,
+    ) -> Result<(Arc<Mutex<Connection>>, String), Error> {
+        let statement = self
+            .table
+            .get(statement)
+            .map_err(|e| Error::Database(format!("Could not get statement resource: {e:?}")))?;
+        Ok((self.sql.get(statement.connection)?, statement.sql.clone()))
+    }
+}
+
+impl sql::Host for PluginState {}
+
+impl sql::HostConnection for PluginState {
+    async fn open(&mut self) -> Result<Resource<SqlConnection>, Error> {
+        let database = self.services.sql.as_ref().ok_or(Error::NotEnabled)?;
+        let id = self.sql.insert(database.open()?);
+        self.table
+            .push(SqlConnection { id })
+            .map_err(|e| Error::Database(format!("Could not create connection resource: {e:?}")))
+    }
+
+    async fn prepare(
+        &mut self,
+        connection: Resource<SqlConnection>,
+        sql: String,
+    ) -> Result<Resource<SqlStatement>, Error> {
+        let id = self
+            .table
+            .get(&connection)
+            .map_err(|e| Error::Database(format!("Could not get connection resource: {e:?}")))?
+            .id;
+        let statement = sql.clone();
+        with_connection(self.sql.get(id)?, move |c| {
+            c.prepare_cached(&statement).map(|_| ())
+        })
+        .await?;
+
+        self.table
+            .push(SqlStatement {
+                connection: id,
+                sql,
+            })
+            .map_err(|e| Error::Database(format!("Could not create statement resource: {e:?}")))
+    }
+
+    async fn execute(
+        &mut self,
+        connection: Resource<SqlConnection>,
+        sql: String,
+        params: Vec<Value>,
+    ) -> Result<u64, Error> {
+        let connection = self.sql_connection(&connection)?;
+        with_connection(connection, move |c| execute(c, &sql, params)).await
+    }
+
+    async fn query(
+        &mut self,
+        connection: Resource<SqlConnection>,
+        sql: String,
+        params: Vec<Value>,
+    ) -> Result<Rows, Error> {
+        let connection = self.sql_connection(&connection)?;
+        with_connection(connection, move |c| query(c, &sql, params)).await
+    }
+
+    async fn drop(&mut self, connection: Resource<SqlConnection>) -> wasmtime::Result<()> {
+        let connection = self.table.delete(connection)?;
+        self.sql.open.remove(&connection.id);
+        Ok(())
+    }
+}
+
+impl sql::HostStatement for PluginState {
+    async fn execute(
+        &mut self,
+        statement: Resource<SqlStatement>,
+        params: Vec<Value>,
+    ) -> Result<u64, Error> {
+        let (connection, sql) = self.sql_statement(&statement)?;
+        with_connection(connection, move |c| execute(c, &sql, params)).await
+    }
+
+    async fn query(
+        &mut self,
+        statement: Resource<SqlStatement>,
+        params: Vec<Value>,
+    ) -> Result<Rows, Error> {
+        let (connection, sql) = self.sql_statement(&statement)?;
+        with_connection(connection, move |c| query(c, &sql, params)).await
+    }
+
+    async fn drop(&mut self, statement: Resource<SqlStatement>) -> wasmtime::Result<()> {
+        self.table.delete(statement)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(migrations: Option<&str>) -> PluginSql {
+        PluginSql {
+            database: default_database(),
+            migrations: migrations.map(PathBuf::from),
+            max_connections: 1,
+            busy_timeout_ms: default_busy_timeout_ms(),
+        }
+    }
+
+    #[test]
+    fn migrations_are_applied_once() {
+        let stack = tempfile::tempdir().unwrap();
+        let plugin_dir = tempfile::tempdir().unwrap();
+        let migrations = plugin_dir.path().join("migrations");
+        fs::create_dir_all(&migrations).unwrap();
+        fs::write(
+            migrations.join("001_notes.sql"),
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT NOT NULL);",
+        )
+        .unwrap();
+        fs::write(migrations.join("readme.txt"), "not a migration").unwrap();
+
+        let settings = SqlSettings {
+            path: stack.path().join(".wassel/sql"),
+        };
+        let plugin = plugin(Some("migrations"));
+        SqlDatabase::new(
+            &settings,
+            &SqlConnectionLimits::default(),
+            "notes",
+            plugin_dir.path(),
+            &plugin,
+        )
+        .unwrap();
+        // Applying the migration again would fail as the table exists
+        let database = SqlDatabase::new(
+            &settings,
+            &SqlConnectionLimits::default(),
+            "notes",
+            plugin_dir.path(),
+            &plugin,
+        )
+        .unwrap();
+
+        assert_eq!(
+            database.path,
+            stack.path().join(".wassel/sql/notes/database.sqlite")
+        );
+        let connection = database.connect().unwrap();
+        let applied: i64 = connection
+            .query_row(
+                &format!("SELECT COUNT(*) FROM {MIGRATIONS_TABLE}"),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(applied, 1);
+    }
+
+    #[test]
+    fn execute_and_query_convert_values() {
+        let stack = tempfile::tempdir().unwrap();
+        let settings = SqlSettings {
+            path: stack.path().to_owned(),
+        };
+        let database = SqlDatabase::new(
+            &settings,
+            &SqlConnectionLimits::default(),
+            "test",
+            stack.path(),
+            &plugin(None),
+        )
+        .unwrap();
+        let connection = database.connect().unwrap();
+        connection
+            .execute_batch("CREATE TABLE t (i INTEGER, r REAL, s TEXT, b BLOB)")
+            .unwrap();
+
+        let params = vec![
+            Value::Integer(1),
+            Value::Real(0.5),
+            Value::Text("text".to_owned()),
+            Value::Blob(vec![1, 2]),
+        ];
+        let changed =
+            execute(&connection, "INSERT INTO t VALUES (?1, ?2, ?3, ?4)", params).unwrap();
+        assert_eq!(changed, 1);
+
+        let rows = query(
+            &connection,
+            "SELECT i, r, s, b, NULL AS n FROM t",
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(rows.columns, ["i", "r", "s", "b", "n"]);
+        assert!(matches!(
+            rows.rows.as_slice(),
+            [row] if matches!(
+                row.as_slice(),
+                [Value::Integer(1), Value::Real(r), Value::Text(s), Value::Blob(b), Value::Null]
+                    if *r == 0.5 && s == "text" && b == &[1, 2]
+            )
+        ));
+    }
+
+    #[test]
+    fn connections_are_limited() {
+        let stack = tempfile::tempdir().unwrap();
+        let settings = SqlSettings {
+            path: stack.path().to_owned(),
+        };
+        let database = SqlDatabase::new(
+            &settings,
+            &SqlConnectionLimits::default(),
+            "test",
+            stack.path(),
+            &plugin(None),
+        )
+        .unwrap();
+
+        let open = database.open().unwrap();
+        assert_eq!(database.open_connections(), 1);
+        assert!(matches!(database.open(), Err(Error::ConnectionLimit)));
+        drop(open);
+        assert!(database.open().is_ok());
+    }
+
+    #[test]
+    fn reloaded_databases_share_the_limit() {
+        let stack = tempfile::tempdir().unwrap();
+        let settings = SqlSettings {
+            path: stack.path().to_owned(),
+        };
+        let limits = SqlConnectionLimits::default();
+        let old =
+            SqlDatabase::new(&settings, &limits, "test", stack.path(), &plugin(None)).unwrap();
+        let new =
+            SqlDatabase::new(&settings, &limits, "test", stack.path(), &plugin(None)).unwrap();
+
+        let _open = old.open().unwrap();
+        assert!(matches!(new.open(), Err(Error::ConnectionLimit)));
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let stack = tempfile::tempdir().unwrap();
+        let settings = SqlSettings {
+            path: stack.path().to_owned(),
+        };
+        let limits = SqlConnectionLimits::default();
+        for database in ["/tmp/escape.sqlite", "../escape.sqlite", "a/../../b", ""] {
+            let plugin = PluginSql {
+                database: PathBuf::from(database),
+                ..plugin(None)
+            };
+            assert!(SqlDatabase::new(&settings, &limits, "test", stack.path(), &plugin).is_err());
+        }
+        let plugin = PluginSql {
+            max_connections: 0,
+            ..plugin(None)
+        };
+        assert!(SqlDatabase::new(&settings, &limits, "test", stack.path(), &plugin).is_err());
+    }
+}
@@ -1,37 +1,78 @@
 use anyhow::Context as _;
+use tracing::{Instrument as _, Span};
 use wasmtime_wasi::{
     DirPerms, FilePerms, ResourceTable, WasiCtx, WasiCtxBuilder, WasiCtxView, WasiView,
+    cli::AsyncStdoutStream,
 };
 use wasmtime_wasi_config::WasiConfigVariables;
-use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView, body::HostIncomingBody};
+use wasmtime_wasi_http::{
+    HttpResult, WasiHttpCtx, WasiHttpView,
+    body::{HostIncomingBody, HyperOutgoingBody},
+    types::{
+        HostFutureIncomingResponse, IncomingResponse as HostIncomingResponse, OutgoingRequestConfig,
+    },
+};
 
-use std::{path::Path, pin::Pin, time::Duration};
+use std::path::Path;
 
-use bytes::Bytes;
-use futures_util::{Stream, TryStreamExt as _};
-use http::Method;
 use http::method::InvalidMethod;
-use http_body_util::{BodyExt as _, combinators::UnsyncBoxBody};
-use wasmtime::component::Resource;
+use http::{HeaderMap, HeaderValue, Method};
+use http_body_util::{BodyExt as _, Empty};
+use hyper::Request;
+use wasmtime::{GuestProfiler, component::Resource};
 use wassel_world::{
-    wasi::http::types::{ErrorCode, Method as WasiMethod},
+    wasi::http::types::{ErrorCode, Method as WasiMethod, Scheme},
     wassel::foundation::http_client::{self, IncomingResponse, OutgoingRequest},
 };
 
+use crate::{
+    logging::{CapturedOutput, LogWriter, OutputStream},
+    outbound,
+    request_id::REQUEST_ID_HEADER,
+    services::PluginServices,
+    sql::SqlConnections,
+    websocket::WebSocketState,
+};
+
+/// Bytes of guest stdout and stderr buffered before writes block
+const OUTPUT_BUDGET: usize = 8 * 1024;
+
 pub struct PluginState {
     ctx: WasiCtx,
     config_vars: WasiConfigVariables,
-    table: ResourceTable,
+    pub(crate) table: ResourceTable,
     http_ctx: WasiHttpCtx,
-    http_client: reqwest::Client,
+    pub(crate) services: PluginServices,
+    pub(crate) span: Span,
+    pub(crate) websocket: Option<WebSocketState>,
+    pub(crate) sql: SqlConnections,
+    /// Id of the request being handled, passed on to outbound requests
+    pub(crate) request_id: Option<String>,
+    /// Present while the instance is being profiled
+    pub(crate) profiler: Option<GuestProfiler>,
+    /// Guest stderr, kept when the services ask for it
+    pub(crate) stderr: Option<CapturedOutput>,
 }
 
 impl PluginState {
-    pub fn new(data_dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+    pub fn new(
+        data_dir: impl AsRef<Path>,
+        services: PluginServices,
+        span: Span,
+    ) -> anyhow::Result<Self> {
+        let stderr = services.capture_stderr.then(CapturedOutput::default);
         let ctx = {
             let mut builder = WasiCtxBuilder::new();
-            builder.inherit_stdout();
-            builder.inherit_stderr();
+            builder.stdout(AsyncStdoutStream::new(
+                OUTPUT_BUDGET,
+                LogWriter::new(OutputStream::Stdout, span.clone(), services.secrets.clone()),
+            ));
+            let mut stderr_writer =
+                LogWriter::new(OutputStream::Stderr, span.clone(), services.secrets.clone());
+            if let Some(capture) = &stderr {
+                stderr_writer = stderr_writer.capturing(capture.clone());
+            }
+            builder.stderr(AsyncStdoutStream::new(OUTPUT_BUDGET, stderr_writer));
             builder
                 .preopened_dir(data_dir.as_ref(), ".", DirPerms::all(), FilePerms::all())
                 .context(format!(
@@ -46,7 +87,13 @@ impl PluginState {
             config_vars: WasiConfigVariables::new(),
             table: ResourceTable::new(),
             http_ctx: WasiHttpCtx::new(),
-            http_client: reqwest::Client::new(),
+            services,
+            span,
+            websocket: None,
+            sql: SqlConnections::default(),
+            request_id: None,
+            profiler: None,
+            stderr,
         };
 
         Ok(s)
@@ -55,6 +102,16 @@ impl PluginState {
     pub fn config_vars(&self) -> &WasiConfigVariables {
         &self.config_vars
     }
+
+    /// Adds id of the request being handled unless the guest set one itself
+    fn add_request_id(&self, headers: &mut HeaderMap) {
+        if let Some(id) = &self.request_id
+            && !headers.contains_key(REQUEST_ID_HEADER)
+            && let Ok(value) = HeaderValue::from_str(id)
+        {
+            headers.insert(REQUEST_ID_HEADER, value);
+        }
+    }
 }
 
 impl WasiView for PluginState {
@@ -74,6 +131,32 @@ impl WasiHttpView for PluginState {
     fn table(&mut self) -> &mut ResourceTable {
         &mut self.table
     }
+
+    fn send_request(
+        &mut self,
+        mut request: Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+    ) -> HttpResult<HostFutureIncomingResponse> {
+        self.add_request_id(request.headers_mut());
+        let outbound = self.services.outbound.clone();
+        let metrics = self.services.metrics.clone();
+        let span = Span::current();
+        let handle = wasmtime_wasi::runtime::spawn(
+            async move {
+                let response = outbound.send(request, &config).await;
+                metrics.record_outbound(response.as_ref().ok().map(|r| r.status()));
+                let response = response.map(|resp| HostIncomingResponse {
+                    resp,
+                    worker: None,
+                    between_bytes_timeout: config.between_bytes_timeout,
+                });
+                Ok(response)
+            }
+            .instrument(span),
+        );
+
+        Ok(HostFutureIncomingResponse::pending(handle))
+    }
 }
 
 impl http_client::Host for PluginState {
@@ -91,35 +174,33 @@ impl http_client::Host for PluginState {
         let method = convert_wasi_method_to_reqwest_method(&req.method)
             .map_err(|_| ErrorCode::HttpRequestMethodInvalid)?;
 
-        let mut request = self
-            .http_client
-            .request(method, url)
-            .headers(req.headers.clone());
-
-        if let Some(body) = req.body.take() {
-            let body = reqwest::Body::wrap_stream(body.into_data_stream());
-            request = request.body(body);
-        }
-
-        let response = request
-            .send()
-            .await
-            .map_err(convert_reqwest_error_to_error_code)?;
-
-        let status = response.status().into();
-        let headers = response.headers().to_owned();
-
-        let body_stream = Box::pin(response.bytes_stream());
-
-        let hyper_body = UnsyncBoxBody::new(StreamBody {
-            stream: body_stream,
-        });
-        let incoming_body = HostIncomingBody::new(hyper_body, Duration::from_secs(5));
-
+        let body = req
+            .body
+            .take()
+            .unwrap_or_else(|| Empty::new().map_err(|e| match e {}).boxed_unsync());
+
+        let mut request = Request::builder()
+            .method(method)
+            .uri(&url)
+            .body(body)
+            .map_err(|_| ErrorCode::HttpRequestUriInvalid)?;
+        *request.headers_mut() = req.headers.clone();
+
+        let use_tls = matches!(req.scheme, Some(Scheme::Https)) || url.starts_with("https:");
+        let config = outbound::http_client_request_config(use_tls);
+        self.add_request_id(request.headers_mut());
+
+        let response = self.services.outbound.send(request, &config).await;
+        self.services
+            .metrics
+            .record_outbound(response.as_ref().ok().map(|r| r.status()));
+        let response = response?;
+
+        let (parts, body) = response.into_parts();
         let response = IncomingResponse {
-            status,
-            headers,
-            body: Some(incoming_body),
+            status: parts.status.into(),
+            headers: parts.headers,
+            body: Some(HostIncomingBody::new(body, config.between_bytes_timeout)),
         };
 
         let resource = self.table.push(response).map_err(|e| {
@@ -132,35 +213,6 @@ impl http_client::Host for PluginState {
     }
 }
 
-struct StreamBody<S> {
-    stream: S,
-}
-
-impl<S> hyper::body::Body for StreamBody<Pin<Box<S>>>
-where
-    S: Stream<Item = reqwest::Result<Bytes>>,
-{
-    type Data = Bytes;
-
-    type Error = ErrorCode;
-
-    fn poll_frame(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Option<Result<hyper::body::Frame<Self::Data>, Self::Error>>> {
-        match self.get_mut().stream.try_poll_next_unpin(cx) {
-            std::task::Poll::Ready(v) => match v {
-                Some(result) => std::task::Poll::Ready(Some(match result {
-                    Ok(bytes) => Ok(hyper::body::Frame::data(bytes)),
-                    Err(e) => Err(convert_reqwest_error_to_error_code(e)),
-                })),
-                None => std::task::Poll::Ready(None),
-            },
-            std::task::Poll::Pending => std::task::Poll::Pending,
-        }
-    }
-}
-
 fn convert_wasi_method_to_reqwest_method(method: &WasiMethod) -> Result<Method, InvalidMethod> {
     let method = match method {
         WasiMethod::Get => Method::GET,
@@ -177,7 +229,3 @@ fn convert_wasi_method_to_reqwest_method(method: &WasiMethod) -> Result<Method,
 
     Ok(method)
 }
-
-fn convert_reqwest_error_to_error_code(e: reqwest::Error) -> ErrorCode {
-    ErrorCode::InternalError(Some(format!("Reqwest error: {e:?}")))
-}
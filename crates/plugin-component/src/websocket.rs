@@ -0,0 +1,316 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{SinkExt as _, StreamExt as _};
+use http::{HeaderMap, Method, header};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, time::Instant};
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{
+        self, Message,
+        protocol::{CloseFrame as TungsteniteCloseFrame, Role, frame::coding::CloseCode},
+    },
+};
+use wasmtime::component::Resource;
+use wassel_world::{
+    resources::WebSocketSession,
+    wassel::foundation::websocket::{self, CloseFrame, Error, Frame},
+};
+
+use crate::state::PluginState;
+
+/// Time given to the peer to answer the closing handshake
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// WebSocket limits of a plugin, read from `plugin.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginWebSocket {
+    /// Maximum number of simultaneously open connections
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+
+    /// Connection is closed when no frame was sent or received for this long
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for PluginWebSocket {
+    fn default() -> Self {
+        Self {
+            max_connections: default_max_connections(),
+            idle_timeout_secs: default_idle_timeout_secs(),
+        }
+    }
+}
+
+fn default_max_connections() -> usize {
+    100
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    300
+}
+
+/// Whether the request asks for a WebSocket upgrade
+pub fn is_upgrade_request<B>(req: &http::Request<B>) -> bool {
+    let has_token = |name: header::HeaderName, token: &str| {
+        req.headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(token))
+    };
+
+    req.method() == Method::GET
+        && has_token(header::CONNECTION, "upgrade")
+        && has_token(header::UPGRADE, "websocket")
+        && req.headers().contains_key(header::SEC_WEBSOCKET_KEY)
+        && req
+            .headers()
+            .get(header::SEC_WEBSOCKET_VERSION)
+            .is_some_and(|v| v == "13")
+}
+
+type Stream = WebSocketStream<TokioIo<Upgraded>>;
+
+/// Connection served by an instance, from the upgrade request until it is closed
+pub(crate) struct WebSocketState {
+    path_with_query: String,
+    headers: HeaderMap,
+    accept: Option<oneshot::Sender<Option<String>>>,
+    upgrade: Option<OnUpgrade>,
+    stream: Option<Stream>,
+    idle: IdleTimer,
+}
+
+impl WebSocketState {
+    pub(crate) fn new(
+        path_with_query: String,
+        headers: HeaderMap,
+        accept: oneshot::Sender<Option<String>>,
+        upgrade: OnUpgrade,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self {
+            path_with_query,
+            headers,
+            accept: Some(accept),
+            upgrade: Some(upgrade),
+            stream: None,
+            idle: IdleTimer {
+                last_activity: Arc::new(Mutex::new(Instant::now())),
+                timeout: idle_timeout,
+            },
+        }
+    }
+
+    /// Timer of the session, used to close it when the guest is stuck
+    pub(crate) fn idle_timer(&self) -> IdleTimer {
+        self.idle.clone()
+    }
+
+    fn stream(&mut self) -> Result<&mut Stream, Error> {
+        match &mut self.stream {
+            Some(stream) => Ok(stream),
+            None if self.upgrade.is_some() => Err(Error::NotAccepted),
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Performs closing handshake if the guest left the connection open
+    pub(crate) async fn close(&mut self) {
+        let Some(mut stream) = self.stream.take() else {
+            return;
+        };
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, stream.close(None)).await;
+    }
+
+    /// Closes the connection telling the peer it was idle for too long
+    pub(crate) async fn close_idle(&mut self) {
+        let Some(mut stream) = self.stream.take() else {
+            return;
+        };
+        let frame = TungsteniteCloseFrame {
+            code: CloseCode::Away,
+            reason: "Idle timeout".into(),
+        };
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, stream.close(Some(frame))).await;
+    }
+}
+
+/// Time of the last frame sent or received, shared with the task serving
+/// the session
+#[derive(Clone)]
+pub(crate) struct IdleTimer {
+    last_activity: Arc<Mutex<Instant>>,
+    timeout: Duration,
+}
+
+impl IdleTimer {
+    fn touch(&self) {
+        *self
+            .last_activity
+            .lock()
+            .expect("Idle timer lock should not be poisoned") = Instant::now();
+    }
+
+    fn deadline(&self) -> Instant {
+        *self
+            .last_activity
+            .lock()
+            .expect("Idle timer lock should not be poisoned")
+            + self.timeout
+    }
+
+    /// Completes once no frame was sent or received for the idle timeout
+    pub(crate) async fn expired(&self) {
+        loop {
+            let deadline = self.deadline();
+            if deadline <= Instant::now() {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+fn convert_tungstenite_error(e: tungstenite::Error) -> Error {
+    match e {
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => Error::Closed,
+        e => Error::Other(format!("WebSocket error: {e}")),
+    }
+}
+
+impl PluginState {
+    fn websocket_session(
+        &mut self,
+        session: &Resource<WebSocketSession>,
+    ) -> Result<&mut WebSocketState, Error> {
+        self.table
+            .get(session)
+            .map_err(|e| Error::Other(format!("Could not get session resource: {e:?}")))?;
+        self.websocket
+            .as_mut()
+            .ok_or_else(|| Error::Other("Instance does not serve a WebSocket session".to_owned()))
+    }
+}
+
+impl websocket::Host for PluginState {}
+
+impl websocket::HostSession for PluginState {
+    async fn path_with_query(&mut self, session: Resource<WebSocketSession>) -> String {
+        self.websocket_session(&session)
+            .map(|s| s.path_with_query.clone())
+            .unwrap_or_default()
+    }
+
+    async fn headers(&mut self, session: Resource<WebSocketSession>) -> Vec<(String, Vec<u8>)> {
+        let Ok(session) = self.websocket_session(&session) else {
+            return Vec::new();
+        };
+        session
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str().to_owned(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    async fn accept(
+        &mut self,
+        session: Resource<WebSocketSession>,
+        protocol: Option<String>,
+    ) -> Result<(), Error> {
+        let session = self.websocket_session(&session)?;
+        let (Some(accept), Some(upgrade)) = (session.accept.take(), session.upgrade.take()) else {
+            return Err(Error::Other("Session is already accepted".to_owned()));
+        };
+
+        accept.send(protocol).map_err(|_| Error::Closed)?;
+        let upgraded = upgrade
+            .await
+            .map_err(|e| Error::Other(format!("Upgrading connection: {e}")))?;
+        session.stream = Some(
+            WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await,
+        );
+        session.idle.touch();
+
+        Ok(())
+    }
+
+    async fn send(
+        &mut self,
+        session: Resource<WebSocketSession>,
+        frame: Frame,
+    ) -> Result<(), Error> {
+        let session = self.websocket_session(&session)?;
+        let message = match frame {
+            Frame::Text(text) => Message::Text(text.into()),
+            Frame::Binary(data) => Message::Binary(data.into()),
+            Frame::Close(CloseFrame { code, reason }) => {
+                Message::Close(Some(TungsteniteCloseFrame {
+                    code: CloseCode::from(code),
+                    reason: reason.into(),
+                }))
+            }
+        };
+
+        session
+            .stream()?
+            .send(message)
+            .await
+            .map_err(convert_tungstenite_error)?;
+        session.idle.touch();
+
+        Ok(())
+    }
+
+    async fn receive(
+        &mut self,
+        session: Resource<WebSocketSession>,
+    ) -> Result<Option<Frame>, Error> {
+        let session = self.websocket_session(&session)?;
+        loop {
+            let deadline = session.idle.deadline();
+            let message = match tokio::time::timeout_at(deadline, session.stream()?.next()).await {
+                Ok(Some(message)) => message.map_err(convert_tungstenite_error)?,
+                Ok(None) => return Ok(None),
+                Err(_) => {
+                    session.close_idle().await;
+                    return Err(Error::Timeout);
+                }
+            };
+            session.idle.touch();
+
+            let frame = match message {
+                Message::Text(text) => Frame::Text(text.as_str().to_owned()),
+                Message::Binary(data) => Frame::Binary(data.to_vec()),
+                Message::Close(frame) => Frame::Close(
+                    frame
+                        .map(|f| CloseFrame {
+                            code: f.code.into(),
+                            reason: f.reason.as_str().to_owned(),
+                        })
+                        .unwrap_or(CloseFrame {
+                            code: CloseCode::Status.into(),
+                            reason: String::new(),
+                        }),
+                ),
+                // Pings are answered by the protocol implementation
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            };
+
+            return Ok(Some(frame));
+        }
+    }
+
+    async fn drop(&mut self, session: Resource<WebSocketSession>) -> wasmtime::Result<()> {
+        self.table.delete(session)?;
+        Ok(())
+    }
+}
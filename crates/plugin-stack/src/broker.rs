@@ -0,0 +1,351 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{Instrument as _, debug, error, info, info_span, warn};
+use wassel_plugin_component::{PluginMeta, PubSubMessage};
+
+use crate::Stack;
+
+/// Parsed `[[subscriptions]]` entry of a loaded plugin
+pub struct Subscriber {
+    plugin_id: String,
+    topic: String,
+    concurrency: Arc<Semaphore>,
+    max_attempts: u32,
+    retry_delay: Duration,
+}
+
+impl Subscriber {
+    pub fn from_meta(meta: &PluginMeta) -> anyhow::Result<Vec<Self>> {
+        meta.subscriptions
+            .iter()
+            .map(|s| {
+                if s.concurrency == 0 {
+                    anyhow::bail!("Concurrency of subscription to `{}` must not be 0", s.topic);
+                }
+                if s.max_attempts == 0 {
+                    anyhow::bail!(
+                        "Max attempts of subscription to `{}` must not be 0",
+                        s.topic
+                    );
+                }
+                Ok(Self {
+                    plugin_id: meta.id.clone(),
+                    topic: s.topic.clone(),
+                    concurrency: Arc::new(Semaphore::new(s.concurrency)),
+                    max_attempts: s.max_attempts,
+                    retry_delay: Duration::from_millis(s.retry_delay_ms),
+                })
+            })
+            .collect()
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+/// Running message broker of a stack
+pub struct BrokerHandle {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<mpsc::Receiver<PubSubMessage>>,
+}
+
+impl BrokerHandle {
+    /// Stops taking messages off the queue and hands the queue back, so a
+    /// reloaded stack can carry on with the messages still waiting in it.
+    /// Deliveries already started finish on their own.
+    pub async fn stop(self) -> Option<mpsc::Receiver<PubSubMessage>> {
+        let _ = self.stop.send(());
+        match self.task.await {
+            Ok(receiver) => Some(receiver),
+            Err(e) => {
+                error!("Message broker failed: {e}");
+                None
+            }
+        }
+    }
+}
+
+impl Stack {
+    /// Spawns the task delivering published messages to subscribed plugins.
+    /// Delivery is at-least-once while the process runs: failed deliveries are
+    /// retried, but messages still queued on shutdown are lost.
+    pub fn spawn_broker(&self) -> Option<BrokerHandle> {
+        let receiver = self
+            .messages
+            .lock()
+            .expect("Broker lock should not be poisoned")
+            .take();
+        let Some(receiver) = receiver else {
+            warn!("Message broker is already running");
+            return None;
+        };
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(run_broker(self.clone(), receiver, stopped));
+        Some(BrokerHandle { stop, task })
+    }
+
+    /// Takes over the queue of a stopped broker, to be read by the broker
+    /// spawned next
+    pub fn adopt_messages(&self, receiver: mpsc::Receiver<PubSubMessage>) {
+        *self
+            .messages
+            .lock()
+            .expect("Broker lock should not be poisoned") = Some(receiver);
+    }
+}
+
+/// Dispatches messages until the queue closes or the broker is stopped, which
+/// also happens when its handle is dropped
+async fn run_broker(
+    stack: Stack,
+    mut receiver: mpsc::Receiver<PubSubMessage>,
+    mut stopped: oneshot::Receiver<()>,
+) -> mpsc::Receiver<PubSubMessage> {
+    info!(
+        "Starting message broker with {} subscriptions",
+        stack.subscribers.values().map(Vec::len).sum::<usize>()
+    );
+
+    loop {
+        let message = tokio::select! {
+            biased;
+            _ = &mut stopped => {
+                info!("Stopped message broker");
+                return receiver;
+            }
+            message = receiver.recv() => message,
+        };
+        let Some(message) = message else {
+            return receiver;
+        };
+
+        let Some(subscribers) = stack.subscribers.get(&message.topic) else {
+            debug!(
+                "No subscribers for message on `{}` from `{}`",
+                message.topic, message.source
+            );
+            continue;
+        };
+
+        for (index, subscriber) in subscribers.iter().enumerate() {
+            // Waiting for a free slot here rather than in the delivery task
+            // keeps messages in the bounded queue, so publishers see backpressure
+            let Ok(permit) = subscriber.concurrency.clone().acquire_owned().await else {
+                continue;
+            };
+            let span = info_span!(
+                "message",
+                topic = %message.topic,
+                source = %message.source,
+                plugin = %subscriber.plugin_id
+            );
+            tokio::spawn(deliver(stack.clone(), message.clone(), index, permit).instrument(span));
+        }
+    }
+}
+
+/// Delivers the message to a subscriber, holding one of its concurrency
+/// slots until done
+async fn deliver(
+    stack: Stack,
+    message: PubSubMessage,
+    index: usize,
+    _permit: OwnedSemaphorePermit,
+) {
+    let subscriber = &stack.subscribers[&message.topic][index];
+
+    let Some(image) = stack.map.get(&subscriber.plugin_id) else {
+        error!("Plugin `{}` is not loaded", subscriber.plugin_id);
+        return;
+    };
+    if !image.is_enabled() {
+        warn!("Dropping message for disabled plugin");
+        return;
+    }
+
+    let mut delay = subscriber.retry_delay;
+    for attempt in 1..=subscriber.max_attempts {
+        let result = match image.instantiate(&stack.engine).await {
+            Ok(instance) => instance
+                .handle_message(&message.topic, &message.payload)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e.context("Instantiating plugin")),
+        };
+
+        match result {
+            Ok(()) => {
+                debug!("Message delivered on attempt {attempt}");
+                return;
+            }
+            Err(e) if attempt < subscriber.max_attempts => {
+                warn!("Delivery attempt {attempt} failed, retrying in {delay:?}: {e:#}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => error!("Dropping message after {attempt} failed attempts: {e:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::config::StackOptions;
+
+    /// Component whose message handler calls the core function `export`,
+    /// either `ok` or `fail`
+    fn handler_component(export: &str) -> String {
+        format!(
+            r#"(component
+                (core module $m
+                    (memory (export "memory") 1)
+                    (global $next (mut i32) (i32.const 1024))
+                    (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+                        (local $ptr i32)
+                        (local.set $ptr (global.get $next))
+                        (global.set $next (i32.add (global.get $next) (local.get 3)))
+                        (local.get $ptr))
+                    (data (i32.const 0) "\00\00\00\00")
+                    (data (i32.const 16) "\01\00\00\00\20\00\00\00\06\00\00\00")
+                    (data (i32.const 32) "failed")
+                    (func (export "ok") (param i32 i32 i32 i32) (result i32) (i32.const 0))
+                    (func (export "fail") (param i32 i32 i32 i32) (result i32) (i32.const 16)))
+                (core instance $i (instantiate $m))
+                (func $handle
+                    (param "topic" string) (param "payload" (list u8))
+                    (result (result (error string)))
+                    (canon lift (core func $i "{export}")
+                        (memory $i "memory") (realloc (func $i "realloc"))))
+                (instance $handler (export "handle-message" (func $handle)))
+                (export "wassel:foundation/message-handler" (instance $handler)))"#
+        )
+    }
+
+    fn write_plugin(base: &Path, id: &str, export: &str) {
+        let dir = base.join("plugins").join(id);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("plugin.toml"),
+            format!(
+                r#"
+                id = "{id}"
+
+                [[subscriptions]]
+                topic = "jobs"
+                max_attempts = 3
+                retry_delay_ms = 1
+                "#
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("plugin.wasm"), handler_component(export)).unwrap();
+    }
+
+    /// Number of times the plugin was instantiated, once per delivery attempt
+    fn attempts(stack: &Stack, id: &str) -> u64 {
+        let label = format!("plugin_id=\"{id}\"");
+        stack.map[id]
+            .metrics()
+            .registry()
+            .render()
+            .lines()
+            .find(|l| {
+                l.starts_with("wassel_instantiation_duration_seconds_count") && l.contains(&label)
+            })
+            .and_then(|l| l.rsplit(' ').next()?.parse().ok())
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        write_plugin(dir.path(), "accepting", "ok");
+        write_plugin(dir.path(), "failing", "fail");
+        let options = StackOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let stack = Stack::load(dir.path(), options).await.unwrap();
+
+        let (sender, receiver) = mpsc::channel(1);
+        let (_stop, stopped) = oneshot::channel();
+        let broker = tokio::spawn(run_broker(stack.clone(), receiver, stopped));
+        sender
+            .send(PubSubMessage {
+                topic: "jobs".to_owned(),
+                payload: Bytes::from_static(b"{}"),
+                source: Arc::from("test"),
+            })
+            .await
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while attempts(&stack, "failing") < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Failing delivery should be attempted three times");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(attempts(&stack, "failing"), 3);
+        assert_eq!(attempts(&stack, "accepting"), 1);
+        broker.abort();
+    }
+
+    #[tokio::test]
+    async fn queued_messages_survive_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        write_plugin(dir.path(), "accepting", "ok");
+        let options = StackOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let stack = Stack::load(dir.path(), options).await.unwrap();
+        let messages = stack.spawn_broker().unwrap().stop().await.unwrap();
+
+        stack
+            .publisher
+            .for_plugin("test")
+            .publish("jobs".to_owned(), Bytes::from_static(b"{}"))
+            .await
+            .unwrap();
+        let reloaded = stack.reload().await.unwrap();
+        reloaded.adopt_messages(messages);
+        let broker = reloaded.spawn_broker().unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while attempts(&reloaded, "accepting") < 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Queued message should be delivered by the new broker");
+        drop(broker);
+    }
+
+    #[test]
+    fn zero_concurrency_is_rejected() {
+        let meta: PluginMeta = toml::from_str(
+            r#"
+            id = "test"
+
+            [[subscriptions]]
+            topic = "jobs"
+            concurrency = 0
+            "#,
+        )
+        .unwrap();
+        assert!(Subscriber::from_meta(&meta).is_err());
+    }
+}
@@ -2,11 +2,15 @@ use anyhow::{Context, bail};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
+    net::IpAddr,
     path::{Path, PathBuf},
 };
 use tokio::fs;
 use tracing::{debug, error};
-use wassel_plugin_component::PluginMeta;
+use wassel_plugin_component::{
+    BlobStoreSettings, CoreDumpSettings, HttpCacheSettings, KeyValueSettings, OutboundMode,
+    PluginMeta, ProfilingSettings, SecretsSettings, SqlSettings,
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct StackConfig {
@@ -15,10 +19,64 @@ pub struct StackConfig {
     pub plugins: HashMap<String, PluginMeta>,
 }
 
+/// Options for loading a stack that come from the command line rather than
+/// from `wassel.toml`
+#[derive(Debug, Clone, Default)]
+pub struct StackOptions {
+    pub outbound: OutboundMode,
+
+    /// Fail loading when any plugin fails, instead of serving the others
+    pub strict: bool,
+
+    /// Replaces `[profiling]` of `wassel.toml`, e.g. to profile a single request
+    pub profiling: Option<ProfilingSettings>,
+
+    /// Answer failed plugin calls with error pages showing the trap, wasm
+    /// backtrace and guest stderr. Only meant for local development.
+    pub dev_mode: bool,
+
+    /// Value the profiling header has to carry for a request to be profiled,
+    /// usually the admin token. Not needed in dev mode.
+    pub profile_token: Option<String>,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct StackMeta {
     #[serde(default = "HashMap::default")]
     pub variables: HashMap<String, String>,
+
+    #[serde(default = "HttpCacheSettings::default")]
+    pub http_cache: HttpCacheSettings,
+
+    #[serde(default = "KeyValueSettings::default")]
+    pub keyvalue: KeyValueSettings,
+
+    #[serde(default = "SecretsSettings::default")]
+    pub secrets: SecretsSettings,
+
+    #[serde(default = "BlobStoreSettings::default")]
+    pub blobstore: BlobStoreSettings,
+
+    #[serde(default = "SqlSettings::default")]
+    pub sql: SqlSettings,
+
+    #[serde(default = "RequestIdSettings::default")]
+    pub request_id: RequestIdSettings,
+
+    #[serde(default = "ProfilingSettings::default")]
+    pub profiling: ProfilingSettings,
+
+    #[serde(default = "CoreDumpSettings::default")]
+    pub coredump: CoreDumpSettings,
+}
+
+/// `[request_id]` section of `wassel.toml`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestIdSettings {
+    /// Clients whose `X-Request-Id` is kept, usually reverse proxies.
+    /// Requests from anyone else get a newly generated id.
+    #[serde(default = "Vec::default")]
+    pub trusted_sources: Vec<IpAddr>,
 }
 
 impl StackConfig {
@@ -86,6 +144,14 @@ async fn read_plugin_entry(
     dir: &fs::DirEntry,
 ) -> Result<(), anyhow::Error> {
     let plugin_meta_path = dir.path().join("plugin.toml");
+    // Directory of an uninstalled plugin, only holding its data
+    if !matches!(fs::try_exists(&plugin_meta_path).await, Ok(true)) {
+        debug!(
+            "Skipping `{}` without plugin.toml",
+            dir.path().to_string_lossy()
+        );
+        return Ok(());
+    }
     let plugin_meta: PluginMeta =
         toml::from_slice(&fs::read(&plugin_meta_path).await.context(format!(
             "Reading plugin meta at `{}`",
@@ -0,0 +1,107 @@
+use bytes::Bytes;
+use http_body_util::{BodyExt as _, Full};
+use hyper::{
+    HeaderMap, StatusCode,
+    header::{self, HeaderValue},
+};
+use serde::Serialize;
+use wasmtime::WasmBacktrace;
+use wassel_plugin_component::PluginHandleError;
+
+use crate::response::Response;
+
+/// Details of a failed plugin call shown in dev mode
+#[derive(Debug, Serialize)]
+pub(crate) struct DebugError {
+    plugin_id: String,
+    message: String,
+    /// Symbolicated wasm backtrace, present when the guest trapped
+    backtrace: Option<String>,
+    stderr: Option<String>,
+}
+
+impl DebugError {
+    pub(crate) fn new(plugin_id: &str, error: &PluginHandleError, stderr: Option<String>) -> Self {
+        let (message, backtrace) = match error {
+            PluginHandleError::CallingHandleMethod(e) | PluginHandleError::Guest(e) => (
+                e.root_cause().to_string(),
+                e.downcast_ref::<WasmBacktrace>().map(ToString::to_string),
+            ),
+            e => (e.to_string(), None),
+        };
+        Self {
+            plugin_id: plugin_id.to_owned(),
+            message,
+            backtrace,
+            stderr: stderr.filter(|s| !s.is_empty()),
+        }
+    }
+
+    /// Renders HTML for browsers and JSON for everyone else
+    pub(crate) fn into_response(self, request_headers: &HeaderMap) -> Response {
+        let wants_html = request_headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("text/html"));
+        let (content_type, body) = if wants_html {
+            ("text/html; charset=utf-8", self.html())
+        } else {
+            (
+                "application/json",
+                serde_json::to_string_pretty(&self).expect("Debug error should serialize"),
+            )
+        };
+
+        let mut response = Response::new(
+            Full::new(Bytes::from(body))
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        );
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        response
+    }
+
+    fn html(&self) -> String {
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Plugin `{id}` failed</title>\n\
+             <style>body {{ font-family: sans-serif; margin: 2em; }} \
+             pre {{ background: #f4f4f4; padding: 1em; overflow-x: auto; }}</style>\n\
+             </head>\n<body>\n<h1>Plugin <code>{id}</code> failed</h1>\n<pre>{message}</pre>\n",
+            id = escape_html(&self.plugin_id),
+            message = escape_html(&self.message),
+        );
+        if let Some(backtrace) = &self.backtrace {
+            html += &format!(
+                "<h2>Wasm backtrace</h2>\n<pre>{}</pre>\n",
+                escape_html(backtrace)
+            );
+        }
+        if let Some(stderr) = &self.stderr {
+            html += &format!(
+                "<h2>Guest stderr</h2>\n<pre>{}</pre>\n",
+                escape_html(stderr)
+            );
+        }
+        html += "<p>Shown because the server runs in dev mode.</p>\n</body>\n</html>\n";
+        html
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,3 +1,5 @@
+use std::{collections::BTreeMap, fmt};
+
 use hyper::StatusCode;
 use wassel_plugin_component::PluginHandleError;
 
@@ -9,6 +11,25 @@ pub enum ServeError {
     PluginError(#[from] PluginHandleError),
 }
 
+/// Plugins which failed to load while loading a stack in strict mode
+#[derive(Debug)]
+pub struct LoadError {
+    /// Ids of the failed plugins and the reason
+    pub failures: BTreeMap<String, String>,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} plugins failed to load:", self.failures.len())?;
+        for (id, reason) in &self.failures {
+            write!(f, "\n  - `{id}`: {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LoadError {}
+
 impl IntoResponse for ServeError {
     fn into_response(self) -> super::response::Response {
         StatusCode::INTERNAL_SERVER_ERROR.into_response()
@@ -1,9 +1,21 @@
 mod body;
+mod broker;
 mod config;
+mod debug_page;
 mod errors;
 mod response;
+mod scheduler;
 mod service;
 mod stack;
+mod websocket;
 
-pub use config::StackConfig;
-pub use stack::Stack;
+pub use broker::BrokerHandle;
+pub use config::{RequestIdSettings, StackConfig, StackOptions};
+pub use errors::LoadError;
+pub use service::{ClientAddr, RoutedPlugin};
+pub use stack::{Stack, StackCheck};
+pub use wassel_plugin_component::{
+    CoreDumpInfo, CoreDumpSettings, CoreDumps, GuestProfiling, MetricsRegistry, OutboundMode,
+    PluginImage, PluginStats, ProfilingSettings, REQUEST_ID_HEADER, Secret, SecretsFile,
+    SecretsSettings, constant_time_eq,
+};
@@ -0,0 +1,143 @@
+use std::{
+    str::FromStr as _,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use chrono::Utc;
+use cron::Schedule;
+use tokio::task::JoinHandle;
+use tracing::{Instrument as _, error, info, info_span, warn};
+use wassel_plugin_component::PluginMeta;
+
+use crate::Stack;
+
+/// Parsed `[[schedules]]` entry of a loaded plugin
+pub struct PluginSchedule {
+    plugin_id: String,
+    name: String,
+    schedule: Schedule,
+    jitter: Duration,
+    running: Arc<AtomicBool>,
+}
+
+impl PluginSchedule {
+    pub fn from_meta(meta: &PluginMeta) -> anyhow::Result<Vec<Self>> {
+        meta.schedules
+            .iter()
+            .map(|s| {
+                let schedule = parse_cron(&s.cron)
+                    .context(format!("Parsing cron expression of schedule `{}`", s.name))?;
+                Ok(Self {
+                    plugin_id: meta.id.clone(),
+                    name: s.name.clone(),
+                    schedule,
+                    jitter: Duration::from_secs(s.jitter_secs),
+                    running: Arc::new(AtomicBool::new(false)),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Stack {
+    /// Spawns a task for every plugin schedule which runs its scheduled
+    /// handler until the runtime shuts down
+    pub fn spawn_schedules(&self) -> Vec<JoinHandle<()>> {
+        (0..self.schedules.len())
+            .map(|index| tokio::spawn(run_schedule(self.clone(), index)))
+            .collect()
+    }
+}
+
+async fn run_schedule(stack: Stack, index: usize) {
+    let schedule = &stack.schedules[index];
+    info!(
+        "Scheduling `{}` of plugin `{}` at `{}`",
+        schedule.name, schedule.plugin_id, schedule.schedule
+    );
+
+    loop {
+        let Some(next) = schedule.schedule.upcoming(Utc).next() else {
+            info!(
+                "Schedule `{}` of plugin `{}` has no more runs",
+                schedule.name, schedule.plugin_id
+            );
+            return;
+        };
+
+        let delay = (next - Utc::now()).to_std().unwrap_or_default() + jitter(schedule.jitter);
+        tokio::time::sleep(delay).await;
+
+        if schedule.running.swap(true, Ordering::AcqRel) {
+            warn!(
+                "Skipping run of schedule `{}` of plugin `{}`: previous run is still in progress",
+                schedule.name, schedule.plugin_id
+            );
+            continue;
+        }
+
+        let span = info_span!("schedule", plugin = %schedule.plugin_id, name = %schedule.name);
+        tokio::spawn(run_once(stack.clone(), index).instrument(span));
+    }
+}
+
+async fn run_once(stack: Stack, index: usize) {
+    let schedule = &stack.schedules[index];
+    let _running = RunningGuard(&schedule.running);
+
+    let Some(image) = stack.map.get(&schedule.plugin_id) else {
+        error!("Plugin `{}` is not loaded", schedule.plugin_id);
+        return;
+    };
+    if !image.is_enabled() {
+        info!("Skipping scheduled run of disabled plugin");
+        return;
+    }
+
+    let start = Instant::now();
+    let result = match image.instantiate(&stack.engine).await {
+        Ok(instance) => instance
+            .handle_scheduled(&schedule.name)
+            .await
+            .map_err(anyhow::Error::from),
+        Err(e) => Err(e.context("Instantiating plugin")),
+    };
+    let elapsed = start.elapsed();
+
+    match result {
+        Ok(()) => info!("Scheduled run succeeded in {elapsed:?}"),
+        Err(e) => error!("Scheduled run failed after {elapsed:?}: {e:#}"),
+    }
+}
+
+/// Marks schedule as not running when the run ends, even if it panics
+struct RunningGuard<'a>(&'a AtomicBool);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Accepts standard five-field expressions in addition to the ones with
+/// leading seconds that the `cron` crate expects
+fn parse_cron(expression: &str) -> Result<Schedule, cron::error::Error> {
+    let expression = expression.trim();
+    if expression.split_whitespace().count() == 5 {
+        Schedule::from_str(&format!("0 {expression}"))
+    } else {
+        Schedule::from_str(expression)
+    }
+}
+
+fn jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(fastrand::u64(..=max.as_millis() as u64))
+}
@@ -1,40 +1,218 @@
-use std::pin::Pin;
+use std::{net::SocketAddr, pin::Pin, time::Instant};
 
-use hyper::{Request, StatusCode, body::Incoming, service::Service};
-use tracing::{error, trace};
+use hyper::{Request, StatusCode, body::Incoming, header::HeaderValue, service::Service};
+use tracing::{Instrument as _, debug, error, info, info_span, trace};
+use wassel_plugin_component::{
+    PluginHandleError, PluginImage, REQUEST_ID_HEADER, generate_request_id, is_upgrade_request,
+    read_request_id, set_remote_parent,
+};
 
 use crate::Stack;
 
 use crate::{
+    debug_page::DebugError,
     errors::ServeError,
     response::{self, IntoResponse},
 };
 
+/// Response extension naming the plugin which handled the request
+#[derive(Debug, Clone)]
+pub struct RoutedPlugin(pub String);
+
+/// Request extension with the address of the client, set by the server.
+/// Needed to accept request ids from trusted sources.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub SocketAddr);
+
 impl Service<Request<Incoming>> for Stack {
     type Response = response::Response;
     type Error = ServeError;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, req: Request<Incoming>) -> Self::Future {
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
         let s = self.clone();
+        let request_id = self.resolve_request_id(&req);
+        let span = info_span!(
+            "request",
+            otel.kind = "server",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.uri().path(),
+        );
+        set_remote_parent(&span, req.headers());
+
+        let request_id =
+            HeaderValue::from_str(&request_id).expect("Request id should be a valid header value");
+        req.headers_mut()
+            .insert(REQUEST_ID_HEADER, request_id.clone());
 
         let future = async move {
-            let plugin = match s.get_plugin(req.uri().path()).await {
-                Ok(Some(p)) => p,
-                Ok(None) => {
-                    trace!("No plugin found for {}", req.uri().path());
-                    return Ok(StatusCode::NOT_FOUND.into_response());
-                }
-                Err(e) => {
-                    error!("Could not get plugin for {}: {:#}", req.uri().path(), e);
-                    return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
-                }
-            };
+            let mut result = s.route(req).await;
+            if let Ok(response) = &mut result {
+                response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+            }
+            result
+        };
 
-            let result = plugin.handle(req).await.map_err(ServeError::PluginError);
-            Ok(result?.into_response())
+        Box::pin(future.instrument(span))
+    }
+}
+
+impl Stack {
+    /// Keeps the request id sent by a trusted client, otherwise generates one
+    fn resolve_request_id<B>(&self, req: &Request<B>) -> String {
+        let trusted = req.extensions().get::<ClientAddr>().is_some_and(|addr| {
+            self.request_id
+                .trusted_sources
+                .contains(&addr.0.ip().to_canonical())
+        });
+        trusted
+            .then(|| read_request_id(req.headers()))
+            .flatten()
+            .map(str::to_owned)
+            .unwrap_or_else(generate_request_id)
+    }
+
+    async fn route(&self, req: Request<Incoming>) -> Result<response::Response, ServeError> {
+        let image = match info_span!("route").in_scope(|| self.get_image(req.uri().path())) {
+            Ok(Some(i)) => i,
+            Ok(None) => {
+                trace!("No plugin found for {}", req.uri().path());
+                return Ok(StatusCode::NOT_FOUND.into_response());
+            }
+            Err(e) => {
+                error!("Could not get plugin for {}: {:#}", req.uri().path(), e);
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        };
+        if !image.is_enabled() {
+            trace!("Plugin {} is disabled", image.id());
+            return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response());
+        }
+
+        let started = Instant::now();
+        let mut result = self.serve_plugin(image, req).await;
+        let status = match &result {
+            Ok(response) => response.status(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
+        image.metrics().record_request(status, started.elapsed());
+        if let Ok(response) = &mut result {
+            response
+                .extensions_mut()
+                .insert(RoutedPlugin(image.id().to_owned()));
+        }
+
+        result
+    }
+
+    async fn serve_plugin(
+        &self,
+        image: &PluginImage,
+        req: Request<Incoming>,
+    ) -> Result<response::Response, ServeError> {
+        if image.has_websocket_handler() && is_upgrade_request(&req) {
+            return Ok(self.handle_websocket(image, req).await);
+        }
+        if !image.has_http_handler() {
+            trace!("Plugin {} does not handle HTTP requests", image.id());
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        }
+
+        let profiled = self.profiling.should_profile(req.headers());
+        let instance = if profiled {
+            image
+                .instantiate_profiled(&self.engine, self.profiling.interval())
+                .await
+        } else {
+            image.instantiate(&self.engine).await
+        };
+        let plugin = match instance {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Could not instantiate plugin {}: {:#}", image.id(), e);
+                if self.options.dev_mode {
+                    let error = PluginHandleError::Instantiate(e);
+                    return Ok(
+                        DebugError::new(image.id(), &error, None).into_response(req.headers())
+                    );
+                }
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        };
+        debug!(
+            "Instantiated plugin {} to handle {}",
+            image.id(),
+            req.uri().path()
+        );
+
+        let request_id = read_request_id(req.headers())
+            .unwrap_or_default()
+            .to_owned();
+        let request_headers = self.options.dev_mode.then(|| req.headers().clone());
+        let result = plugin.handle(req).await;
+        if profiled && let Some(profiler) = plugin.take_profile().await {
+            let profiling = self.profiling.clone();
+            let plugin_id = image.id().to_owned();
+            let saved = tokio::task::spawn_blocking(move || {
+                profiling.save(&plugin_id, &request_id, profiler)
+            })
+            .await;
+            match saved {
+                Ok(Ok(path)) => info!("Saved profile to `{}`", path.to_string_lossy()),
+                Ok(Err(e)) => error!("Could not save profile of {}: {e:#}", image.id()),
+                Err(e) => error!("Saving profile of {} failed: {e}", image.id()),
+            }
+        }
+
+        match (result, request_headers) {
+            (Ok(response), _) => Ok(response.into_response()),
+            (Err(e), Some(headers)) => {
+                error!("Plugin {} failed: {e}", image.id());
+                let stderr = plugin.captured_stderr().await;
+                Ok(DebugError::new(image.id(), &e, stderr).into_response(&headers))
+            }
+            (Err(e), None) => Err(ServeError::PluginError(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(client: &str, request_id: &str) -> Request<()> {
+        let mut req = Request::builder()
+            .header(REQUEST_ID_HEADER, request_id)
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(ClientAddr(client.parse().unwrap()));
+        req
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_only_kept_from_trusted_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("wassel.toml"),
+            "[request_id]\ntrusted_sources = [\"10.0.0.1\"]\n",
+        )
+        .unwrap();
+        let stack = Stack::load(dir.path(), Default::default()).await.unwrap();
+
+        let kept = stack.resolve_request_id(&request("10.0.0.1:4000", "upstream-1"));
+        assert_eq!(kept, "upstream-1");
+
+        let untrusted = stack.resolve_request_id(&request("10.0.0.2:4000", "upstream-1"));
+        assert_ne!(untrusted, "upstream-1");
+        assert!(uuid_like(&untrusted));
+
+        let malformed = stack.resolve_request_id(&request("10.0.0.1:4000", "has space"));
+        assert!(uuid_like(&malformed));
+    }
 
-        Box::pin(future)
+    fn uuid_like(id: &str) -> bool {
+        id.len() == 36 && id.bytes().filter(|b| *b == b'-').count() == 4
     }
 }
@@ -1,12 +1,27 @@
-use std::{collections::HashMap, ops::Deref, path::Path, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Context;
-use tokio::fs;
+use tokio::{fs, sync::mpsc};
 use tracing::{debug, error, info, trace};
-use wasmtime::Engine;
-use wassel_plugin_component::{PluginImage, PluginInstance};
+use wasmtime::{Engine, WasmBacktraceDetails};
+use wassel_plugin_component::{
+    BlobContainers, BlobStore, BlobStoreSettings, CoreDumps, GuestProfiling, KeyValue,
+    KeyValueSettings, MemoryBuckets, MetricsRegistry, OutboundHttp, PluginImage, PluginInstance,
+    PluginMeta, PluginMetrics, PluginSecrets, PluginServices, PubSubMessage, Publisher,
+    SecretsFile, SqlConnectionLimits, SqlDatabase, SqlSettings,
+};
 
-use crate::config::StackConfig;
+use crate::{
+    broker::Subscriber,
+    config::{RequestIdSettings, StackConfig, StackOptions},
+    errors::LoadError,
+    scheduler::PluginSchedule,
+};
 
 #[derive(Clone)]
 pub struct Stack(Arc<StackInner>);
@@ -20,30 +35,201 @@ impl Deref for Stack {
 }
 
 impl Stack {
-    pub async fn load(base_path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        Ok(Self(Arc::new(StackInner::load(base_path).await?)))
+    pub async fn load(base_path: impl AsRef<Path>, options: StackOptions) -> anyhow::Result<Self> {
+        Ok(Self(Arc::new(StackInner::load(base_path, options).await?)))
+    }
+
+    /// Loads the stack recording its metrics in an existing registry
+    pub async fn load_with_metrics(
+        base_path: impl AsRef<Path>,
+        options: StackOptions,
+        metrics: Arc<MetricsRegistry>,
+    ) -> anyhow::Result<Self> {
+        let state = CarriedState {
+            metrics,
+            ..Default::default()
+        };
+        Ok(Self(Arc::new(
+            StackInner::load_with_state(base_path, options, state).await?,
+        )))
+    }
+
+    /// Validates the stack the way loading would, without preparing host
+    /// services: no data directories, databases or migrations are touched.
+    /// Every plugin is checked, so all of the failures are reported at once.
+    pub async fn check(base_path: impl AsRef<Path>) -> anyhow::Result<StackCheck> {
+        let base_path = base_path.as_ref();
+        let config = StackConfig::load(base_path).await.context(format!(
+            "Loading config in `{}`",
+            base_path.to_string_lossy()
+        ))?;
+
+        let mut secrets_settings = config.meta.secrets.clone();
+        secrets_settings.file = base_path.join(&secrets_settings.file);
+        let secrets = SecretsFile::open(&secrets_settings).context("Opening secrets file")?;
+
+        let engine = {
+            let mut config = wasmtime::Config::new();
+            config.async_support(true);
+            Engine::new(&config).context("Creating Engine")?
+        };
+
+        let mut check = StackCheck::default();
+        let mut router = matchit::Router::new();
+        for (plugin_id, meta) in config.plugins {
+            let plugin_path = &config.plugin_paths[&plugin_id];
+            let result = async {
+                let wasm_path = plugin_path.join("plugin.wasm");
+                let bytes = fs::read(&wasm_path)
+                    .await
+                    .context(format!("Reading `{}`", wasm_path.to_string_lossy()))?;
+                let exports = PluginImage::check(&engine, &bytes, &meta)?;
+                PluginSecrets::resolve(&meta.secrets, base_path, &secrets)
+                    .context("Resolving secrets")?;
+                PluginSchedule::from_meta(&meta).context("Loading schedules")?;
+                Subscriber::from_meta(&meta).context("Loading subscriptions")?;
+                if exports.serves_endpoint() {
+                    let base_url = add_route(&mut router, &meta.endpoint, &meta.id)?;
+                    check.routes.push((base_url, meta.id.clone()));
+                }
+                anyhow::Ok(())
+            }
+            .await;
+
+            match result {
+                Ok(()) => check.plugins.push(plugin_id),
+                Err(e) => {
+                    check.failures.insert(plugin_id, format!("{e:#}"));
+                }
+            }
+        }
+        Ok(check)
     }
 
     pub async fn get_plugin(&self, route: &str) -> Result<Option<PluginInstance>, anyhow::Error> {
-        let name = self.router.at(route).map(|m| m.value.as_str())?;
-        let Some(image) = self.map.get(name) else {
+        let Some(image) = self.get_image(route)? else {
             return Ok(None);
         };
-        trace!("Found plugin image for {route}");
         let plugin = image.instantiate(&self.0.engine).await?;
         debug!("Instantiated plugin {} to handle {}", image.id(), route);
         Ok(Some(plugin))
     }
+
+    /// Loads the stack again from disk. Metrics, the message queue and
+    /// in-memory key-value buckets carry on and plugins which are disabled
+    /// stay disabled. A running broker has to be stopped and its queue
+    /// handed to the new stack with [`Stack::adopt_messages`].
+    pub async fn reload(&self) -> anyhow::Result<Self> {
+        self.reload_with(None).await
+    }
+
+    /// Like [`Stack::reload`], but only the given plugins are loaded again.
+    /// All others are kept as they are, along with the engine, so changes to
+    /// engine settings in `wassel.toml` need a full reload.
+    pub async fn reload_plugins(&self, plugin_ids: &[String]) -> anyhow::Result<Self> {
+        let images = self
+            .map
+            .iter()
+            .filter(|(id, _)| !plugin_ids.contains(id))
+            .map(|(id, image)| (id.clone(), image.clone()))
+            .collect();
+        let kept = KeptPlugins {
+            engine: self.engine.clone(),
+            profiling: self.profiling.clone(),
+            images,
+        };
+        self.reload_with(Some(kept)).await
+    }
+
+    async fn reload_with(&self, kept: Option<KeptPlugins>) -> anyhow::Result<Self> {
+        let state = CarriedState {
+            metrics: self.metrics.clone(),
+            publisher: Some(self.publisher.clone()),
+            memory_keyvalue: self.memory_keyvalue.clone(),
+            sql_connections: self.sql_connections.clone(),
+            blob_containers: self.blob_containers.clone(),
+            kept,
+        };
+        let inner =
+            StackInner::load_with_state(&self.base_path, self.options.clone(), state).await?;
+        let messages = self
+            .messages
+            .lock()
+            .expect("Broker lock should not be poisoned")
+            .take();
+        *inner
+            .messages
+            .lock()
+            .expect("Broker lock should not be poisoned") = messages;
+        for image in inner.map.values() {
+            if self
+                .map
+                .get(image.id())
+                .is_some_and(|old| !old.is_enabled())
+            {
+                image.set_enabled(false);
+            }
+        }
+        Ok(Self(Arc::new(inner)))
+    }
+
+    pub fn get_image(&self, route: &str) -> Result<Option<&PluginImage>, anyhow::Error> {
+        let name = self.router.at(route).map(|m| m.value.as_str())?;
+        let image = self.map.get(name).map(|image| &**image);
+        if image.is_some() {
+            trace!("Found plugin image for {route}");
+        }
+        Ok(image)
+    }
 }
 
 pub struct StackInner {
-    map: HashMap<String, PluginImage>,
-    engine: Engine,
+    pub(crate) map: HashMap<String, Arc<PluginImage>>,
+    pub(crate) engine: Engine,
     router: matchit::Router<String>,
+    pub(crate) schedules: Vec<PluginSchedule>,
+    /// Subscribers of every topic
+    pub(crate) subscribers: HashMap<String, Vec<Subscriber>>,
+    /// Published messages, taken by the broker task once it is spawned
+    pub(crate) messages: Mutex<Option<mpsc::Receiver<PubSubMessage>>>,
+    /// Sending side of the message queue, reused by reloaded stacks
+    pub(crate) publisher: Publisher,
+    /// Buckets of the memory key-value backend, reused by reloaded stacks
+    memory_keyvalue: MemoryBuckets,
+    /// SQL connection limits, shared with reloaded stacks
+    sql_connections: SqlConnectionLimits,
+    /// Opened blob containers, shared with reloaded stacks
+    blob_containers: BlobContainers,
+    /// Host and plugin metrics served on the metrics endpoint
+    metrics: Arc<MetricsRegistry>,
+    pub(crate) request_id: RequestIdSettings,
+    pub(crate) profiling: Arc<GuestProfiling>,
+    /// Registered endpoints and the ids of the plugins serving them
+    routes: Vec<(String, String)>,
+    /// Plugins which failed to load and the reason
+    failures: BTreeMap<String, String>,
+    base_path: PathBuf,
+    pub(crate) options: StackOptions,
 }
 
 impl StackInner {
-    pub async fn load(base_path: impl AsRef<Path>) -> anyhow::Result<Self> {
+    pub async fn load(base_path: impl AsRef<Path>, options: StackOptions) -> anyhow::Result<Self> {
+        Self::load_with_state(base_path, options, CarriedState::default()).await
+    }
+
+    async fn load_with_state(
+        base_path: impl AsRef<Path>,
+        options: StackOptions,
+        state: CarriedState,
+    ) -> anyhow::Result<Self> {
+        let CarriedState {
+            metrics,
+            publisher,
+            memory_keyvalue,
+            sql_connections,
+            blob_containers,
+            kept,
+        } = state;
         let config = StackConfig::load(&base_path).await.context(format!(
             "Loading config in `{}`",
             base_path.as_ref().to_string_lossy()
@@ -51,73 +237,456 @@ impl StackInner {
 
         info!("Loading plugins");
         let mut successes = 0;
-        let mut errors = 0;
+        let mut failures = BTreeMap::new();
 
-        let engine = {
-            let mut config = wasmtime::Config::new();
-            config.async_support(true);
-            Engine::new(&config).context("Creating Engine")?
+        let mut coredump = config.meta.coredump.clone();
+        coredump.directory = base_path.as_ref().join(&coredump.directory);
+        let coredumps = Arc::new(CoreDumps::new(coredump));
+
+        // Kept plugins only run on the engine they were compiled for
+        let (engine, profiling, mut images) = match kept {
+            Some(kept) => (kept.engine, kept.profiling, kept.images),
+            None => {
+                let mut profiling = options
+                    .profiling
+                    .clone()
+                    .unwrap_or_else(|| config.meta.profiling.clone());
+                profiling.directory = base_path.as_ref().join(&profiling.directory);
+                let profiling = Arc::new(GuestProfiling::new(
+                    profiling,
+                    options.dev_mode,
+                    options.profile_token.clone(),
+                ));
+
+                let engine = {
+                    let mut config = wasmtime::Config::new();
+                    config.async_support(true);
+                    config.epoch_interruption(profiling.is_enabled());
+                    config.coredump_on_trap(coredumps.is_enabled());
+                    if options.dev_mode {
+                        config.wasm_backtrace(true);
+                        config.wasm_backtrace_details(WasmBacktraceDetails::Enable);
+                        config.debug_info(true);
+                    }
+                    Engine::new(&config).context("Creating Engine")?
+                };
+                if profiling.is_enabled() {
+                    profiling.start_ticker(&engine)?;
+                    info!("Guest profiling is enabled");
+                }
+                (engine, profiling, HashMap::new())
+            }
+        };
+
+        let mut cache_settings = config.meta.http_cache.clone();
+        cache_settings.path = base_path.as_ref().join(&cache_settings.path);
+        let outbound = OutboundHttp::new(options.outbound.clone(), cache_settings)
+            .context("Creating outbound HTTP client")?;
+
+        let mut keyvalue = config.meta.keyvalue.clone();
+        keyvalue.path = base_path.as_ref().join(&keyvalue.path);
+
+        let (publisher, messages) = match publisher {
+            Some(publisher) => (publisher, None),
+            None => {
+                let (publisher, messages) = Publisher::channel();
+                (publisher, Some(messages))
+            }
+        };
+
+        let mut blobstore = config.meta.blobstore.clone();
+        blobstore.path = base_path.as_ref().join(&blobstore.path);
+
+        let mut sql = config.meta.sql.clone();
+        sql.path = base_path.as_ref().join(&sql.path);
+
+        let mut secrets_settings = config.meta.secrets.clone();
+        secrets_settings.file = base_path.as_ref().join(&secrets_settings.file);
+        let secrets = SecretsFile::open(&secrets_settings).context("Opening secrets file")?;
+
+        let shared = SharedServices {
+            base_path: base_path.as_ref().to_owned(),
+            outbound,
+            keyvalue,
+            memory_keyvalue: memory_keyvalue.clone(),
+            blobstore,
+            blob_containers: blob_containers.clone(),
+            sql,
+            sql_connections: sql_connections.clone(),
+            publisher: publisher.clone(),
+            secrets,
+            metrics: metrics.clone(),
+            capture_stderr: options.dev_mode,
+            coredumps,
         };
 
         let mut map = HashMap::new();
         let mut router = matchit::Router::new();
+        let mut routes = Vec::new();
+        let mut schedules = Vec::new();
+        let mut subscribers: HashMap<String, Vec<Subscriber>> = HashMap::new();
 
         for (plugin_id, plugin_meta) in config.plugins {
             let plugin_path = &config.plugin_paths[&plugin_id];
             debug!("Loading `{}`", plugin_path.to_string_lossy());
 
-            let mut base_url = plugin_meta.endpoint.clone();
-            if !base_url.ends_with('/') {
-                base_url += "/";
-            }
-            let base_url_catchall = base_url.clone() + "{*path}";
-
-            let wasm_path = plugin_path.join("plugin.wasm");
-            let bytes = fs::read(&wasm_path)
-                .await
-                .context(format!("Reading `{}`", wasm_path.to_string_lossy()))?;
-            let data_dir = plugin_path.join(&plugin_meta.data_dir);
-            if !data_dir.exists() {
-                fs::create_dir_all(&data_dir).await?;
-            }
-            let plugin = match PluginImage::load(&engine, &bytes, plugin_meta, data_dir).await {
-                Ok(p) => p,
+            let plugin = match images.remove(&plugin_id) {
+                Some(image) => {
+                    debug!("Keeping `{plugin_id}` as it was loaded");
+                    image
+                }
+                None => match load_image(&engine, &shared, plugin_path, plugin_meta).await {
+                    Ok(image) => Arc::new(image),
+                    Err(e) => {
+                        error!(
+                            "Error loading plugin `{}`: {e:#}",
+                            plugin_path.to_string_lossy()
+                        );
+                        failures.insert(plugin_id, format!("{e:#}"));
+                        continue;
+                    }
+                },
+            };
+
+            let plugin_schedules = match PluginSchedule::from_meta(plugin.meta()) {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("Error loading schedules of plugin `{}`: {e:#}", plugin.id());
+                    failures.insert(plugin_id, format!("{e:#}"));
+                    continue;
+                }
+            };
+
+            let plugin_subscribers = match Subscriber::from_meta(plugin.meta()) {
+                Ok(s) => s,
                 Err(e) => {
                     error!(
-                        "Error loading plugin `{path:?}`: {e:#}",
-                        path = plugin_path.to_string_lossy()
+                        "Error loading subscriptions of plugin `{}`: {e:#}",
+                        plugin.id()
                     );
-                    errors += 1;
+                    failures.insert(plugin_id, format!("{e:#}"));
                     continue;
                 }
             };
 
-            trace!("Registering plugin at route {base_url}");
-
-            if router.at(&base_url).is_ok() || router.at(&base_url_catchall).is_ok() {
-                error!("Same url `{base_url}` is already handled by another plugin");
-                errors += 1;
-                continue;
+            if plugin.has_http_handler() || plugin.has_websocket_handler() {
+                match add_route(&mut router, &plugin.meta().endpoint, plugin.id()) {
+                    Ok(base_url) => routes.push((base_url, plugin.id().to_owned())),
+                    Err(e) => {
+                        error!("{e:#}");
+                        failures.insert(plugin_id, format!("{e:#}"));
+                        continue;
+                    }
+                }
             }
 
-            router
-                .insert(base_url, plugin.id().to_owned())
-                .context("Inserting plugin into router")?;
-            router
-                .insert(base_url_catchall, plugin.id().to_owned())
-                .context("Inserting plugin into router")?;
-
+            schedules.extend(plugin_schedules);
+            for subscriber in plugin_subscribers {
+                subscribers
+                    .entry(subscriber.topic().to_owned())
+                    .or_default()
+                    .push(subscriber);
+            }
             map.insert(plugin.id().to_owned(), plugin);
 
             successes += 1;
         }
 
-        info!("Loaded {successes} plugins with {errors} errors");
+        info!(
+            "Loaded {successes} plugins with {errors} errors",
+            errors = failures.len()
+        );
+        if options.strict && !failures.is_empty() {
+            return Err(LoadError { failures }.into());
+        }
 
         Ok(Self {
             map,
             engine,
             router,
+            schedules,
+            subscribers,
+            messages: Mutex::new(messages),
+            publisher,
+            memory_keyvalue,
+            sql_connections,
+            blob_containers,
+            metrics,
+            request_id: config.meta.request_id,
+            profiling,
+            routes,
+            failures,
+            base_path: base_path.as_ref().to_owned(),
+            options,
         })
     }
+
+    pub fn plugins(&self) -> impl Iterator<Item = &PluginImage> {
+        self.map.values().map(AsRef::as_ref)
+    }
+
+    pub fn plugin(&self, id: &str) -> Option<&PluginImage> {
+        self.map.get(id).map(AsRef::as_ref)
+    }
+
+    pub fn routes(&self) -> &[(String, String)] {
+        &self.routes
+    }
+
+    /// Error which kept a plugin from loading, if it failed
+    pub fn failure(&self, id: &str) -> Option<&str> {
+        self.failures.get(id).map(String::as_str)
+    }
+
+    /// Plugins which failed to load and the reason, ordered by id
+    pub fn failures(&self) -> &BTreeMap<String, String> {
+        &self.failures
+    }
+
+    pub fn metrics(&self) -> &Arc<MetricsRegistry> {
+        &self.metrics
+    }
+
+    pub fn profiling(&self) -> &GuestProfiling {
+        &self.profiling
+    }
+}
+
+/// Reads, compiles and links a plugin, creating its data directory
+async fn load_image(
+    engine: &Engine,
+    shared: &SharedServices,
+    plugin_path: &Path,
+    meta: PluginMeta,
+) -> anyhow::Result<PluginImage> {
+    let wasm_path = plugin_path.join("plugin.wasm");
+    let bytes = fs::read(&wasm_path)
+        .await
+        .context(format!("Reading `{}`", wasm_path.to_string_lossy()))?;
+    let data_dir = plugin_path.join(&meta.data_dir);
+    if !data_dir.exists() {
+        fs::create_dir_all(&data_dir).await.context(format!(
+            "Creating data directory `{}`",
+            data_dir.to_string_lossy()
+        ))?;
+    }
+    let services = shared.for_plugin(&meta, plugin_path)?;
+    PluginImage::load(engine, &bytes, meta, data_dir, services).await
+}
+
+/// Routes the endpoint and everything below it to the plugin, returning the
+/// registered base url
+fn add_route(
+    router: &mut matchit::Router<String>,
+    endpoint: &str,
+    plugin_id: &str,
+) -> anyhow::Result<String> {
+    let mut base_url = endpoint.to_owned();
+    if !base_url.ends_with('/') {
+        base_url += "/";
+    }
+    let base_url_catchall = base_url.clone() + "{*path}";
+    trace!("Registering plugin at route {base_url}");
+
+    if let Ok(existing) = router
+        .at(&base_url)
+        .or_else(|_| router.at(&base_url_catchall))
+    {
+        anyhow::bail!(
+            "Url `{base_url}` is already handled by plugin `{}`",
+            existing.value
+        );
+    }
+    router
+        .insert(&base_url, plugin_id.to_owned())
+        .context("Inserting plugin into router")?;
+    router
+        .insert(base_url_catchall, plugin_id.to_owned())
+        .context("Inserting plugin into router")?;
+    Ok(base_url)
+}
+
+/// Outcome of validating a stack without loading it
+#[derive(Debug, Default)]
+pub struct StackCheck {
+    /// Ids of the plugins which passed
+    pub plugins: Vec<String>,
+    /// Endpoints and the ids of the plugins which would serve them
+    pub routes: Vec<(String, String)>,
+    /// Plugins which would fail to load and the reason
+    pub failures: BTreeMap<String, String>,
+}
+
+/// State a reloaded stack takes over from the one it replaces
+#[derive(Default)]
+struct CarriedState {
+    metrics: Arc<MetricsRegistry>,
+    /// Publisher of the replaced stack, whose queue the new broker reads
+    publisher: Option<Publisher>,
+    memory_keyvalue: MemoryBuckets,
+    sql_connections: SqlConnectionLimits,
+    blob_containers: BlobContainers,
+    /// Plugins kept as they were loaded, when only some are reloaded
+    kept: Option<KeptPlugins>,
+}
+
+/// Loaded plugins along with the engine and profiling they were loaded
+/// with, which the reloaded plugins then share
+struct KeptPlugins {
+    engine: Engine,
+    profiling: Arc<GuestProfiling>,
+    images: HashMap<String, Arc<PluginImage>>,
+}
+
+/// Stack-wide state the host services of every plugin are derived from
+struct SharedServices {
+    base_path: PathBuf,
+    outbound: OutboundHttp,
+    keyvalue: KeyValueSettings,
+    memory_keyvalue: MemoryBuckets,
+    blobstore: BlobStoreSettings,
+    blob_containers: BlobContainers,
+    sql: SqlSettings,
+    sql_connections: SqlConnectionLimits,
+    publisher: Publisher,
+    secrets: SecretsFile,
+    metrics: Arc<MetricsRegistry>,
+    capture_stderr: bool,
+    coredumps: Arc<CoreDumps>,
+}
+
+impl SharedServices {
+    fn for_plugin(&self, meta: &PluginMeta, plugin_path: &Path) -> anyhow::Result<PluginServices> {
+        let sql = meta
+            .sql
+            .as_ref()
+            .map(|settings| {
+                SqlDatabase::new(
+                    &self.sql,
+                    &self.sql_connections,
+                    &meta.id,
+                    plugin_path,
+                    settings,
+                )
+            })
+            .transpose()
+            .context("Preparing SQL database")?
+            .map(Arc::new);
+
+        let secrets = Arc::new(
+            PluginSecrets::resolve(&meta.secrets, &self.base_path, &self.secrets)
+                .context("Resolving secrets")?,
+        );
+
+        Ok(PluginServices {
+            outbound: self
+                .outbound
+                .for_plugin(meta, secrets.clone())
+                .context("Preparing outbound HTTP")?,
+            keyvalue: Arc::new(
+                KeyValue::new(
+                    &self.keyvalue,
+                    &self.memory_keyvalue,
+                    &meta.id,
+                    &meta.keyvalue,
+                )
+                .context("Opening key-value store")?,
+            ),
+            publisher: self.publisher.for_plugin(&meta.id),
+            sql,
+            secrets,
+            blobstore: Arc::new(
+                BlobStore::new(
+                    &self.blobstore,
+                    &self.blob_containers,
+                    &meta.id,
+                    &meta.blobstore,
+                )
+                .context("Opening blob store")?,
+            ),
+            metrics: PluginMetrics::new(
+                self.metrics.clone(),
+                &meta.id,
+                &meta.version,
+                meta.metrics.clone(),
+            )
+            .context("Preparing metrics")?,
+            capture_stderr: self.capture_stderr,
+            coredumps: self.coredumps.for_plugin(&meta.id, &meta.version),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_plugin(base: &Path, id: &str, wasm: Option<&[u8]>) {
+        let dir = base.join("plugins").join(id);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("plugin.toml"),
+            format!(
+                r#"
+                id = "{id}"
+
+                [keyvalue.buckets.cache]
+                "#
+            ),
+        )
+        .unwrap();
+        if let Some(wasm) = wasm {
+            std::fs::write(dir.join("plugin.wasm"), wasm).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn check_reports_every_failure_without_side_effects() {
+        let dir = tempfile::tempdir().unwrap();
+        write_plugin(dir.path(), "missing", None);
+        write_plugin(dir.path(), "invalid", Some(b"not wasm"));
+
+        let check = Stack::check(dir.path()).await.unwrap();
+        assert!(check.plugins.is_empty());
+        assert_eq!(
+            check.failures.keys().collect::<Vec<_>>(),
+            ["invalid", "missing"]
+        );
+        assert!(!dir.path().join(".wassel").exists());
+        assert!(!dir.path().join("plugins/invalid/data").exists());
+    }
+
+    /// Component with a message handler which accepts every message
+    const HANDLER: &str = r#"(component
+        (core module $m
+            (memory (export "memory") 1)
+            (func (export "realloc") (param i32 i32 i32 i32) (result i32) (i32.const 1024))
+            (func (export "handle") (param i32 i32 i32 i32) (result i32) (i32.const 0)))
+        (core instance $i (instantiate $m))
+        (func $handle
+            (param "topic" string) (param "payload" (list u8))
+            (result (result (error string)))
+            (canon lift (core func $i "handle")
+                (memory $i "memory") (realloc (func $i "realloc"))))
+        (instance $handler (export "handle-message" (func $handle)))
+        (export "wassel:foundation/message-handler" (instance $handler)))"#;
+
+    #[tokio::test]
+    async fn only_changed_plugins_are_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        write_plugin(dir.path(), "kept", Some(HANDLER.as_bytes()));
+        write_plugin(dir.path(), "changed", Some(HANDLER.as_bytes()));
+        let options = StackOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let stack = Stack::load(dir.path(), options).await.unwrap();
+
+        let reloaded = stack.reload_plugins(&["changed".to_owned()]).await.unwrap();
+        assert!(Arc::ptr_eq(&stack.map["kept"], &reloaded.map["kept"]));
+        assert!(!Arc::ptr_eq(
+            &stack.map["changed"],
+            &reloaded.map["changed"]
+        ));
+    }
 }
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use hyper::{Request, StatusCode, body::Incoming};
+use tracing::{debug, error, warn};
+use wassel_plugin_component::PluginImage;
+
+use crate::{
+    Stack,
+    errors::ServeError,
+    response::{self, IntoResponse},
+};
+
+impl Stack {
+    /// Hands WebSocket upgrade request to a new instance of the plugin, which
+    /// is kept alive for the lifetime of the connection
+    pub(crate) async fn handle_websocket(
+        &self,
+        image: &PluginImage,
+        req: Request<Incoming>,
+    ) -> response::Response {
+        let Some(permit) = image.try_acquire_websocket() else {
+            warn!(
+                "Plugin {} reached its limit of {} WebSocket connections",
+                image.id(),
+                image.meta().websocket.max_connections
+            );
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        };
+
+        let plugin = match image.instantiate(&self.engine).await {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Could not instantiate plugin {}: {:#}", image.id(), e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        debug!(
+            "Instantiated plugin {} to handle WebSocket session",
+            image.id()
+        );
+
+        let idle_timeout = Duration::from_secs(image.meta().websocket.idle_timeout_secs);
+        plugin
+            .handle_websocket(req, idle_timeout, permit)
+            .await
+            .map_err(ServeError::PluginError)
+            .into_response()
+    }
+}
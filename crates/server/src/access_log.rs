@@ -0,0 +1,361 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::mpsc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use bytes::Buf;
+use chrono::{DateTime, Local};
+use hyper::{
+    HeaderMap, Request, Response, StatusCode,
+    body::{Body, Frame, SizeHint},
+    header,
+};
+use serde::Deserialize;
+use tracing::error;
+
+use wassel_plugin_stack::{REQUEST_ID_HEADER, RoutedPlugin};
+
+/// `access_log` section of the server config
+#[derive(Clone, Debug, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default = "AccessLogFormat::default")]
+    pub format: AccessLogFormat,
+
+    /// File the log is appended to, standard output when unset
+    #[serde(default = "Option::default")]
+    pub file: Option<PathBuf>,
+
+    /// Size in bytes at which the file is rotated
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+
+    /// Rotated files kept next to the log as `<file>.1` up to `<file>.<max_files>`
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+fn default_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Common Log Format followed by plugin id, request id and duration in
+    /// milliseconds
+    #[default]
+    Common,
+    /// Combined Log Format, which adds referer and user agent to the common
+    /// one, followed by plugin id, request id and duration in milliseconds
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+/// Writes access log lines on a dedicated thread so requests never wait on
+/// the disk
+#[derive(Clone)]
+pub(crate) struct AccessLog {
+    format: AccessLogFormat,
+    sender: mpsc::Sender<String>,
+}
+
+impl AccessLog {
+    pub(crate) fn new(config: &AccessLogConfig) -> anyhow::Result<Self> {
+        let mut output: Box<dyn Write + Send> = match &config.file {
+            Some(path) => Box::new(RotatingFile::open(
+                path.clone(),
+                config.max_size,
+                config.max_files,
+            )?),
+            None => Box::new(io::stdout()),
+        };
+
+        let (sender, receiver) = mpsc::channel::<String>();
+        std::thread::Builder::new()
+            .name("access-log".to_owned())
+            .spawn(move || {
+                for line in receiver {
+                    if let Err(e) = output
+                        .write_all(line.as_bytes())
+                        .and_then(|_| output.flush())
+                    {
+                        error!("Error writing access log: {e}");
+                    }
+                }
+            })
+            .context("Spawning access log thread")?;
+
+        Ok(Self {
+            format: config.format,
+            sender,
+        })
+    }
+
+    /// Starts the entry of a request, written once its response is finished
+    pub(crate) fn start<B>(&self, req: &Request<B>, client: IpAddr) -> PendingEntry {
+        PendingEntry {
+            log: self.clone(),
+            started: Instant::now(),
+            time: Local::now(),
+            client,
+            method: req.method().to_string(),
+            path: req
+                .uri()
+                .path_and_query()
+                .map(|paq| paq.as_str().to_owned())
+                .unwrap_or_else(|| req.uri().path().to_owned()),
+            protocol: format!("{:?}", req.version()),
+            referer: header_value(req.headers(), header::REFERER),
+            user_agent: header_value(req.headers(), header::USER_AGENT),
+        }
+    }
+}
+
+fn header_value(headers: &HeaderMap, name: impl header::AsHeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Request whose response is still being sent
+pub(crate) struct PendingEntry {
+    log: AccessLog,
+    started: Instant,
+    time: DateTime<Local>,
+    client: IpAddr,
+    method: String,
+    path: String,
+    protocol: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl PendingEntry {
+    pub(crate) fn finish(self, head: &ResponseHead, bytes: u64) {
+        let duration = self.started.elapsed();
+        let line = match self.log.format {
+            AccessLogFormat::Common => self.format_common(head, bytes, duration, false),
+            AccessLogFormat::Combined => self.format_common(head, bytes, duration, true),
+            AccessLogFormat::Json => self.format_json(head, bytes, duration),
+        };
+        let _ = self.log.sender.send(line);
+    }
+
+    fn format_common(
+        &self,
+        head: &ResponseHead,
+        bytes: u64,
+        duration: Duration,
+        combined: bool,
+    ) -> String {
+        let quoted = |value: Option<&str>| match value {
+            Some(v) => format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")),
+            None => "\"-\"".to_owned(),
+        };
+        let bytes = match bytes {
+            0 => "-".to_owned(),
+            n => n.to_string(),
+        };
+
+        let mut line = format!(
+            "{client} - - [{time}] {request} {status} {bytes}",
+            client = self.client,
+            time = self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            request = quoted(Some(&format!(
+                "{} {} {}",
+                self.method, self.path, self.protocol
+            ))),
+            status = head.status.as_u16(),
+        );
+        if combined {
+            line += &format!(
+                " {} {}",
+                quoted(self.referer.as_deref()),
+                quoted(self.user_agent.as_deref())
+            );
+        }
+        line += &format!(
+            " {} {} {}\n",
+            quoted(head.plugin_id.as_deref()),
+            quoted(head.request_id.as_deref()),
+            duration.as_millis()
+        );
+        line
+    }
+
+    fn format_json(&self, head: &ResponseHead, bytes: u64, duration: Duration) -> String {
+        let entry = serde_json::json!({
+            "time": self.time.to_rfc3339(),
+            "client_ip": self.client.to_string(),
+            "method": self.method,
+            "path": self.path,
+            "protocol": self.protocol,
+            "status": head.status.as_u16(),
+            "bytes": bytes,
+            "duration_ms": duration.as_secs_f64() * 1000.0,
+            "plugin_id": head.plugin_id,
+            "request_id": head.request_id,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+        });
+        format!("{entry}\n")
+    }
+}
+
+/// Response body counting the bytes sent, finishing the access log entry
+/// once the body is done or dropped
+pub(crate) struct LoggedBody<B> {
+    inner: B,
+    bytes: u64,
+    entry: Option<(PendingEntry, ResponseHead)>,
+}
+
+/// Parts of the response head written to the access log
+pub(crate) struct ResponseHead {
+    pub(crate) status: StatusCode,
+    pub(crate) plugin_id: Option<String>,
+    pub(crate) request_id: Option<String>,
+}
+
+impl<B> LoggedBody<B> {
+    pub(crate) fn wrap(response: Response<B>, entry: Option<PendingEntry>) -> Response<Self> {
+        let head = ResponseHead {
+            status: response.status(),
+            plugin_id: response
+                .extensions()
+                .get::<RoutedPlugin>()
+                .map(|p| p.0.clone()),
+            request_id: header_value(response.headers(), REQUEST_ID_HEADER),
+        };
+        response.map(|inner| Self {
+            inner,
+            bytes: 0,
+            entry: entry.map(|e| (e, head)),
+        })
+    }
+}
+
+impl<B: Body + Unpin> Body for LoggedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = Pin::new(&mut this.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            this.bytes += data.remaining() as u64;
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for LoggedBody<B> {
+    fn drop(&mut self) {
+        if let Some((entry, head)) = self.entry.take() {
+            entry.finish(&head, self.bytes);
+        }
+    }
+}
+
+/// Log file moved aside as `<path>.1` once it grows past the size limit,
+/// shifting older files up to `<path>.<max_files>`
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent()
+            && !dir.as_os_str().is_empty()
+        {
+            fs::create_dir_all(dir).context(format!(
+                "Creating access log directory `{}`",
+                dir.to_string_lossy()
+            ))?;
+        }
+        let file = open_append(&path)
+            .context(format!("Opening access log `{}`", path.to_string_lossy()))?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    File::options().create(true).append(true).open(path)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
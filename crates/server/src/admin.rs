@@ -0,0 +1,363 @@
+use std::{
+    convert::Infallible,
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::Incoming,
+    header::{self, HeaderValue},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, lookup_host},
+};
+use tracing::{error, info, warn};
+
+use wassel_plugin_stack::{PluginImage, PluginStats, Stack, constant_time_eq};
+
+use crate::live::LiveStack;
+
+/// `admin` section of the server config
+#[derive(Clone, Debug, Deserialize)]
+pub struct AdminConfig {
+    /// TCP address to listen on, which has to be a loopback address
+    #[serde(default = "Option::default")]
+    pub address: Option<String>,
+
+    /// Unix socket to listen on instead of a TCP address
+    #[serde(default = "Option::default")]
+    pub socket: Option<PathBuf>,
+
+    /// Environment variable holding the token clients have to send as
+    /// `Authorization: Bearer <token>`
+    #[serde(default = "default_token_env")]
+    pub token_env: String,
+}
+
+fn default_token_env() -> String {
+    "WASSEL_ADMIN_TOKEN".to_owned()
+}
+
+struct Admin {
+    live: Arc<LiveStack>,
+    token: String,
+}
+
+/// Binds the admin listener and serves it in the background
+pub(crate) async fn spawn_admin_endpoint(
+    config: &AdminConfig,
+    live: Arc<LiveStack>,
+) -> anyhow::Result<()> {
+    let token = env::var(&config.token_env)
+        .context(format!("Reading admin token from `{}`", config.token_env))?;
+    if token.trim().is_empty() {
+        anyhow::bail!("Admin token in `{}` is empty", config.token_env);
+    }
+    let admin = Arc::new(Admin {
+        live,
+        token: token.trim().to_owned(),
+    });
+
+    match (&config.address, &config.socket) {
+        (Some(addr), None) => {
+            let addrs = resolve_loopback(addr).await?;
+            let listener = TcpListener::bind(addrs.as_slice())
+                .await
+                .context(format!("Binding admin listener to {addr}"))?;
+            let local_addr = listener.local_addr()?;
+            info!("Serving admin API at {local_addr}");
+
+            tokio::task::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((tcp, _)) => serve_connection(tcp, admin.clone()),
+                        Err(e) => error!("Error accepting admin connection: {e:?}"),
+                    }
+                }
+            });
+        }
+        (None, Some(path)) => spawn_unix_listener(path, admin)?,
+        (Some(_), Some(_)) => anyhow::bail!("Admin listener needs either an address or a socket"),
+        (None, None) => anyhow::bail!("Admin listener needs an address or a socket"),
+    }
+
+    Ok(())
+}
+
+/// Resolves the admin address, refusing it before anything is bound unless
+/// all of its addresses are loopback ones
+async fn resolve_loopback(addr: &str) -> anyhow::Result<Vec<SocketAddr>> {
+    let addrs: Vec<_> = lookup_host(addr)
+        .await
+        .context(format!("Resolving admin address {addr}"))?
+        .collect();
+    if let Some(addr) = addrs.iter().find(|a| !a.ip().is_loopback()) {
+        anyhow::bail!("Admin listener must be bound to localhost, not {addr}");
+    }
+    Ok(addrs)
+}
+
+#[cfg(unix)]
+fn spawn_unix_listener(path: &Path, admin: Arc<Admin>) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt as _;
+
+    use tokio::net::UnixListener;
+
+    // Socket left behind by a previous run would make binding fail, anything
+    // else at the path is left alone
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!(
+                "Admin socket path `{}` exists and is not a socket",
+                path.to_string_lossy()
+            );
+        }
+        std::fs::remove_file(path).context(format!(
+            "Removing stale admin socket `{}`",
+            path.to_string_lossy()
+        ))?;
+    }
+    let listener = UnixListener::bind(path)
+        .context(format!("Binding admin socket `{}`", path.to_string_lossy()))?;
+    info!("Serving admin API at `{}`", path.to_string_lossy());
+
+    tokio::task::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => serve_connection(stream, admin.clone()),
+                Err(e) => error!("Error accepting admin connection: {e:?}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_unix_listener(_path: &Path, _admin: Arc<Admin>) -> anyhow::Result<()> {
+    anyhow::bail!("Admin sockets are only supported on Unix")
+}
+
+fn serve_connection<S>(stream: S, admin: Arc<Admin>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| {
+        let admin = admin.clone();
+        async move { Ok::<_, Infallible>(admin.handle(req).await) }
+    });
+
+    tokio::task::spawn(async move {
+        if let Err(e) = http1::Builder::new()
+            .timer(TokioTimer::new())
+            .serve_connection(TokioIo::new(stream), service)
+            .await
+        {
+            error!("Error serving admin API: {e:?}");
+        }
+    });
+}
+
+#[derive(Serialize)]
+struct PluginSummary<'a> {
+    id: &'a str,
+    version: &'a str,
+    endpoint: &'a str,
+    hash: &'a str,
+    enabled: bool,
+}
+
+impl<'a> PluginSummary<'a> {
+    fn new(image: &'a PluginImage) -> Self {
+        Self {
+            id: image.id(),
+            version: &image.meta().version,
+            endpoint: &image.meta().endpoint,
+            hash: image.hash(),
+            enabled: image.is_enabled(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PluginHealth<'a> {
+    id: &'a str,
+    status: &'static str,
+    #[serde(flatten)]
+    stats: PluginStats,
+}
+
+#[derive(Serialize)]
+struct Route<'a> {
+    endpoint: &'a str,
+    plugin_id: &'a str,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl Admin {
+    async fn handle(&self, req: Request<Incoming>) -> Response<Full<Bytes>> {
+        if !is_authorized(&req, &self.token) {
+            warn!("Rejected unauthorized admin request");
+            return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+        }
+
+        let path = req.uri().path().trim_matches('/');
+        let segments: Vec<&str> = path.split('/').collect();
+        let stack = self.live.current();
+
+        match (req.method(), segments.as_slice()) {
+            (&Method::GET, ["plugins"]) => json(StatusCode::OK, &plugins(&stack)),
+            (&Method::GET, ["routes"]) => {
+                let routes: Vec<_> = stack
+                    .routes()
+                    .iter()
+                    .map(|(endpoint, plugin_id)| Route {
+                        endpoint,
+                        plugin_id,
+                    })
+                    .collect();
+                json(StatusCode::OK, &routes)
+            }
+            (&Method::GET, ["plugins", id, "health"]) => match stack.plugin(id) {
+                Some(image) => json(
+                    StatusCode::OK,
+                    &PluginHealth {
+                        id: image.id(),
+                        status: if image.is_enabled() {
+                            "enabled"
+                        } else {
+                            "disabled"
+                        },
+                        stats: image.stats(),
+                    },
+                ),
+                None => plugin_not_found(id),
+            },
+            (&Method::POST, ["plugins", id, action @ ("enable" | "disable")]) => {
+                match stack.plugin(id) {
+                    Some(image) => {
+                        image.set_enabled(*action == "enable");
+                        info!("Plugin `{id}` was {action}d through the admin API");
+                        json(StatusCode::OK, &PluginSummary::new(image))
+                    }
+                    None => plugin_not_found(id),
+                }
+            }
+            (&Method::POST, ["reload"]) => match self.live.reload().await {
+                Ok(()) => json(StatusCode::OK, &plugins(&self.live.current())),
+                Err(e) => {
+                    error!("Reload requested through the admin API failed: {e:#}");
+                    error(StatusCode::INTERNAL_SERVER_ERROR, &format!("{e:#}"))
+                }
+            },
+            _ => error(StatusCode::NOT_FOUND, "Unknown admin endpoint"),
+        }
+    }
+}
+
+fn is_authorized<B>(req: &Request<B>, token: &str) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|sent| constant_time_eq(sent.trim().as_bytes(), token.as_bytes()))
+}
+
+fn plugins(stack: &Stack) -> Vec<PluginSummary<'_>> {
+    let mut plugins: Vec<_> = stack.plugins().map(PluginSummary::new).collect();
+    plugins.sort_by_key(|p| p.id);
+    plugins
+}
+
+fn plugin_not_found(id: &str) -> Response<Full<Bytes>> {
+    error(
+        StatusCode::NOT_FOUND,
+        &format!("Plugin `{id}` is not loaded"),
+    )
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    json(
+        status,
+        &ErrorBody {
+            error: message.to_owned(),
+        },
+    )
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(value).expect("Admin responses should serialize");
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder().uri("/plugins");
+        if let Some(value) = authorization {
+            builder = builder.header(header::AUTHORIZATION, value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn requests_need_the_bearer_token() {
+        assert!(is_authorized(&request(Some("Bearer s3cr3t")), "s3cr3t"));
+        assert!(!is_authorized(&request(Some("Bearer s3cr3")), "s3cr3t"));
+        assert!(!is_authorized(&request(Some("Bearer s3cr3t!")), "s3cr3t"));
+        assert!(!is_authorized(&request(Some("s3cr3t")), "s3cr3t"));
+        assert!(!is_authorized(&request(Some("Basic s3cr3t")), "s3cr3t"));
+        assert!(!is_authorized(&request(None), "s3cr3t"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn only_stale_sockets_are_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let stack = Stack::load(dir.path(), Default::default()).await.unwrap();
+        let admin = Arc::new(Admin {
+            live: Arc::new(LiveStack::start(stack)),
+            token: "s3cr3t".to_owned(),
+        });
+
+        let file = dir.path().join("admin.txt");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(spawn_unix_listener(&file, admin.clone()).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+
+        let socket = dir.path().join("admin.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        spawn_unix_listener(&socket, admin).unwrap();
+    }
+
+    #[tokio::test]
+    async fn non_loopback_addresses_are_refused() {
+        assert!(resolve_loopback("127.0.0.1:0").await.is_ok());
+        assert!(resolve_loopback("[::1]:0").await.is_ok());
+        assert!(resolve_loopback("0.0.0.0:0").await.is_err());
+        assert!(resolve_loopback("192.0.2.1:0").await.is_err());
+    }
+}
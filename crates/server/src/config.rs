@@ -1,16 +1,57 @@
+use std::path::Path;
+
 use config::ConfigError;
 use serde::Deserialize;
 
+use crate::{
+    access_log::AccessLogConfig, admin::AdminConfig, health::HealthConfig,
+    telemetry::TelemetryConfig,
+};
+
+/// Deployment profile selecting defaults suited for it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    #[default]
+    Development,
+    Production,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub host: String,
     pub port: String,
+
+    /// Unset means development, but dev mode needs it set explicitly
+    #[serde(default = "Option::default")]
+    pub profile: Option<Profile>,
+
+    /// Abort startup when any plugin fails to load. On by default in the
+    /// production profile.
+    pub strict: Option<bool>,
+
+    /// Address of the Prometheus metrics endpoint, disabled when unset
+    pub metrics_address: Option<String>,
+
+    /// Access log of all requests, disabled when unset
+    pub access_log: Option<AccessLogConfig>,
+
+    /// OpenTelemetry trace export, disabled when unset
+    pub telemetry: Option<TelemetryConfig>,
+
+    /// Admin API listener, disabled when unset
+    pub admin: Option<AdminConfig>,
+
+    /// Liveness and readiness probes answered by the server, disabled when unset
+    pub health: Option<HealthConfig>,
 }
 
 impl Config {
-    pub fn load() -> Result<Self, ConfigError> {
+    /// Reads `wassel.toml` of the stack in `base_path`
+    pub fn load(base_path: &Path) -> Result<Self, ConfigError> {
+        let file = base_path.join("wassel");
         let config = config::Config::builder()
-            .add_source(config::File::with_name("wassel").required(false))
+            .add_source(config::File::with_name(&file.to_string_lossy()).required(false))
             .set_default("host", "127.0.0.1")
             .unwrap()
             .set_default("port", "9000")
@@ -19,6 +60,14 @@ impl Config {
 
         config.try_deserialize()
     }
+
+    pub fn profile(&self) -> Profile {
+        self.profile.unwrap_or_default()
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict.unwrap_or(self.profile() == Profile::Production)
+    }
 }
 
 impl Default for Config {
@@ -26,6 +75,13 @@ impl Default for Config {
         Self {
             host: "127.0.0.1".to_owned(),
             port: "9000".to_owned(),
+            profile: None,
+            strict: None,
+            metrics_address: None,
+            access_log: None,
+            telemetry: None,
+            admin: None,
+            health: None,
         }
     }
 }
@@ -0,0 +1,134 @@
+use std::sync::{
+    Arc, OnceLock,
+    atomic::{AtomicBool, Ordering},
+};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt as _, Full, combinators::UnsyncBoxBody};
+use hyper::{Response, StatusCode, header};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::live::LiveStack;
+
+/// `health` section of the server config
+#[derive(Clone, Debug, Deserialize)]
+pub struct HealthConfig {
+    /// Path answering whether the server process is up
+    #[serde(default = "default_liveness_path")]
+    pub liveness_path: String,
+
+    /// Path answering whether the server should receive traffic
+    #[serde(default = "default_readiness_path")]
+    pub readiness_path: String,
+
+    /// Plugins which have to be loaded without error for the server to be ready
+    #[serde(default = "Vec::default")]
+    pub required_plugins: Vec<String>,
+
+    /// How long the server keeps serving while reporting not-ready after
+    /// being asked to shut down, so load balancers can take it out first
+    #[serde(default = "default_drain_secs")]
+    pub drain_secs: u64,
+}
+
+fn default_liveness_path() -> String {
+    "/healthz".to_owned()
+}
+
+fn default_readiness_path() -> String {
+    "/readyz".to_owned()
+}
+
+fn default_drain_secs() -> u64 {
+    5
+}
+
+pub(crate) type ProbeResponse = Response<UnsyncBoxBody<Bytes, anyhow::Error>>;
+
+/// Lifecycle of the server as seen by probes
+pub(crate) struct Health {
+    config: Option<HealthConfig>,
+    /// Set once the stack has loaded
+    live: OnceLock<Arc<LiveStack>>,
+    draining: AtomicBool,
+}
+
+impl Health {
+    pub(crate) fn new(config: Option<HealthConfig>) -> Self {
+        Self {
+            config,
+            live: OnceLock::new(),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn set_loaded(&self, live: Arc<LiveStack>) {
+        if self.live.set(live).is_err() {
+            panic!("Stack should only be loaded once");
+        }
+    }
+
+    /// Stack being served, unless it is still loading
+    pub(crate) fn live(&self) -> Option<&Arc<LiveStack>> {
+        self.live.get()
+    }
+
+    pub(crate) fn start_draining(&self) {
+        info!("Draining, readiness probes fail from now on");
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn drain_secs(&self) -> u64 {
+        self.config.as_ref().map_or(0, |c| c.drain_secs)
+    }
+
+    /// Answers the request if it is a probe
+    pub(crate) fn probe(&self, path: &str) -> Option<ProbeResponse> {
+        let config = self.config.as_ref()?;
+        if path == config.liveness_path {
+            Some(probe_response(StatusCode::OK, "ok".to_owned()))
+        } else if path == config.readiness_path {
+            Some(match self.not_ready_reason(config) {
+                Some(reason) => probe_response(StatusCode::SERVICE_UNAVAILABLE, reason),
+                None => probe_response(StatusCode::OK, "ready".to_owned()),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn not_ready_reason(&self, config: &HealthConfig) -> Option<String> {
+        if self.draining.load(Ordering::Relaxed) {
+            return Some("draining".to_owned());
+        }
+        let Some(live) = self.live.get() else {
+            return Some("loading".to_owned());
+        };
+
+        let stack = live.current();
+        config.required_plugins.iter().find_map(|id| {
+            if stack.plugin(id).is_some() {
+                None
+            } else if let Some(error) = stack.failure(id) {
+                Some(format!("plugin `{id}` failed to load: {error}"))
+            } else {
+                Some(format!("plugin `{id}` is not loaded"))
+            }
+        })
+    }
+}
+
+pub(crate) fn probe_response(status: StatusCode, body: String) -> ProbeResponse {
+    let mut response = Response::new(
+        Full::new(Bytes::from(body + "\n"))
+            .map_err(|never| match never {})
+            .boxed_unsync(),
+    );
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
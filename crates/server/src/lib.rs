@@ -1,29 +1,89 @@
 use anyhow::Context;
 use config::Config;
+use opentelemetry::trace::TracerProvider as _;
 use server::Server;
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::EnvFilter;
+use tracing::{error, level_filters::LevelFilter};
+use tracing_subscriber::{EnvFilter, layer::SubscriberExt as _, util::SubscriberInitExt as _};
 
+mod access_log;
+mod admin;
 mod config;
+mod health;
+mod live;
+mod metrics;
+mod options;
+mod profile;
 mod server;
+mod telemetry;
 
-pub async fn run_server() -> anyhow::Result<()> {
-    let filter = EnvFilter::builder()
-        .with_default_directive(LevelFilter::INFO.into())
-        .from_env_lossy()
-        .add_directive("wasmtime=info".parse()?)
-        .add_directive("cranelift_codegen=info".parse()?)
-        .add_directive("cranelift_frontend=info".parse()?);
+pub use hyper::Method;
+pub use options::{PluginChanges, ServerOptions};
+pub use profile::{ProfileOutcome, ProfileRequest};
+pub use wassel_plugin_stack::{
+    CoreDumpInfo, CoreDumpSettings, CoreDumps, LoadError, OutboundMode, Secret, SecretsFile,
+    SecretsSettings, Stack, StackCheck, StackOptions,
+};
+
+pub async fn run_server(options: ServerOptions) -> anyhow::Result<()> {
+    let mut config = Config::load(options.base_path()).context("Loading config")?;
+    if let Some(host) = &options.host {
+        config.host = host.clone();
+    }
+    if let Some(port) = options.port {
+        config.port = port.to_string();
+    }
 
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
-        .with_target(false)
+    let tracer_provider = config
+        .telemetry
+        .as_ref()
+        .map(telemetry::tracer_provider)
+        .transpose()
+        .context("Setting up OpenTelemetry")?;
+    let otel_layer = tracer_provider
+        .as_ref()
+        .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("wassel")));
+
+    tracing_subscriber::registry()
+        .with(env_filter(options.log_level.as_deref())?)
+        .with(tracing_subscriber::fmt::layer().with_target(false))
+        .with(otel_layer)
         .init();
 
-    let config = Config::load().context("Loading config")?;
+    let server = Server::new(config, options);
+    let result = server.serve().await.context("Serving");
+
+    if let Some(provider) = tracer_provider
+        && let Err(e) = provider.shutdown()
+    {
+        error!("Could not flush OpenTelemetry spans: {e}");
+    }
+
+    result
+}
+
+/// Loads the stack and profiles a single request
+pub async fn run_profile(
+    options: ServerOptions,
+    request: ProfileRequest,
+) -> anyhow::Result<ProfileOutcome> {
+    tracing_subscriber::registry()
+        .with(env_filter(options.log_level.as_deref())?)
+        .with(tracing_subscriber::fmt::layer().with_target(false))
+        .init();
 
-    let server = Server::new(config);
-    server.serve().await.context("Serving")?;
+    profile::profile_request(options, request).await
+}
 
-    Ok(())
+fn env_filter(log_level: Option<&str>) -> anyhow::Result<EnvFilter> {
+    let builder = EnvFilter::builder().with_default_directive(LevelFilter::INFO.into());
+    let filter = match log_level {
+        Some(level) => builder
+            .parse(level)
+            .context(format!("Invalid log level `{level}`"))?,
+        None => builder.from_env_lossy(),
+    };
+    Ok(filter
+        .add_directive("wasmtime=info".parse()?)
+        .add_directive("cranelift_codegen=info".parse()?)
+        .add_directive("cranelift_frontend=info".parse()?))
 }
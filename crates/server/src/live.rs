@@ -0,0 +1,92 @@
+use std::sync::{Mutex, RwLock};
+
+use anyhow::Context as _;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use wassel_plugin_stack::{BrokerHandle, Stack};
+
+/// Stack being served along with its background tasks. Reloading swaps in a
+/// freshly loaded stack while requests in flight finish on the old one.
+pub(crate) struct LiveStack {
+    current: RwLock<Stack>,
+    schedules: Mutex<Vec<JoinHandle<()>>>,
+    broker: Mutex<Option<BrokerHandle>>,
+    /// Held during a reload so concurrent reloads do not interleave
+    reloading: tokio::sync::Mutex<()>,
+}
+
+impl LiveStack {
+    /// Starts schedules and the message broker of the stack
+    pub(crate) fn start(stack: Stack) -> Self {
+        Self {
+            schedules: Mutex::new(stack.spawn_schedules()),
+            broker: Mutex::new(stack.spawn_broker()),
+            current: RwLock::new(stack),
+            reloading: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn current(&self) -> Stack {
+        self.current
+            .read()
+            .expect("Stack lock should not be poisoned")
+            .clone()
+    }
+
+    pub(crate) async fn reload(&self) -> anyhow::Result<()> {
+        let _reloading = self.reloading.lock().await;
+        let stack = self.current().reload().await.context("Reloading stack")?;
+        self.replace(stack).await;
+        info!("Reloaded stack");
+        Ok(())
+    }
+
+    /// Reloads only the given plugins, keeping the others as they are
+    pub(crate) async fn reload_plugins(&self, plugin_ids: &[String]) -> anyhow::Result<()> {
+        let _reloading = self.reloading.lock().await;
+        let stack = self
+            .current()
+            .reload_plugins(plugin_ids)
+            .await
+            .context(format!("Reloading {}", plugin_ids.join(", ")))?;
+        self.replace(stack).await;
+        info!("Reloaded {}", plugin_ids.join(", "));
+        Ok(())
+    }
+
+    /// Swaps in the stack and moves the background tasks over to it
+    async fn replace(&self, stack: Stack) {
+        *self
+            .current
+            .write()
+            .expect("Stack lock should not be poisoned") = stack.clone();
+
+        {
+            let mut schedules = self
+                .schedules
+                .lock()
+                .expect("Task lock should not be poisoned");
+            for task in schedules.drain(..) {
+                task.abort();
+            }
+            *schedules = stack.spawn_schedules();
+        }
+
+        // Messages still queued move over to the broker of the new stack
+        let old_broker = self
+            .broker
+            .lock()
+            .expect("Task lock should not be poisoned")
+            .take();
+        if let Some(old_broker) = old_broker
+            && let Some(messages) = old_broker.stop().await
+        {
+            stack.adopt_messages(messages);
+        }
+        *self
+            .broker
+            .lock()
+            .expect("Task lock should not be poisoned") = stack.spawn_broker();
+    }
+}
@@ -1,4 +1,4 @@
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    wassel_server::run_server().await
+    wassel_server::run_server(Default::default()).await
 }
@@ -0,0 +1,81 @@
+use std::{convert::Infallible, sync::Arc};
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    Method, Request, Response, StatusCode, body::Incoming, header, server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use wassel_plugin_stack::MetricsRegistry;
+
+const METRICS_PATH: &str = "/metrics";
+
+/// Content type of the Prometheus text exposition format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Binds the metrics endpoint and serves it in the background, apart from
+/// the plugin routes so it is never shadowed by a plugin endpoint
+pub(crate) async fn spawn_metrics_endpoint(
+    addr: &str,
+    metrics: Arc<MetricsRegistry>,
+) -> anyhow::Result<()> {
+    info!("Serving metrics at {addr}{METRICS_PATH}");
+    let listener = TcpListener::bind(addr)
+        .await
+        .context(format!("Binding metrics endpoint to {addr}"))?;
+
+    tokio::task::spawn(async move {
+        loop {
+            let tcp = match listener.accept().await {
+                Ok((tcp, _)) => tcp,
+                Err(e) => {
+                    error!("Error accepting metrics connection: {e:?}");
+                    continue;
+                }
+            };
+            let io = TokioIo::new(tcp);
+
+            let metrics = metrics.clone();
+            let service = service_fn(move |req| {
+                let response = metrics_response(&metrics, &req);
+                async move { Ok::<_, Infallible>(response) }
+            });
+
+            tokio::task::spawn(async move {
+                if let Err(e) = http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .serve_connection(io, service)
+                    .await
+                {
+                    error!("Error serving metrics: {e:?}");
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn metrics_response(metrics: &MetricsRegistry, req: &Request<Incoming>) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    if req.uri().path() != METRICS_PATH {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+    if req.method() != Method::GET {
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+        return response;
+    }
+
+    *response.body_mut() = Full::new(Bytes::from(metrics.render()));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(CONTENT_TYPE),
+    );
+    response
+}
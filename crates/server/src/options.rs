@@ -0,0 +1,83 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+use wassel_plugin_stack::{OutboundMode, StackOptions};
+
+/// Options passed to the server by the embedding application, e.g. `wassel-cli`
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// Directory with `wassel.toml` and `plugins`, the current one by default
+    pub base_path: Option<PathBuf>,
+
+    /// Replaces `host` of the server config
+    pub host: Option<String>,
+
+    /// Replaces `port` of the server config
+    pub port: Option<u16>,
+
+    /// Log filter like `debug` or `info,wassel=trace`, takes precedence over `RUST_LOG`
+    pub log_level: Option<String>,
+
+    pub outbound: OutboundMode,
+
+    /// Debug error pages for failed plugin calls, refused in the production profile
+    pub dev_mode: bool,
+
+    /// Reloads the plugins pushed to it, e.g. by watch mode after a plugin
+    /// was rebuilt. The previous stack keeps serving when reloading fails.
+    pub reload: Option<Arc<PluginChanges>>,
+}
+
+/// Plugins changed on disk since the server last reloaded them
+#[derive(Debug, Default)]
+pub struct PluginChanges {
+    changed: Mutex<BTreeSet<String>>,
+    notify: Notify,
+}
+
+impl PluginChanges {
+    /// Marks the plugin to be reloaded
+    pub fn push(&self, plugin_id: &str) {
+        self.changed
+            .lock()
+            .expect("Changes lock should not be poisoned")
+            .insert(plugin_id.to_owned());
+        self.notify.notify_one();
+    }
+
+    /// Waits for changes and takes all of them
+    pub(crate) async fn next(&self) -> Vec<String> {
+        loop {
+            let changed = std::mem::take(
+                &mut *self
+                    .changed
+                    .lock()
+                    .expect("Changes lock should not be poisoned"),
+            );
+            if !changed.is_empty() {
+                return changed.into_iter().collect();
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+impl ServerOptions {
+    pub fn base_path(&self) -> &Path {
+        self.base_path.as_deref().unwrap_or(Path::new("."))
+    }
+
+    pub fn stack_options(&self) -> StackOptions {
+        StackOptions {
+            outbound: self.outbound.clone(),
+            strict: false,
+            profiling: None,
+            dev_mode: self.dev_mode,
+            profile_token: None,
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use http_body_util::{BodyExt as _, Full};
+use hyper::{
+    Method, Request, StatusCode, client, header,
+    server::conn::http1,
+    service::{Service as _, service_fn},
+};
+use hyper_util::rt::TokioIo;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::error;
+
+use wassel_plugin_stack::{
+    ClientAddr, ProfilingSettings, REQUEST_ID_HEADER, Stack, StackConfig, StackOptions,
+};
+
+use crate::options::ServerOptions;
+
+/// Single request sent to a profiled stack
+#[derive(Debug, Clone)]
+pub struct ProfileRequest {
+    pub method: Method,
+    /// Path of the request, including the plugin endpoint
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+#[derive(Debug)]
+pub struct ProfileOutcome {
+    pub status: StatusCode,
+    pub body: Bytes,
+    /// Profile written for the request, in the Firefox Profiler format
+    pub profile: PathBuf,
+}
+
+/// Loads the stack in the base path, sends it the request through
+/// a local listener and returns where the guest profile of it was written
+pub(crate) async fn profile_request(
+    options: ServerOptions,
+    request: ProfileRequest,
+) -> anyhow::Result<ProfileOutcome> {
+    let base_path = options.base_path().to_owned();
+    let config = StackConfig::load(&base_path)
+        .await
+        .context("Loading config")?;
+    let settings = ProfilingSettings {
+        enabled: true,
+        sample_rate: 0.0,
+        ..config.meta.profiling
+    };
+    let profile_header = settings.header.clone();
+    let profile_token = format!("{:032x}", fastrand::u128(..));
+    let options = StackOptions {
+        strict: true,
+        profiling: Some(settings),
+        profile_token: Some(profile_token.clone()),
+        ..options.stack_options()
+    };
+    let stack = Stack::load(&base_path, options)
+        .await
+        .context("Loading stack")?;
+    let path = request.path.split('?').next().unwrap_or_default();
+    let plugin_id = stack
+        .get_image(path)
+        .ok()
+        .flatten()
+        .map(|image| image.id().to_owned())
+        .context(format!("No plugin serves `{path}`"))?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .context("Binding local listener")?;
+    let addr = listener.local_addr()?;
+    let served = stack.clone();
+    tokio::task::spawn(async move {
+        while let Ok((tcp, client)) = listener.accept().await {
+            let stack = served.clone();
+            let service = service_fn(move |mut req| {
+                req.extensions_mut().insert(ClientAddr(client));
+                stack.call(req)
+            });
+            tokio::task::spawn(async move {
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(tcp), service)
+                    .await
+                {
+                    error!("Error serving profiled request: {e:?}");
+                }
+            });
+        }
+    });
+
+    let tcp = TcpStream::connect(addr)
+        .await
+        .context("Connecting to local listener")?;
+    let (mut sender, connection) = client::conn::http1::handshake(TokioIo::new(tcp))
+        .await
+        .context("Connecting to local listener")?;
+    tokio::task::spawn(connection);
+
+    let mut builder = Request::builder()
+        .method(request.method)
+        .uri(&request.path)
+        .header(header::HOST, addr.to_string())
+        .header(profile_header, profile_token);
+    for (name, value) in &request.headers {
+        builder = builder.header(name, value);
+    }
+    let req = builder
+        .body(Full::new(request.body))
+        .context("Building request")?;
+
+    let response = sender.send_request(req).await.context("Sending request")?;
+    let status = response.status();
+    let request_id = response
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+        .context("Response has no request id")?;
+    let body = response
+        .into_body()
+        .collect()
+        .await
+        .context("Reading response body")?
+        .to_bytes();
+
+    let profile = stack
+        .profiling()
+        .find_profile(&plugin_id, &request_id)
+        .context(format!(
+            "No profile was written for request `{request_id}`, see the log for errors"
+        ))?;
+
+    Ok(ProfileOutcome {
+        status,
+        body,
+        profile,
+    })
+}
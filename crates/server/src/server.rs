@@ -1,24 +1,67 @@
+use std::{env, sync::Arc, time::Duration};
+
 use anyhow::Context as _;
-use hyper::server::conn::http1;
+use hyper::{
+    Request, StatusCode,
+    body::Incoming,
+    server::conn::http1,
+    service::{Service as _, service_fn},
+};
 use hyper_util::rt::{TokioIo, TokioTimer};
-use tokio::net::TcpListener;
-use tracing::{error, info};
+use tokio::{net::TcpListener, sync::watch, time::Instant};
+use tracing::{error, info, warn};
+
+use wassel_plugin_stack::{ClientAddr, MetricsRegistry, Stack, StackOptions};
+
+use crate::{
+    access_log::{AccessLog, LoggedBody, ResponseHead},
+    admin::spawn_admin_endpoint,
+    config::{Config, Profile},
+    health::{Health, probe_response},
+    live::LiveStack,
+    metrics::spawn_metrics_endpoint,
+    options::ServerOptions,
+};
 
-use wassel_plugin_stack::Stack;
+const ACTIVE_CONNECTIONS: &str = "wassel_active_connections";
+const ACTIVE_CONNECTIONS_HELP: &str = "Open HTTP connections to the server";
 
-use crate::config::Config;
+/// How long connections still open after draining get to close on their own
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct Server {
     config: Config,
+    options: ServerOptions,
 }
 
 impl Server {
-    pub fn new(config: Config) -> Self {
-        Self { config }
+    pub fn new(config: Config, options: ServerOptions) -> Self {
+        Self { config, options }
     }
 
+    /// Serves until the process is asked to shut down. The listener is bound
+    /// before the stack loads so probes can tell loading from dead.
     pub async fn serve(&self) -> anyhow::Result<()> {
-        let stack = Stack::load(".").await.context("Loading stack")?;
+        check_dev_mode(self.options.dev_mode, self.config.profile)?;
+        if self.options.dev_mode {
+            warn!("Dev mode is on, error pages show plugin internals");
+        }
+
+        let metrics = Arc::new(MetricsRegistry::new());
+        if let Some(addr) = &self.config.metrics_address {
+            spawn_metrics_endpoint(addr, metrics.clone()).await?;
+        }
+        metrics.gauge_set(ACTIVE_CONNECTIONS, ACTIVE_CONNECTIONS_HELP, &[], 0.0)?;
+
+        let access_log = self
+            .config
+            .access_log
+            .as_ref()
+            .map(AccessLog::new)
+            .transpose()
+            .context("Opening access log")?;
+
+        let health = Arc::new(Health::new(self.config.health.clone()));
 
         let addr = format!(
             "{host}:{port}",
@@ -28,23 +71,234 @@ impl Server {
         info!("Starting server at {addr}");
         let listener = TcpListener::bind(&addr)
             .await
-            .context("Binding to {addr}")?;
+            .context(format!("Binding to {addr}"))?;
+
+        // Compiling plugins keeps a thread busy for a while, so loading runs
+        // on its own task and accepting connections carries on meanwhile
+        let mut load = tokio::task::spawn(load_stack(
+            self.config.clone(),
+            self.options.clone(),
+            health.clone(),
+            metrics.clone(),
+        ));
+        let mut loaded = false;
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+        let drain_timer = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(drain_timer);
+        let mut draining = false;
+
+        // Connections hold a receiver each, so the sender can tell when all are closed
+        let (drain, _) = watch::channel(());
 
         loop {
-            let (tcp, _) = listener.accept().await.context("Accepting connection")?;
+            let (tcp, client) = tokio::select! {
+                result = &mut load, if !loaded => {
+                    result.context("Loading stack")??;
+                    loaded = true;
+                    continue;
+                }
+                () = &mut shutdown => {
+                    if draining {
+                        warn!("Shutting down again, not waiting for draining to finish");
+                        return Ok(());
+                    }
+                    health.start_draining();
+                    let deadline = Instant::now() + Duration::from_secs(health.drain_secs());
+                    drain_timer.as_mut().reset(deadline);
+                    draining = true;
+                    shutdown.set(shutdown_signal());
+                    continue;
+                }
+                () = &mut drain_timer, if draining => break,
+                accepted = listener.accept() => accepted.context("Accepting connection")?,
+            };
             let io = TokioIo::new(tcp);
 
-            let service = stack.clone();
+            let health = health.clone();
+            let access_log = access_log.clone();
+            let service = service_fn(move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(ClientAddr(client));
+                let entry = access_log.as_ref().map(|log| log.start(&req, client.ip()));
+                let probe = health.probe(req.uri().path());
+                let response = match (&probe, health.live()) {
+                    (None, Some(live)) => Some(live.current().call(req)),
+                    _ => None,
+                };
+                async move {
+                    let result = match (probe, response) {
+                        (Some(probe), _) => Ok(probe),
+                        (None, Some(response)) => response.await,
+                        (None, None) => Ok(probe_response(
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "Stack is still loading".to_owned(),
+                        )),
+                    };
+                    match result {
+                        Ok(response) => Ok(LoggedBody::wrap(response, entry)),
+                        Err(e) => {
+                            if let Some(entry) = entry {
+                                let head = ResponseHead {
+                                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                                    plugin_id: None,
+                                    request_id: None,
+                                };
+                                entry.finish(&head, 0);
+                            }
+                            Err(e)
+                        }
+                    }
+                }
+            });
+            let metrics = metrics.clone();
+            let mut drained = drain.subscribe();
 
             tokio::task::spawn(async move {
-                if let Err(e) = http1::Builder::new()
+                let _ = metrics.gauge_add(ACTIVE_CONNECTIONS, ACTIVE_CONNECTIONS_HELP, &[], 1.0);
+                let connection = http1::Builder::new()
                     .timer(TokioTimer::new())
                     .serve_connection(io, service)
-                    .await
-                {
+                    .with_upgrades();
+                tokio::pin!(connection);
+                let result = tokio::select! {
+                    result = connection.as_mut() => result,
+                    _ = drained.changed() => {
+                        connection.as_mut().graceful_shutdown();
+                        connection.as_mut().await
+                    }
+                };
+                if let Err(e) = result {
                     error!("Error serving: {e:?}");
                 }
+                let _ = metrics.gauge_add(ACTIVE_CONNECTIONS, ACTIVE_CONNECTIONS_HELP, &[], -1.0);
             });
         }
+
+        info!("Waiting for open connections to finish");
+        load.abort();
+        let _ = drain.send(());
+        tokio::select! {
+            result = tokio::time::timeout(CLOSE_TIMEOUT, drain.closed()) => {
+                if result.is_err() {
+                    warn!(
+                        "Closing {} connections still open after {CLOSE_TIMEOUT:?}",
+                        drain.receiver_count()
+                    );
+                }
+            }
+            () = &mut shutdown => {
+                warn!("Shutting down again, not waiting for connections to finish");
+            }
+        }
+        info!("Server stopped");
+        Ok(())
+    }
+}
+
+/// A stack which does not say it is a development one might well be
+/// deployed, so dev mode has to be asked for in the config as well
+fn check_dev_mode(dev_mode: bool, profile: Option<Profile>) -> anyhow::Result<()> {
+    match (dev_mode, profile) {
+        (true, Some(Profile::Production)) => {
+            anyhow::bail!("Dev mode must not be used with the production profile")
+        }
+        (true, None) => {
+            anyhow::bail!("Dev mode needs `profile = \"development\"` in wassel.toml")
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn load_stack(
+    config: Config,
+    options: ServerOptions,
+    health: Arc<Health>,
+    metrics: Arc<MetricsRegistry>,
+) -> anyhow::Result<()> {
+    // Profiling on request is as privileged as the admin API
+    let profile_token = config
+        .admin
+        .as_ref()
+        .and_then(|admin| env::var(&admin.token_env).ok())
+        .map(|token| token.trim().to_owned())
+        .filter(|token| !token.is_empty());
+    let stack_options = StackOptions {
+        strict: config.is_strict(),
+        profile_token,
+        ..options.stack_options()
+    };
+    let stack = Stack::load_with_metrics(options.base_path(), stack_options, metrics)
+        .await
+        .context("Loading stack")?;
+    let live = Arc::new(LiveStack::start(stack));
+
+    if let Some(changes) = &options.reload {
+        let changes = changes.clone();
+        let live = live.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let plugin_ids = changes.next().await;
+                if let Err(e) = live.reload_plugins(&plugin_ids).await {
+                    error!("Keeping previous stack, reloading failed: {e:#}");
+                }
+            }
+        });
+    }
+
+    if let Some(admin) = &config.admin {
+        spawn_admin_endpoint(admin, live.clone())
+            .await
+            .context("Starting admin API")?;
+    }
+
+    health.set_loaded(live);
+    info!("Stack loaded, ready to serve");
+    Ok(())
+}
+
+/// Completes on Ctrl-C, or on SIGTERM where there is one
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Could not listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Could not listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dev_mode_needs_explicit_development_profile() {
+        assert!(check_dev_mode(true, Some(Profile::Development)).is_ok());
+        assert!(check_dev_mode(true, Some(Profile::Production)).is_err());
+        assert!(check_dev_mode(true, None).is_err());
+        assert!(check_dev_mode(false, None).is_ok());
+        assert!(check_dev_mode(false, Some(Profile::Production)).is_ok());
     }
 }
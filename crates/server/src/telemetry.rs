@@ -0,0 +1,105 @@
+use anyhow::Context as _;
+use opentelemetry::global;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig as _};
+use opentelemetry_sdk::{Resource, propagation::TraceContextPropagator, trace::SdkTracerProvider};
+use serde::Deserialize;
+
+/// `telemetry` section of the server config
+#[derive(Clone, Debug, Deserialize)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+    /// Standard `OTEL_EXPORTER_OTLP_*` environment variables apply when unset.
+    #[serde(default = "Option::default")]
+    pub otlp_endpoint: Option<String>,
+
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_service_name() -> String {
+    "wassel".to_owned()
+}
+
+/// Creates provider exporting spans over OTLP and installs W3C trace context
+/// propagation, which plugins use for incoming and outbound requests
+pub(crate) fn tracer_provider(config: &TelemetryConfig) -> anyhow::Result<SdkTracerProvider> {
+    let mut exporter = SpanExporter::builder().with_http();
+    if let Some(endpoint) = &config.otlp_endpoint {
+        exporter = exporter.with_endpoint(endpoint);
+    }
+    let exporter = exporter.build().context("Creating OTLP span exporter")?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Ok(provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use http_body_util::{BodyExt as _, Full};
+    use hyper::{Request, Response, body::Incoming, server::conn::http1, service::service_fn};
+    use hyper_util::rt::TokioIo;
+    use opentelemetry::trace::{Tracer as _, TracerProvider as _};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use super::*;
+
+    /// Accepts OTLP/HTTP exports and passes on their paths and bodies
+    async fn collector() -> (String, mpsc::UnboundedReceiver<(String, Bytes)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let sender = sender.clone();
+                    async move {
+                        let path = req.uri().path().to_owned();
+                        let body = req.into_body().collect().await?.to_bytes();
+                        let _ = sender.send((path, body));
+                        Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::new())))
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        (endpoint, receiver)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_are_exported_to_collector() {
+        let (endpoint, mut exports) = collector().await;
+        let config = TelemetryConfig {
+            otlp_endpoint: Some(endpoint),
+            service_name: "wassel-test".to_owned(),
+        };
+
+        let provider = tracer_provider(&config).unwrap();
+        provider.tracer("test").in_span("exported-span", |_| {});
+        tokio::task::spawn_blocking(move || provider.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let (path, body) = tokio::time::timeout(Duration::from_secs(10), exports.recv())
+            .await
+            .expect("Collector should receive spans")
+            .unwrap();
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert_eq!(path, "/v1/traces");
+        assert!(contains(b"exported-span"));
+        assert!(contains(b"wassel-test"));
+    }
+}
@@ -3,7 +3,60 @@ wasmtime::component::bindgen!({
     world: "http-plugin",
     with: {
         "wasi:http": wasmtime_wasi_http::bindings::http,
+        "wasi:keyvalue/store.bucket": crate::resources::Bucket,
+        "wassel:foundation/websocket.session": crate::resources::WebSocketSession,
+        "wassel:foundation/sql.connection": crate::resources::SqlConnection,
+        "wassel:foundation/sql.statement": crate::resources::SqlStatement,
+        "wassel:foundation/blobstore.container": crate::resources::BlobContainer,
+        "wassel:foundation/blobstore.blob-writer": crate::resources::BlobWriter,
+        "wassel:foundation/blobstore.blob-reader": crate::resources::BlobReader,
     },
     imports: { default: async },
     exports: { default: async },
 });
+
+/// Host representations of resources declared in Wassel's WIT
+pub mod resources {
+    /// Opened `wasi:keyvalue/store.bucket`
+    pub struct Bucket {
+        pub name: String,
+    }
+
+    /// `wassel:foundation/websocket.session`. An instance serves a single
+    /// connection, so its state is kept by the host in the plugin state.
+    pub struct WebSocketSession;
+
+    /// `wassel:foundation/sql.connection`. The database connection is kept
+    /// by the host in the plugin state under this id.
+    pub struct SqlConnection {
+        pub id: u64,
+    }
+
+    /// `wassel:foundation/sql.statement`, prepared on the connection with the id
+    pub struct SqlStatement {
+        pub connection: u64,
+        pub sql: String,
+    }
+
+    /// Opened `wassel:foundation/blobstore.container`
+    pub struct BlobContainer {
+        pub name: String,
+    }
+
+    /// `wassel:foundation/blobstore.blob-writer`, writing into a temporary
+    /// file until it is finished
+    pub struct BlobWriter {
+        pub container: String,
+        pub name: String,
+        pub content_type: Option<String>,
+        pub path: std::path::PathBuf,
+        pub file: Option<std::fs::File>,
+        pub written: u64,
+    }
+
+    /// `wassel:foundation/blobstore.blob-reader`
+    pub struct BlobReader {
+        pub metadata: crate::wassel::foundation::blobstore::ObjectMetadata,
+        pub file: std::fs::File,
+    }
+}